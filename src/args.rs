@@ -1,10 +1,11 @@
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf};
 
 use byte_unit::Byte;
 use clap::{Parser, Subcommand};
 use ipnet::Ipv4Net;
+use url::Url;
 
-use crate::id::Id;
+use crate::{id::Id, instance::DiskFormat, qemu_img::Preallocation};
 
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -24,7 +25,47 @@ pub enum Command {
         command: NetworkCommand,
     },
 
-    Server,
+    Instance {
+        #[clap(subcommand)]
+        command: InstanceCommand,
+    },
+
+    Image {
+        #[clap(subcommand)]
+        command: ImageCommand,
+    },
+
+    Server {
+        #[clap(long)]
+        metrics_addr: Option<SocketAddr>,
+
+        #[clap(long)]
+        http: Option<SocketAddr>,
+
+        /// Convert raw images to qcow2 as they're cached
+        #[clap(long)]
+        convert_raw_images: bool,
+    },
+
+    Run {
+        #[clap(long)]
+        image: Url,
+
+        #[clap(short, long, default_value = "2GiB")]
+        memory: Byte,
+
+        #[clap(short, long, default_value_t = 2)]
+        cpus: u8,
+
+        #[clap(short('N'), long)]
+        network: Option<Id>,
+
+        #[clap(long)]
+        rm: bool,
+    },
+
+    Doctor,
+    Gc,
 }
 
 #[derive(Debug, Subcommand)]
@@ -52,10 +93,182 @@ pub enum MachineCommand {
         #[clap(short, long)]
         virtiofs: Vec<PathBuf>,
     },
+    Clone {
+        source: Id,
+
+        #[clap(short('n'), long)]
+        name: Option<String>,
+    },
+    Rename {
+        id: Id,
+        name: String,
+    },
+    Export {
+        id: Id,
+
+        #[clap(short, long)]
+        out: Option<PathBuf>,
+    },
+    Import {
+        file: PathBuf,
+
+        #[clap(long)]
+        id: Option<Id>,
+    },
+    Start {
+        id: Id,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ImageCommand {
+    /// Downloads an image into the cache. Ctrl-C cancels the download.
+    Pull {
+        url: Url,
+
+        #[clap(long)]
+        hash: Option<String>,
+    },
+
+    /// Cancels an image download, failing everything waiting on it
+    Cancel { url: Url },
 }
 
 #[derive(Debug, Subcommand)]
 pub enum NetworkCommand {
     List,
-    Create { name: String, ip: Ipv4Net },
+    Create {
+        name: String,
+        ip: Ipv4Net,
+
+        #[clap(long)]
+        user: bool,
+    },
+    Rename {
+        id: Id,
+        name: String,
+    },
+    Inspect {
+        id: Id,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SnapshotCommand {
+    Create { id: Id, name: String },
+    List { id: Id },
+    Apply { id: Id, name: String },
+    Delete { id: Id, name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum InstanceCommand {
+    List,
+    Start {
+        id: Id,
+
+        #[clap(long)]
+        paused: bool,
+    },
+    Stop {
+        id: Id,
+    },
+    Resume {
+        id: Id,
+    },
+    SetMemory {
+        id: Id,
+        memory: Byte,
+    },
+    AttachDisk {
+        id: Id,
+        path: PathBuf,
+
+        #[clap(short, long, value_enum, default_value_t = DiskFormat::Qcow2)]
+        format: DiskFormat,
+
+        /// Creates the image with this size if it doesn't exist
+        #[clap(short, long)]
+        size: Option<Byte>,
+
+        /// How much of a created image's space is allocated up front
+        #[clap(long, value_enum, default_value_t = Preallocation::Off)]
+        preallocation: Preallocation,
+
+        #[clap(long)]
+        iops: Option<u64>,
+
+        /// Bytes per second
+        #[clap(long)]
+        bps: Option<u64>,
+    },
+    DetachDisk {
+        id: Id,
+        disk_id: String,
+    },
+    AddShare {
+        id: Id,
+        path: PathBuf,
+    },
+    /// Detaches a share by its tag, which the guest should unmount first
+    RemoveShare {
+        id: Id,
+        tag: String,
+    },
+    /// Changes a disk throttle of a running instance until it's next started.
+    /// A limit that's left out is removed.
+    Throttle {
+        id: Id,
+
+        /// An attached disk, rather than the root disk
+        #[clap(long)]
+        disk: Option<String>,
+
+        #[clap(long)]
+        iops: Option<u64>,
+
+        /// Bytes per second
+        #[clap(long)]
+        bps: Option<u64>,
+    },
+    EjectCdrom {
+        id: Id,
+    },
+    Status {
+        id: Id,
+    },
+    Snapshot {
+        #[clap(subcommand)]
+        command: SnapshotCommand,
+    },
+    Wait {
+        id: Id,
+
+        #[clap(long)]
+        ssh: bool,
+
+        #[clap(short, long, default_value_t = 300)]
+        timeout: u64,
+    },
+    Console {
+        id: Id,
+    },
+    Monitor {
+        id: Id,
+    },
+    Events {
+        id: Id,
+    },
+    Top {
+        id: Option<Id>,
+
+        #[clap(short, long, default_value_t = 2)]
+        interval: u64,
+    },
+    QemuArgs {
+        id: Id,
+
+        #[clap(long)]
+        paused: bool,
+    },
 }
@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, bail};
+use byte_unit::Byte;
+
+use crate::id::Id;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CGROUP_PARENT: &str = "vmm";
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// A cgroup v2 group holding a single instance's qemu process
+pub struct InstanceCgroup {
+    instance_id: Id,
+    path: PathBuf,
+}
+
+impl InstanceCgroup {
+    pub async fn create(instance_id: Id) -> Result<Self> {
+        let root = PathBuf::from(CGROUP_ROOT);
+
+        if !root.join("cgroup.controllers").exists() {
+            bail!("cgroup v2 is not mounted at {}", root.display());
+        }
+
+        let parent = root.join(CGROUP_PARENT);
+        let path = parent.join(instance_id.to_string());
+
+        tokio::fs::create_dir_all(&path)
+            .await
+            .context("failed to create cgroup")
+            .context(instance_id)?;
+
+        Ok(Self { instance_id, path })
+    }
+
+    pub async fn set_cpu_quota(&self, cores: f64) -> Result<()> {
+        if !cores.is_finite() || cores <= 0.0 {
+            bail!("cpu quota must be positive: {}", cores);
+        }
+
+        self.enable_controller("cpu").await?;
+
+        let quota = (cores * CPU_PERIOD_US as f64).round() as u64;
+        self.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD_US))
+            .await
+    }
+
+    /// Caps the memory charged to the group. Guest RAM is charged to qemu as
+    /// the guest touches it, including the shared `memory-backend-file` pages
+    /// that virtiofs needs, so the limit must leave headroom above the
+    /// configured guest memory. Hugepages are accounted by the separate
+    /// hugetlb controller and do not count against this limit.
+    pub async fn set_memory_limit(&self, limit: Byte) -> Result<()> {
+        self.enable_controller("memory").await?;
+        self.write("memory.max", &limit.as_u64().to_string()).await
+    }
+
+    pub async fn add_process(&self, pid: u32) -> Result<()> {
+        self.write("cgroup.procs", &pid.to_string()).await
+    }
+
+    /// Removes the cgroup, which only succeeds once its processes have exited
+    pub async fn remove(self) -> Result<()> {
+        tokio::fs::remove_dir(&self.path)
+            .await
+            .context("failed to remove cgroup")
+            .context(self.instance_id)?;
+        Ok(())
+    }
+
+    async fn enable_controller(&self, controller: &str) -> Result<()> {
+        // Controllers must be enabled on every ancestor's subtree_control for
+        // the interface files to show up in the instance cgroup
+        let root = PathBuf::from(CGROUP_ROOT);
+        for dir in [root.clone(), root.join(CGROUP_PARENT)] {
+            tokio::fs::write(
+                dir.join("cgroup.subtree_control"),
+                format!("+{}", controller),
+            )
+            .await
+            .context("failed to enable cgroup controller")
+            .context(controller.to_string())?;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, file: &str, value: &str) -> Result<()> {
+        tokio::fs::write(self.path.join(file), value)
+            .await
+            .context(format!("failed to write {}", file))
+            .context(self.instance_id)?;
+        Ok(())
+    }
+}
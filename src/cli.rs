@@ -1,22 +1,64 @@
-use anyhow::{Result, bail};
-use byte_unit::UnitType;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use byte_unit::{Byte, UnitType};
 use clap::Parser;
+use directories::BaseDirs;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    args::{Args, Command, MachineCommand, NetworkCommand},
-    ctx::Ctx,
-    machine::MachineConfig,
-    network::NetworkConfig,
+    args::{
+        Args, Command, ImageCommand, InstanceCommand, MachineCommand, NetworkCommand,
+        SnapshotCommand,
+    },
+    config_format::ConfigFormat,
+    console::{attach_console, attach_monitor},
+    control::{AttachDiskParams, ControlClient, SetIoThrottleParams, serve_control},
+    ctx::{BaseCtx, Ctx},
+    doctor::doctor,
+    gc::collect_garbage,
+    http_api::serve_http_api,
+    id::Id,
+    image_cache::{ImageCacheConfig, create_image_cache},
+    instance::{
+        AttachDiskOptions, Instance, InstanceState, find_qemu, get_mac_address, get_qemu_pid,
+    },
+    instance_events::read_instance_events,
+    machine::{
+        CloudInitSeed, Display, Machine, MachineConfig, MachineDhcpNetworkConfig, MachineExport,
+        MachineImageConfig, MachineInterfaceConfig, MachineNetworkConfig, MachineUserConfig,
+        RestartPolicy,
+    },
+    metrics::serve_metrics,
+    network::{Network, NetworkConfig, NetworkMode},
+    probe::wait_for_ssh,
+    proc_stats::read_process_stats,
+    progress_router::create_progress_router,
+    progress_ui::show_progress,
+    server::{Server, reload_on_sighup, supervise},
+    snapshot::{apply_snapshot, create_snapshot, delete_snapshot, list_snapshots},
+    task_group::TaskGroup,
     text_table::TextTable,
 };
 
 pub struct Cli {
-    ctx: Ctx,
+    ctx: BaseCtx,
 }
 
 impl Cli {
-    pub fn new() -> Self {
-        Self { ctx: Ctx::new() }
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            ctx: BaseCtx::new()?,
+        })
+    }
+
+    pub fn cancel_token(&self) -> &CancellationToken {
+        self.ctx.cancel_token()
     }
 
     pub async fn run(self) -> Result<()> {
@@ -41,16 +83,21 @@ impl Cli {
                     for machine_id in machine_ids {
                         let machine = MachineConfig::open(&self.ctx, machine_id).await?;
 
-                        let Some(network_id) =
-                            network_ids.iter().find(|id| **id == machine.network.id)
-                        else {
-                            bail!(
-                                "Network with id \"{}\" does not exist",
-                                machine.network.id.to_string()
-                            )
-                        };
+                        let network = match &machine.network {
+                            Some(machine_network) => {
+                                let Some(network_id) =
+                                    network_ids.iter().find(|id| **id == machine_network.id)
+                                else {
+                                    bail!(
+                                        "Network with id \"{}\" does not exist",
+                                        machine_network.id.to_string()
+                                    )
+                                };
 
-                        let network = NetworkConfig::open(&self.ctx, *network_id).await?;
+                                Some(NetworkConfig::open(&self.ctx, *network_id).await?)
+                            }
+                            None => None,
+                        };
 
                         table.push(machine_id.to_string());
                         table.push(machine.name);
@@ -76,7 +123,7 @@ impl Cli {
                             );
                         }
 
-                        table.push(network.name.clone());
+                        table.push(network.map(|network| network.name).unwrap_or_default());
                     }
                     table.print();
                 }
@@ -92,6 +139,117 @@ impl Cli {
                 } => {
                     todo!()
                 }
+
+                MachineCommand::Clone { source, name } => {
+                    // Load the config as written so that ${VAR} references
+                    // are carried over instead of their current values
+                    let mut config = MachineConfig::open_raw(&self.ctx, source).await?;
+
+                    config.name = name.unwrap_or_else(|| format!("{}-clone", config.name));
+
+                    if MachineConfig::find_by_name(&self.ctx, &config.name)
+                        .await?
+                        .is_some()
+                    {
+                        bail!("machine name already exists: {}", config.name);
+                    }
+
+                    let machine = Machine::new(&self.ctx, Id::new()?, config).await?;
+
+                    println!("{}", machine.id());
+                }
+
+                MachineCommand::Rename { id, name } => {
+                    let existing_id = MachineConfig::find_by_name(&self.ctx, &name).await?;
+                    if existing_id.is_some_and(|existing_id| existing_id != id) {
+                        bail!("machine name already exists: {}", name);
+                    }
+
+                    MachineConfig::update(&self.ctx, id, |config| config.name = name).await?;
+                }
+
+                MachineCommand::Export { id, out } => {
+                    // Exported with `${VAR}` references and the template resolved
+                    // so that the document is self-contained
+                    let mut machine = MachineConfig::open(&self.ctx, id).await?;
+                    machine.template = None;
+
+                    let export = MachineExport { id, machine };
+
+                    match out {
+                        Some(out) => {
+                            let export_text = ConfigFormat::from_path(&out).serialize(&export)?;
+                            tokio::fs::write(&out, export_text)
+                                .await
+                                .context("failed to write machine export")
+                                .context(out.display().to_string())?;
+                        }
+                        None => println!("{}", ConfigFormat::Json.serialize(&export)?),
+                    }
+                }
+
+                MachineCommand::Import { file, id } => {
+                    let import_text = tokio::fs::read_to_string(&file)
+                        .await
+                        .context("failed to read machine import")
+                        .context(file.display().to_string())?;
+
+                    let import: MachineExport = ConfigFormat::from_path(&file)
+                        .parse(&import_text)
+                        .context("failed to parse machine import")
+                        .context(file.display().to_string())?;
+
+                    let config = import.machine;
+
+                    if let Some(network) = &config.network {
+                        NetworkConfig::open(&self.ctx, network.id)
+                            .await
+                            .context("machine references a missing network")?;
+                    }
+
+                    if MachineConfig::find_by_name(&self.ctx, &config.name)
+                        .await?
+                        .is_some()
+                    {
+                        bail!("machine name already exists: {}", config.name);
+                    }
+
+                    let id = match id {
+                        Some(id) => id,
+                        None => Id::new()?,
+                    };
+
+                    let machine = Machine::new(&self.ctx, id, config).await?;
+
+                    println!("{}", machine.id());
+                }
+
+                MachineCommand::Start { id } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    let instance_id = client.start_machine(id).await?;
+                    println!("{}", instance_id);
+                }
+            },
+
+            Command::Image { command } => match command {
+                ImageCommand::Pull { url, hash } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    let hash = tokio::select! {
+                        result = client.pull_image(url.clone(), hash) => result?,
+                        _ = self.ctx.cancel_token().cancelled() => {
+                            // The first connection is still waiting on the pull
+                            let mut client = ControlClient::connect_required(&self.ctx).await?;
+                            client.cancel_image(url).await?;
+                            bail!("image pull cancelled");
+                        }
+                    };
+                    println!("{}", hash);
+                }
+
+                ImageCommand::Cancel { url } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    client.cancel_image(url).await?;
+                }
             },
 
             Command::Network { command } => match command {
@@ -99,16 +257,699 @@ impl Cli {
                     todo!()
                 }
 
-                NetworkCommand::Create { name, ip } => {
-                    todo!()
+                NetworkCommand::Create { name, ip, user } => {
+                    if NetworkConfig::find_by_name(&self.ctx, &name)
+                        .await?
+                        .is_some()
+                    {
+                        bail!("network name already exists: {}", name);
+                    }
+
+                    let mode = match user {
+                        true => NetworkMode::User,
+                        false => NetworkMode::Bridge,
+                    };
+
+                    let config = NetworkConfig {
+                        name,
+                        ip,
+                        dhcp: false,
+                        mode,
+                        uplinks: vec![],
+                    };
+
+                    let network = Network::new(&self.ctx, Id::new()?, config).await?;
+
+                    println!("{}", network.id());
+                }
+
+                // Machines reference their network by id, so they don't need
+                // to be updated when the name changes
+                NetworkCommand::Rename { id, name } => {
+                    let existing_id = NetworkConfig::find_by_name(&self.ctx, &name).await?;
+                    if existing_id.is_some_and(|existing_id| existing_id != id) {
+                        bail!("network name already exists: {}", name);
+                    }
+
+                    NetworkConfig::update(&self.ctx, id, |config| config.name = name).await?;
+                }
+
+                NetworkCommand::Inspect { id } => {
+                    let network = NetworkConfig::open(&self.ctx, id).await?;
+
+                    println!("ID:      {}", id);
+                    println!("Name:    {}", network.name);
+                    println!("Subnet:  {}", network.ip.trunc());
+                    // Machines with a static address can override it
+                    println!("Gateway: {}", network.ip.addr());
+                    println!("Mode:    {:?}", network.mode);
+                    println!("DHCP:    {}", network.dhcp);
+
+                    let mut machine_table = TextTable::build()
+                        .add_column("Machine")
+                        .add_column("Name")
+                        .add_column("Address")
+                        .add_column("Gateway")
+                        .done();
+
+                    for machine_id in self.ctx.dirs().get_machine_config_ids()? {
+                        let machine = MachineConfig::open(&self.ctx, machine_id).await?;
+                        let Some(machine_network) = &machine.network else {
+                            continue;
+                        };
+                        if machine_network.id != id {
+                            continue;
+                        }
+
+                        machine_table.push(machine_id.to_string());
+                        machine_table.push(machine.name.clone());
+                        machine_table.push(get_address_label(machine_network));
+                        machine_table.push(machine_network.get_gateway(&network).to_string());
+                    }
+
+                    // Whether instances are running is only known to the
+                    // server
+                    let summaries = match ControlClient::connect(&self.ctx).await? {
+                        Some(mut client) => Some(client.list().await?),
+                        None => None,
+                    };
+
+                    let mut instance_table = TextTable::build()
+                        .add_column("Instance")
+                        .add_column("Machine")
+                        .add_column("Status")
+                        .add_column("MAC")
+                        .add_column("Address")
+                        .done();
+
+                    for instance_id in self.ctx.dirs().get_instance_state_ids()? {
+                        let state = InstanceState::open(&self.ctx, instance_id).await?;
+                        if state.network_id != Some(id) {
+                            continue;
+                        }
+                        let machine = MachineConfig::open(&self.ctx, state.machine_id).await?;
+
+                        instance_table.push(instance_id.to_string());
+                        instance_table.push(machine.name.clone());
+
+                        let summary = summaries
+                            .iter()
+                            .flatten()
+                            .find(|summary| summary.id == instance_id);
+
+                        match summary {
+                            Some(summary) if summary.paused => {
+                                instance_table.push("paused".to_string())
+                            }
+                            Some(summary) if summary.running => {
+                                instance_table.push("running".to_string())
+                            }
+                            Some(summary) => instance_table.push(summary.status.to_string()),
+                            None => instance_table.push("".to_string()),
+                        }
+
+                        instance_table.push(get_mac_address(instance_id));
+                        instance_table.push(
+                            machine
+                                .network
+                                .as_ref()
+                                .map(get_address_label)
+                                .unwrap_or_default(),
+                        );
+                    }
+
+                    println!();
+                    machine_table.print();
+                    println!();
+                    instance_table.print();
                 }
             },
 
-            Command::Server => {
-                todo!()
+            Command::Instance { command } => match command {
+                InstanceCommand::List => {
+                    // Whether instances are running is only known to the
+                    // server
+                    let summaries = match ControlClient::connect(&self.ctx).await? {
+                        Some(mut client) => Some(client.list().await?),
+                        None => None,
+                    };
+
+                    let mut table = TextTable::build()
+                        .add_column("ID")
+                        .add_column("Machine")
+                        .add_column("Network")
+                        .add_column("Status")
+                        .add_column("Display")
+                        .done();
+
+                    for instance_id in self.ctx.dirs().get_instance_state_ids()? {
+                        let state = InstanceState::open(&self.ctx, instance_id).await?;
+                        let machine = MachineConfig::open(&self.ctx, state.machine_id).await?;
+                        let network = match state.network_id {
+                            Some(network_id) => {
+                                NetworkConfig::open(&self.ctx, network_id).await?.name
+                            }
+                            None => "".to_string(),
+                        };
+
+                        table.push(instance_id.to_string());
+                        table.push(machine.name.clone());
+                        table.push(network);
+
+                        let summary = summaries
+                            .iter()
+                            .flatten()
+                            .find(|summary| summary.id == instance_id);
+
+                        match summary {
+                            Some(summary) if summary.paused => table.push("paused".to_string()),
+                            Some(summary) if summary.running => table.push("running".to_string()),
+                            Some(summary) => table.push(summary.status.to_string()),
+                            None => table.push("".to_string()),
+                        }
+
+                        match (machine.display.get_scheme(), state.display_port) {
+                            (Some(scheme), Some(port)) => {
+                                table.push(format!("{}://127.0.0.1:{}", scheme, port))
+                            }
+                            _ => table.push("".to_string()),
+                        }
+                    }
+                    table.print();
+                }
+
+                InstanceCommand::Start { id, paused } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    client.start_instance(id, paused).await?;
+                }
+
+                InstanceCommand::Stop { id } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    client.stop_instance(id).await?;
+                }
+
+                InstanceCommand::Resume { id } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    client.resume_instance(id).await?;
+                }
+
+                InstanceCommand::SetMemory { id, memory } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    client.set_memory(id, memory).await?;
+                }
+
+                InstanceCommand::AttachDisk {
+                    id,
+                    path,
+                    format,
+                    size,
+                    preallocation,
+                    iops,
+                    bps,
+                } => {
+                    // The server resolves paths against its own working dir
+                    let path = std::path::absolute(&path)
+                        .context("invalid disk path")
+                        .context(path.display().to_string())?;
+
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    let disk_id = client
+                        .attach_disk(AttachDiskParams {
+                            id,
+                            path,
+                            format,
+                            options: AttachDiskOptions {
+                                size,
+                                preallocation,
+                                iops_limit: iops,
+                                bps_limit: bps,
+                            },
+                        })
+                        .await?;
+                    println!("{}", disk_id);
+                }
+
+                InstanceCommand::DetachDisk { id, disk_id } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    client.detach_disk(id, disk_id).await?;
+                }
+
+                InstanceCommand::AddShare { id, path } => {
+                    // Checked again by the server, but resolved here since it
+                    // has its own working dir
+                    let path = path
+                        .canonicalize()
+                        .context("invalid share dir path")
+                        .context(path.display().to_string())?;
+
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    let tag = client.add_share_dir(id, path).await?;
+                    println!("{}", tag);
+                }
+
+                InstanceCommand::RemoveShare { id, tag } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    client.remove_share_dir(id, tag).await?;
+                }
+
+                InstanceCommand::Throttle {
+                    id,
+                    disk,
+                    iops,
+                    bps,
+                } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    client
+                        .set_io_throttle(SetIoThrottleParams {
+                            id,
+                            disk_id: disk,
+                            iops_limit: iops,
+                            bps_limit: bps,
+                        })
+                        .await?;
+                }
+
+                InstanceCommand::EjectCdrom { id } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    client.eject_cdrom(id).await?;
+                }
+
+                InstanceCommand::Status { id } => {
+                    let mut client = ControlClient::connect_required(&self.ctx).await?;
+                    let status = client.status(id).await?;
+                    println!("{}", ConfigFormat::Json.serialize(&status)?);
+                }
+
+                InstanceCommand::Snapshot { command } => match command {
+                    SnapshotCommand::Create { id, name } => {
+                        let instance = Instance::open(&self.ctx, id).await?;
+                        create_snapshot(&self.ctx, &instance, &name).await?;
+                    }
+
+                    SnapshotCommand::List { id } => {
+                        let instance = Instance::open(&self.ctx, id).await?;
+                        let snapshots = list_snapshots(&self.ctx, &instance).await?;
+
+                        let mut table = TextTable::build()
+                            .add_column("ID")
+                            .add_column("Tag")
+                            .add_column("VM Size")
+                            .add_column("Date")
+                            .add_column("VM Clock")
+                            .add_column("Live")
+                            .done();
+
+                        for snapshot in snapshots {
+                            table.push(snapshot.id);
+                            table.push(snapshot.tag);
+                            table.push(snapshot.vm_size);
+                            table.push(snapshot.date);
+                            table.push(snapshot.vm_clock);
+                            table.push(
+                                snapshot
+                                    .live
+                                    .map(|live| live.to_string())
+                                    .unwrap_or_default(),
+                            );
+                        }
+                        table.print();
+                    }
+
+                    SnapshotCommand::Apply { id, name } => {
+                        let instance = Instance::open(&self.ctx, id).await?;
+                        apply_snapshot(&self.ctx, &instance, &name).await?;
+                    }
+
+                    SnapshotCommand::Delete { id, name } => {
+                        let instance = Instance::open(&self.ctx, id).await?;
+                        delete_snapshot(&self.ctx, &instance, &name).await?;
+                    }
+                },
+
+                InstanceCommand::Wait { id, ssh, timeout } => {
+                    let instance = Instance::open(&self.ctx, id).await?;
+                    let machine_network = instance.machine().config().network.as_ref();
+                    let Some(ip) = machine_network.and_then(|network| network.get_ip_address())
+                    else {
+                        bail!("instance has no static ip address to wait on: {}", id);
+                    };
+
+                    let waited = wait_for_ssh(ip, ssh, Duration::from_secs(timeout)).await?;
+
+                    println!("reachable at {} after {:.1}s", ip, waited.as_secs_f64());
+                }
+
+                InstanceCommand::Top { id, interval } => {
+                    let ids = match id {
+                        Some(id) => vec![id],
+                        None => self.ctx.dirs().get_instance_state_ids()?,
+                    };
+                    show_instance_top(&self.ctx, &ids, Duration::from_secs(interval.max(1)))
+                        .await?;
+                }
+
+                InstanceCommand::Console { id } => {
+                    let instance = Instance::open(&self.ctx, id).await?;
+                    attach_console(&instance.get_serial_socket_path()).await?;
+                }
+
+                InstanceCommand::Events { id } => {
+                    let mut table = TextTable::build()
+                        .add_column("Time")
+                        .add_column("Event")
+                        .add_column("Details")
+                        .done();
+
+                    for entry in read_instance_events(&self.ctx, id).await? {
+                        // The event's own fields are everything but its tag
+                        let mut details = serde_json::to_value(&entry.event)?;
+                        let event = details
+                            .as_object_mut()
+                            .and_then(|fields| fields.remove("event"))
+                            .and_then(|event| event.as_str().map(str::to_string))
+                            .unwrap_or_default();
+
+                        table.push(format_timestamp(entry.timestamp));
+                        table.push(event);
+                        table.push(
+                            details
+                                .as_object()
+                                .into_iter()
+                                .flatten()
+                                .map(|(key, value)| format!("{}={}", key, value))
+                                .collect::<Vec<_>>()
+                                .join(" "),
+                        );
+                    }
+                    table.print();
+                }
+
+                InstanceCommand::Monitor { id } => {
+                    let instance = Instance::open(&self.ctx, id).await?;
+                    if !instance.machine().config().hmp_monitor {
+                        bail!("hmp monitor is off for instance: {}", id);
+                    }
+                    attach_monitor(&instance.get_monitor_socket_path()).await?;
+                }
+
+                InstanceCommand::QemuArgs { id, paused } => {
+                    let mut task_group = TaskGroup::new(self.ctx.cancel_token().clone());
+                    let ctx = self.start_services(&mut task_group, ImageCacheConfig::default());
+
+                    let result = async {
+                        let mut instance = Instance::open(&ctx, id).await?;
+                        instance.get_dry_run_qemu_args(&ctx, paused).await
+                    }
+                    .await;
+
+                    task_group.cancel().await;
+                    let args = result?;
+
+                    // One per line, so they can be read back with e.g.
+                    // `xargs -d '\n'`
+                    println!("{}", find_qemu()?.display());
+                    for arg in args {
+                        println!("{}", arg);
+                    }
+                }
+            },
+
+            Command::Server {
+                metrics_addr,
+                http,
+                convert_raw_images,
+            } => {
+                let mut task_group = TaskGroup::new(self.ctx.cancel_token().clone());
+                let image_cache_config = ImageCacheConfig {
+                    convert_raw_images,
+                    ..ImageCacheConfig::default()
+                };
+                let ctx = self.start_services(&mut task_group, image_cache_config);
+
+                let mut server = Server::new();
+                server.read_all(&ctx).await?;
+                let server = Arc::new(Mutex::new(server));
+
+                task_group.spawn(supervise(server.clone(), ctx.clone()));
+                task_group.spawn(reload_on_sighup(server.clone(), ctx.clone()));
+                task_group.spawn(serve_control(server.clone(), ctx.clone()));
+
+                if let Some(metrics_addr) = metrics_addr {
+                    let server = server.clone();
+                    task_group.spawn(serve_metrics(server, metrics_addr));
+                }
+
+                if let Some(http) = http {
+                    task_group.spawn(serve_http_api(server.clone(), ctx.clone(), http));
+                }
+
+                task_group.wait().await;
+            }
+
+            Command::Run {
+                image,
+                memory,
+                cpus,
+                network,
+                rm,
+            } => {
+                let mut task_group = TaskGroup::new(self.ctx.cancel_token().clone());
+                let ctx = self.start_services(&mut task_group, ImageCacheConfig::default());
+
+                let network = match network {
+                    Some(network_id) => Some(Network::open(&ctx, network_id).await?),
+                    None => None,
+                };
+
+                let machine_id = Id::new()?;
+                let config = MachineConfig {
+                    template: None,
+                    name: format!("run-{}", machine_id),
+                    machine_type: None,
+                    boot_order: None,
+                    install_iso: None,
+                    kernel: None,
+                    initrd: None,
+                    cmdline: None,
+                    cpus,
+                    cpu_quota: None,
+                    cpu_affinity: None,
+                    memory,
+                    max_memory: None,
+                    memory_slots: None,
+                    memory_limit: None,
+                    start_timeout_secs: None,
+                    graceful_timeout_secs: None,
+                    restart: RestartPolicy::No,
+                    console_log: false,
+                    hmp_monitor: false,
+                    display: Display::None,
+                    hugepages: false,
+                    numa_nodes: vec![],
+                    image: MachineImageConfig {
+                        url: image,
+                        hash: None,
+                        iops_limit: None,
+                        bps_limit: None,
+                    },
+                    share_dirs: vec![],
+                    user: MachineUserConfig {
+                        name: std::env::var("USER").unwrap_or("vmm".into()),
+                        ssh_authorized_keys: get_default_ssh_keys(),
+                    },
+                    user_data_file: None,
+                    cloud_init: None,
+                    cloud_init_seed: CloudInitSeed::default(),
+                    ntp_servers: vec![],
+                    network: network.as_ref().map(|network| MachineNetworkConfig {
+                        id: *network.id(),
+                        interface: MachineInterfaceConfig::Dhcp(MachineDhcpNetworkConfig {
+                            interface: None,
+                        }),
+                        rx_rate: None,
+                        tx_rate: None,
+                        host_forwards: vec![],
+                    }),
+                    extra_qemu_args: vec![],
+                    ephemeral: rm,
+                };
+
+                let machine = Machine::new(&ctx, machine_id, config).await?;
+                let mut instance =
+                    Instance::new(&ctx, Id::new()?, machine.clone(), network).await?;
+
+                eprintln!("instance {} of machine {}", instance.id(), machine.id());
+
+                let result = async {
+                    instance.start(&ctx, false).await?;
+                    attach_console(&instance.get_serial_socket_path()).await
+                }
+                .await;
+
+                if let Err(e) = instance.stop(&ctx).await {
+                    eprintln!("error: failed to stop instance {}: {:?}", instance.id(), e);
+                }
+
+                if rm {
+                    instance.delete(&ctx).await?;
+                    machine.delete(&ctx).await?;
+                }
+
+                task_group.cancel().await;
+
+                result?;
+            }
+
+            Command::Doctor => {
+                if !doctor() {
+                    bail!("some checks failed");
+                }
+            }
+
+            Command::Gc => {
+                collect_garbage(&self.ctx).await?;
             }
         }
 
         Ok(())
     }
+
+    /// Starts the progress UI and image cache needed to run instances in
+    /// this process
+    fn start_services(
+        &self,
+        task_group: &mut TaskGroup<Result<()>>,
+        image_cache_config: ImageCacheConfig,
+    ) -> Ctx {
+        let progress_router = create_progress_router(task_group);
+
+        task_group.spawn(show_progress(progress_router.clone()));
+
+        let image_cache = create_image_cache(
+            self.ctx.clone(),
+            progress_router,
+            image_cache_config,
+            task_group,
+        );
+
+        self.ctx
+            .clone()
+            .builder()
+            .image_manager(image_cache)
+            .build()
+    }
+}
+
+/// Shows the host CPU and memory used by each running instance's qemu,
+/// refreshing until interrupted
+async fn show_instance_top(ctx: &BaseCtx, ids: &[Id], interval: Duration) -> Result<()> {
+    let mut names = HashMap::new();
+    for id in ids {
+        let state = InstanceState::open(ctx, *id).await?;
+        let machine = MachineConfig::open(ctx, state.machine_id).await?;
+        names.insert(*id, machine.name);
+    }
+
+    let mut last: HashMap<Id, (Instant, Duration)> = HashMap::new();
+
+    loop {
+        let mut table = TextTable::build()
+            .add_column("ID")
+            .add_column("Machine")
+            .add_column("PID")
+            .add_column("CPU %")
+            .add_column("CPU Time")
+            .add_column("RSS")
+            .done();
+
+        for id in ids {
+            let Some(pid) = get_qemu_pid(ctx, *id)? else {
+                last.remove(id);
+                continue;
+            };
+
+            // The process may exit between reading the pid and its stats
+            let Ok(stats) = read_process_stats(pid) else {
+                continue;
+            };
+
+            let now = Instant::now();
+            let cpu_percent = match last.insert(*id, (now, stats.cpu_time)) {
+                Some((then, cpu_time)) => {
+                    let elapsed = now.duration_since(then).as_secs_f64();
+                    let used = stats.cpu_time.saturating_sub(cpu_time).as_secs_f64();
+                    format!("{:.1}", used / elapsed * 100.0)
+                }
+                None => "".to_string(),
+            };
+
+            table.push(id.to_string());
+            table.push(names[id].clone());
+            table.push(pid.to_string());
+            table.push(cpu_percent);
+            table.push(format!("{:.1}s", stats.cpu_time.as_secs_f64()));
+            table.push(
+                Byte::from_u64(stats.rss)
+                    .get_appropriate_unit(UnitType::Binary)
+                    .to_string(),
+            );
+        }
+
+        // Clears the screen and moves the cursor to the top left
+        print!("\x1b[2J\x1b[H");
+        table.print();
+
+        tokio::select! {
+            _ = ctx.cancel_token().cancelled() => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Formats seconds since the unix epoch as local time
+fn format_timestamp(timestamp: u64) -> String {
+    let time = timestamp as libc::time_t;
+    // SAFETY: tm is plain integers, plus a pointer that may be null until
+    // localtime_r fills it in
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers come from references that outlive the call, and
+    // localtime_r, unlike localtime, doesn't share a static buffer
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return timestamp.to_string();
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// Addresses handed out by DHCP aren't tracked, so those just show as dhcp
+fn get_address_label(config: &MachineNetworkConfig) -> String {
+    match config.get_ip_address() {
+        Some(ip) => ip.to_string(),
+        None => "dhcp".to_string(),
+    }
+}
+
+/// Public keys of the current user, so throwaway machines can be logged into
+/// right away
+fn get_default_ssh_keys() -> Vec<String> {
+    let Some(base_dirs) = BaseDirs::new() else {
+        return vec![];
+    };
+
+    ["id_ed25519.pub", "id_ecdsa.pub", "id_rsa.pub"]
+        .iter()
+        .filter_map(|name| {
+            std::fs::read_to_string(base_dirs.home_dir().join(".ssh").join(name)).ok()
+        })
+        .map(|key| key.trim().to_string())
+        .collect()
 }
@@ -0,0 +1,65 @@
+use std::{
+    path::PathBuf,
+    process::{Output, Stdio},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::process::Command;
+
+use crate::error::VmmError;
+
+/// Commands taking longer than this are assumed to be stuck
+pub const DEFAULT_CMD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs a command to completion, capturing its output
+pub async fn cmd(cmd: &str, args: &[&str]) -> Result<Output> {
+    cmd_with_timeout(cmd, args, DEFAULT_CMD_TIMEOUT).await
+}
+
+/// Runs a command like `cmd`, but kills it once `timeout` has passed
+pub async fn cmd_with_timeout(cmd: &str, args: &[&str], timeout: Duration) -> Result<Output> {
+    let child = Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context(format!("failed to spawn {}", cmd))?;
+
+    // Dropping the child on timeout kills it
+    let Ok(output) = tokio::time::timeout(timeout, child.wait_with_output()).await else {
+        return Err(VmmError::CommandTimeout {
+            cmd: format!("{} {}", cmd, args.join(" ")),
+            timeout,
+        }
+        .into());
+    };
+
+    let output = output.context(format!("failed to wait for {}", cmd))?;
+
+    Ok(output)
+}
+
+/// Runs a command like `cmd`, but fails with its stderr if it exits
+/// unsuccessfully
+pub async fn cmd_success(cmd: &str, args: &[&str]) -> Result<Output> {
+    let output = self::cmd(cmd, args).await?;
+    if !output.status.success() {
+        return Err(VmmError::CommandFailed {
+            cmd: format!("{} {}", cmd, args.join(" ")),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .into());
+    }
+    Ok(output)
+}
+
+pub fn find_program(name: &str) -> Option<PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,165 @@
+use std::path::Path;
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Self::Yaml,
+            _ => Self::Json,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, text: &str) -> Result<T> {
+        let value = match self {
+            Self::Json => serde_json::from_str(text)?,
+            Self::Yaml => serde_yaml::from_str(text)?,
+        };
+        Ok(value)
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String> {
+        let text = match self {
+            Self::Json => serde_json::to_string_pretty(value)?,
+            Self::Yaml => serde_yaml::to_string(value)?,
+        };
+        Ok(text)
+    }
+}
+
+/// Replaces `${VAR}` references in every string within `value` with the
+/// variable from the process environment. This is the only way configs are
+/// interpolated, so that every kind follows the same escaping rules.
+pub fn interpolate_value(value: &mut Value) -> Result<()> {
+    interpolate_value_with(value, &|name| std::env::var(name).ok())
+}
+
+fn interpolate_value_with(
+    value: &mut Value,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<()> {
+    match value {
+        Value::String(text) => *text = interpolate_env(text, lookup)?,
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                interpolate_value_with(value, lookup)?;
+            }
+        }
+        Value::Object(values) => {
+            for value in values.values_mut() {
+                interpolate_value_with(value, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces `${VAR}` with the value `lookup` gives for `VAR`, and `$$` with a
+/// literal `$`. A `$` followed by anything else is left as is.
+fn interpolate_env(text: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.peek() {
+            Some('$') => {
+                chars.next();
+                out.push('$');
+            }
+            Some('{') => {
+                chars.next();
+
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => bail!("unterminated variable reference: ${{{}", name),
+                    }
+                }
+
+                let value = lookup(&name)
+                    .ok_or_else(|| anyhow!("environment variable not set: {}", name))?;
+
+                out.push_str(&value);
+            }
+            _ => out.push('$'),
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOST" => Some("example.com".into()),
+            "PRICE" => Some("$5".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn interpolate_replaces_variables() {
+        let text = interpolate_env("https://${HOST}/image", &lookup).unwrap();
+        assert_eq!(text, "https://example.com/image");
+    }
+
+    #[test]
+    fn interpolate_escapes_dollar_signs() {
+        assert_eq!(interpolate_env("$${HOST}", &lookup).unwrap(), "${HOST}");
+        assert_eq!(interpolate_env("a$$$$b", &lookup).unwrap(), "a$$b");
+        assert_eq!(interpolate_env("$HOST $", &lookup).unwrap(), "$HOST $");
+        // values aren't interpolated again
+        assert_eq!(interpolate_env("${PRICE}", &lookup).unwrap(), "$5");
+    }
+
+    #[test]
+    fn interpolate_rejects_missing_variable() {
+        let e = interpolate_env("${MISSING}", &lookup).unwrap_err();
+        assert!(e.to_string().contains("MISSING"));
+    }
+
+    #[test]
+    fn interpolate_rejects_unterminated_reference() {
+        assert!(interpolate_env("${HOST", &lookup).is_err());
+        assert!(interpolate_env("a ${", &lookup).is_err());
+    }
+
+    #[test]
+    fn interpolate_value_reaches_nested_strings() {
+        let mut value = serde_json::json!({
+            "name": "${HOST}",
+            "cpus": 2,
+            "image": { "url": "https://${HOST}/image" },
+            "args": ["-a", ["${HOST}"], { "b": "$${HOST}" }],
+        });
+
+        interpolate_value_with(&mut value, &lookup).unwrap();
+
+        let expected = serde_json::json!({
+            "name": "example.com",
+            "cpus": 2,
+            "image": { "url": "https://example.com/image" },
+            "args": ["-a", ["example.com"], { "b": "${HOST}" }],
+        });
+        assert_eq!(value, expected);
+    }
+}
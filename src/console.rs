@@ -0,0 +1,162 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, unix::AsyncFd},
+    net::UnixStream,
+};
+
+/// Ctrl-], as used by telnet and virsh
+const DETACH_BYTE: u8 = 0x1d;
+
+/// Proxies the terminal to the serial console socket of an instance until the
+/// user detaches with Ctrl-] or qemu closes the socket
+pub async fn attach_console(socket_path: &Path) -> Result<()> {
+    attach_socket(socket_path, "console").await
+}
+
+/// Like `attach_console`, but for the qemu human monitor socket
+pub async fn attach_monitor(socket_path: &Path) -> Result<()> {
+    attach_socket(socket_path, "monitor").await
+}
+
+async fn attach_socket(socket_path: &Path, name: &str) -> Result<()> {
+    // qemu only serves one client at a time and queues the rest, which would
+    // look like a hang, so a lock makes a second attach fail instead
+    let lock_path = socket_path.with_extension("lock");
+    let lock_file = File::create(&lock_path)
+        .context(format!("failed to open {name} lock"))
+        .context(lock_path.display().to_string())?;
+
+    // SAFETY: the fd belongs to lock_file, which stays open for as long as the
+    // lock is held
+    if unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        bail!("{name} is already attached");
+    }
+
+    let stream = UnixStream::connect(socket_path)
+        .await
+        .context(format!("failed to connect to {name} socket"))
+        .context(socket_path.display().to_string())?;
+
+    let (mut reader, mut writer) = stream.into_split();
+
+    eprintln!("attached to {name}, press Ctrl-] to detach\r");
+
+    let terminal = RawTerminal::new()?;
+    let stdin = AsyncFd::new(std::io::stdin()).context("failed to poll stdin")?;
+
+    let mut stdin_buf = [0u8; 1024];
+    let mut socket_buf = [0u8; 4096];
+
+    loop {
+        tokio::select! {
+            guard = stdin.readable() => {
+                let mut guard = guard.context("failed to poll stdin")?;
+
+                let count = match guard.try_io(|stdin| stdin.get_ref().lock().read(&mut stdin_buf)) {
+                    Ok(count) => count.context("failed to read stdin")?,
+                    Err(_would_block) => continue,
+                };
+
+                if count == 0 {
+                    break;
+                }
+
+                let input = &stdin_buf[..count];
+                match input.iter().position(|byte| *byte == DETACH_BYTE) {
+                    Some(end) => {
+                        writer.write_all(&input[..end]).await?;
+                        break;
+                    }
+                    None => writer.write_all(input).await?,
+                }
+            }
+            count = reader.read(&mut socket_buf) => {
+                let count = count.with_context(|| format!("failed to read {name} socket"))?;
+                if count == 0 {
+                    break;
+                }
+
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&socket_buf[..count])?;
+                stdout.flush()?;
+            }
+        }
+    }
+
+    drop(terminal);
+
+    eprintln!("\ndetached from {name}");
+
+    Ok(())
+}
+
+/// Puts the terminal in raw, non-blocking mode so keystrokes go straight to
+/// the guest, and restores it when dropped
+struct RawTerminal {
+    termios: libc::termios,
+    flags: libc::c_int,
+}
+
+impl RawTerminal {
+    fn new() -> Result<Self> {
+        let fd = libc::STDIN_FILENO;
+
+        // SAFETY: isatty only inspects the fd, and stdin is always a valid fd
+        // number even if it's closed
+        if unsafe { libc::isatty(fd) } != 1 {
+            bail!("console requires a terminal");
+        }
+
+        // SAFETY: termios is plain integers and arrays, and is filled in by
+        // tcgetattr before it's used
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: termios is a valid, exclusively borrowed struct for the call
+        if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to get terminal mode");
+        }
+
+        // SAFETY: F_GETFL takes no argument and only reads the fd's flags
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to get stdin flags");
+        }
+
+        let terminal = Self { termios, flags };
+
+        let mut raw = termios;
+        // SAFETY: cfmakeraw only modifies the struct it's given
+        unsafe { libc::cfmakeraw(&mut raw) };
+
+        // SAFETY: raw is a valid termios that outlives the call
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to set terminal mode");
+        }
+
+        // SAFETY: F_SETFL takes an int of flags, which are the fd's own plus
+        // O_NONBLOCK
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to set stdin flags");
+        }
+
+        Ok(terminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let fd = libc::STDIN_FILENO;
+        // SAFETY: restores the flags and termios read in `new`, which are
+        // valid for this fd
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFL, self.flags);
+            libc::tcsetattr(fd, libc::TCSANOW, &self.termios);
+        }
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use byte_unit::Byte;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+};
+
+use url::Url;
+
+use crate::{
+    ctx::{BaseCtx, Ctx},
+    error::{VmmError, VmmResult},
+    id::Id,
+    image_cache::ImageHash,
+    instance::{AttachDiskOptions, DiskFormat},
+    server::{
+        InstanceSummary, Server, add_instance_share_dir, attach_instance_disk,
+        detach_instance_disk, eject_install_iso, get_instance_status, list_instances,
+        remove_instance_share_dir, resume_instance, set_instance_io_throttle, set_instance_memory,
+        start_instance, start_machine, stop_instance,
+    },
+};
+
+/// Messages larger than this are assumed to be garbage rather than allocated
+const MAX_MESSAGE_LEN: u32 = 16 * 1024 * 1024;
+
+const JSONRPC_VERSION: &str = "2.0";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Application errors are reported as this minus the `VmmError` exit code,
+/// which keeps them inside the range JSON-RPC reserves for servers
+const SERVER_ERROR_BASE: i64 = -32000;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
+    /// Requests without an id are notifications and get no response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    pub id: Value,
+}
+
+impl RpcResponse {
+    fn new(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: JSONRPC_VERSION.into(),
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<RpcErrorData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcErrorData {
+    pub kind: String,
+    pub exit_code: i32,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+impl From<VmmError> for RpcError {
+    fn from(e: VmmError) -> Self {
+        let exit_code = e.exit_code();
+        let kind = e.kind().to_string();
+        Self {
+            code: SERVER_ERROR_BASE - exit_code as i64,
+            message: format!("{:#}", anyhow!(e)),
+            data: Some(RpcErrorData { kind, exit_code }),
+        }
+    }
+}
+
+impl From<RpcError> for VmmError {
+    fn from(e: RpcError) -> Self {
+        VmmError::Server {
+            message: e.message,
+            exit_code: e.data.map_or(1, |data| data.exit_code),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdParams {
+    pub id: Id,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StartParams {
+    pub id: Id,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMemoryParams {
+    pub id: Id,
+    pub memory: Byte,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachDiskParams {
+    pub id: Id,
+    pub path: PathBuf,
+    pub format: DiskFormat,
+    #[serde(flatten)]
+    pub options: AttachDiskOptions,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetachDiskParams {
+    pub id: Id,
+    pub disk_id: String,
+}
+
+/// A limit that's left out removes that throttle
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetIoThrottleParams {
+    pub id: Id,
+    /// The root disk when left out
+    #[serde(default)]
+    pub disk_id: Option<String>,
+    #[serde(default)]
+    pub iops_limit: Option<u64>,
+    #[serde(default)]
+    pub bps_limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddShareDirParams {
+    pub id: Id,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoveShareDirParams {
+    pub id: Id,
+    pub tag: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImagePullParams {
+    pub url: Url,
+    #[serde(default)]
+    pub hash: Option<ImageHash>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImagePullResult {
+    pub hash: ImageHash,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageCancelParams {
+    pub url: Url,
+}
+
+/// Serves the control socket, which lets the CLI and other tools drive a
+/// running server. It speaks JSON-RPC 2.0, with messages in both directions
+/// prefixed by their big-endian u32 length.
+pub async fn serve_control(server: Arc<Mutex<Server>>, ctx: Ctx) -> Result<()> {
+    let socket_path = ctx.dirs().get_control_socket_path()?;
+
+    remove_stale_socket(&socket_path).await?;
+
+    let listener = UnixListener::bind(&socket_path)
+        .context("failed to bind control socket")
+        .context(socket_path.display().to_string())?;
+
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .context("failed to accept control connection")?;
+
+        let server = server.clone();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(server, ctx, stream).await {
+                eprintln!("error: control connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+/// A socket file left behind by a server that didn't shut down cleanly would
+/// make binding fail, but one that's still accepted belongs to a live server
+async fn remove_stale_socket(socket_path: &Path) -> Result<()> {
+    if !socket_path.exists() {
+        return Ok(());
+    }
+
+    if UnixStream::connect(socket_path).await.is_ok() {
+        bail!("server is already running: {}", socket_path.display());
+    }
+
+    tokio::fs::remove_file(socket_path)
+        .await
+        .context("failed to remove stale control socket")
+        .context(socket_path.display().to_string())?;
+
+    Ok(())
+}
+
+async fn handle_connection(
+    server: Arc<Mutex<Server>>,
+    ctx: Ctx,
+    mut stream: UnixStream,
+) -> Result<()> {
+    while let Some(buf) = read_frame(&mut stream).await? {
+        let message = match serde_json::from_slice::<Value>(&buf) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, format!("parse error: {}", e));
+                write_message(&mut stream, &RpcResponse::new(Value::Null, Err(error))).await?;
+                continue;
+            }
+        };
+
+        match message {
+            Value::Array(calls) if calls.is_empty() => {
+                let error = RpcError::new(INVALID_REQUEST, "invalid request: empty batch");
+                write_message(&mut stream, &RpcResponse::new(Value::Null, Err(error))).await?;
+            }
+            Value::Array(calls) => {
+                let mut responses = vec![];
+                for call in calls {
+                    if let Some(response) = handle_call(&server, &ctx, call).await {
+                        responses.push(response);
+                    }
+                }
+                // A batch of only notifications gets no response at all
+                if !responses.is_empty() {
+                    write_message(&mut stream, &responses).await?;
+                }
+            }
+            call => {
+                if let Some(response) = handle_call(&server, &ctx, call).await {
+                    write_message(&mut stream, &response).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn handle_call(server: &Arc<Mutex<Server>>, ctx: &Ctx, call: Value) -> Option<RpcResponse> {
+    let request = match serde_json::from_value::<RpcRequest>(call) {
+        Ok(request) if request.jsonrpc == JSONRPC_VERSION => request,
+        Ok(request) => {
+            let error = RpcError::new(INVALID_REQUEST, "invalid request: unsupported version");
+            return Some(RpcResponse::new(request.id.unwrap_or_default(), Err(error)));
+        }
+        Err(e) => {
+            let error = RpcError::new(INVALID_REQUEST, format!("invalid request: {}", e));
+            return Some(RpcResponse::new(Value::Null, Err(error)));
+        }
+    };
+
+    let result = dispatch(server, ctx, &request.method, request.params).await;
+
+    request.id.map(|id| RpcResponse::new(id, result))
+}
+
+async fn dispatch(
+    server: &Arc<Mutex<Server>>,
+    ctx: &Ctx,
+    method: &str,
+    params: Value,
+) -> Result<Value, RpcError> {
+    match method {
+        "instance.start" => {
+            let StartParams { id, paused } = parse_params(params)?;
+            start_instance(server, ctx, id, paused).await?;
+            Ok(Value::Null)
+        }
+        "instance.eject_cdrom" => {
+            let IdParams { id } = parse_params(params)?;
+            eject_install_iso(server, ctx, id).await?;
+            Ok(Value::Null)
+        }
+        "instance.resume" => {
+            let IdParams { id } = parse_params(params)?;
+            resume_instance(server, id).await?;
+            Ok(Value::Null)
+        }
+        "instance.set_memory" => {
+            let SetMemoryParams { id, memory } = parse_params(params)?;
+            set_instance_memory(server, ctx, id, memory).await?;
+            Ok(Value::Null)
+        }
+        "instance.attach_disk" => {
+            let AttachDiskParams {
+                id,
+                path,
+                format,
+                options,
+            } = parse_params(params)?;
+            let disk_id = attach_instance_disk(server, ctx, id, path, format, options).await?;
+            to_value(disk_id)
+        }
+        "instance.detach_disk" => {
+            let DetachDiskParams { id, disk_id } = parse_params(params)?;
+            detach_instance_disk(server, ctx, id, &disk_id).await?;
+            Ok(Value::Null)
+        }
+        "instance.set_io_throttle" => {
+            let SetIoThrottleParams {
+                id,
+                disk_id,
+                iops_limit,
+                bps_limit,
+            } = parse_params(params)?;
+            set_instance_io_throttle(server, id, disk_id.as_deref(), iops_limit, bps_limit).await?;
+            Ok(Value::Null)
+        }
+        "instance.add_share_dir" => {
+            let AddShareDirParams { id, path } = parse_params(params)?;
+            let tag = add_instance_share_dir(server, ctx, id, path).await?;
+            to_value(tag)
+        }
+        "instance.remove_share_dir" => {
+            let RemoveShareDirParams { id, tag } = parse_params(params)?;
+            remove_instance_share_dir(server, id, &tag).await?;
+            Ok(Value::Null)
+        }
+        "instance.stop" => {
+            let IdParams { id } = parse_params(params)?;
+            stop_instance(server, ctx, id).await?;
+            Ok(Value::Null)
+        }
+        "machine.start" => {
+            let IdParams { id } = parse_params(params)?;
+            to_value(start_machine(server, ctx, id).await?)
+        }
+        "instance.list" => to_value(list_instances(server).await),
+        "instance.status" => {
+            let IdParams { id } = parse_params(params)?;
+            to_value(get_instance_status(server, id).await?)
+        }
+        "image.pull" => {
+            // Downloads can take a long time, so this deliberately doesn't
+            // hold the server lock
+            let ImagePullParams { url, hash } = parse_params(params)?;
+            let result = ctx
+                .image_manager()
+                .get_image_hash(ctx, url.clone(), hash.clone())
+                .await?;
+            let hash = result.into_hash(&url, hash.as_ref())?;
+            to_value(ImagePullResult { hash })
+        }
+        "image.cancel" => {
+            let ImageCancelParams { url } = parse_params(params)?;
+            ctx.image_manager().cancel(url).await?;
+            Ok(Value::Null)
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("method not found: {}", method),
+        )),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params)
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("invalid params: {}", e)))
+}
+
+fn to_value(value: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(value)
+        .context("failed to serialize control response")
+        .map_err(|e| VmmError::from(e).into())
+}
+
+/// Connection to a running server's control socket
+pub struct ControlClient {
+    stream: UnixStream,
+    next_id: u64,
+}
+
+impl ControlClient {
+    /// Returns `None` if no server is running
+    pub async fn connect(ctx: &BaseCtx) -> Result<Option<Self>> {
+        let socket_path = ctx.dirs().get_control_socket_path()?;
+
+        match UnixStream::connect(&socket_path).await {
+            Ok(stream) => Ok(Some(Self { stream, next_id: 1 })),
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+                Ok(None)
+            }
+            Err(e) => Err(e)
+                .context("failed to connect to control socket")
+                .context(socket_path.display().to_string()),
+        }
+    }
+
+    /// Like `connect`, but fails if no server is running
+    pub async fn connect_required(ctx: &BaseCtx) -> Result<Self> {
+        Self::connect(ctx)
+            .await?
+            .ok_or(anyhow!("server is not running; start it with `vmm server`"))
+    }
+
+    pub async fn request<T: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: impl Serialize,
+    ) -> VmmResult<T> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let request = RpcRequest {
+            jsonrpc: JSONRPC_VERSION.into(),
+            method: method.into(),
+            params: serde_json::to_value(params).context("failed to serialize control request")?,
+            id: Some(id.into()),
+        };
+        write_message(&mut self.stream, &request).await?;
+
+        let buf = read_frame(&mut self.stream)
+            .await?
+            .ok_or(anyhow!("server closed the control connection"))?;
+        let response: RpcResponse =
+            serde_json::from_slice(&buf).context("failed to parse control response")?;
+
+        if response.id != id {
+            return Err(anyhow!("control response has unexpected id: {}", response.id).into());
+        }
+
+        if let Some(error) = response.error {
+            return Err(error.into());
+        }
+
+        let result = response.result.unwrap_or_default();
+        Ok(serde_json::from_value(result).context("failed to parse control response")?)
+    }
+
+    pub async fn start_instance(&mut self, id: Id, paused: bool) -> VmmResult<()> {
+        self.request("instance.start", StartParams { id, paused })
+            .await
+    }
+
+    pub async fn eject_cdrom(&mut self, id: Id) -> VmmResult<()> {
+        self.request("instance.eject_cdrom", IdParams { id }).await
+    }
+
+    pub async fn resume_instance(&mut self, id: Id) -> VmmResult<()> {
+        self.request("instance.resume", IdParams { id }).await
+    }
+
+    pub async fn set_memory(&mut self, id: Id, memory: Byte) -> VmmResult<()> {
+        self.request("instance.set_memory", SetMemoryParams { id, memory })
+            .await
+    }
+
+    /// Returns the id of the attached disk
+    pub async fn attach_disk(&mut self, params: AttachDiskParams) -> VmmResult<String> {
+        self.request("instance.attach_disk", params).await
+    }
+
+    pub async fn detach_disk(&mut self, id: Id, disk_id: String) -> VmmResult<()> {
+        self.request("instance.detach_disk", DetachDiskParams { id, disk_id })
+            .await
+    }
+
+    pub async fn set_io_throttle(&mut self, params: SetIoThrottleParams) -> VmmResult<()> {
+        self.request("instance.set_io_throttle", params).await
+    }
+
+    /// Returns the tag the guest mounts the share by
+    pub async fn add_share_dir(&mut self, id: Id, path: PathBuf) -> VmmResult<String> {
+        self.request("instance.add_share_dir", AddShareDirParams { id, path })
+            .await
+    }
+
+    pub async fn remove_share_dir(&mut self, id: Id, tag: String) -> VmmResult<()> {
+        self.request(
+            "instance.remove_share_dir",
+            RemoveShareDirParams { id, tag },
+        )
+        .await
+    }
+
+    pub async fn stop_instance(&mut self, id: Id) -> VmmResult<()> {
+        self.request("instance.stop", IdParams { id }).await
+    }
+
+    /// Returns the id of the instance that was started
+    pub async fn start_machine(&mut self, id: Id) -> VmmResult<Id> {
+        self.request("machine.start", IdParams { id }).await
+    }
+
+    pub async fn list(&mut self) -> VmmResult<Vec<InstanceSummary>> {
+        self.request("instance.list", Value::Null).await
+    }
+
+    pub async fn status(&mut self, id: Id) -> VmmResult<InstanceSummary> {
+        self.request("instance.status", IdParams { id }).await
+    }
+
+    pub async fn pull_image(&mut self, url: Url, hash: Option<ImageHash>) -> VmmResult<ImageHash> {
+        let result: ImagePullResult = self
+            .request("image.pull", ImagePullParams { url, hash })
+            .await?;
+        Ok(result.hash)
+    }
+
+    pub async fn cancel_image(&mut self, url: Url) -> VmmResult<()> {
+        self.request("image.cancel", ImageCancelParams { url })
+            .await
+    }
+}
+
+/// Reads one raw message, or `None` if the peer closed the connection between
+/// messages
+async fn read_frame(stream: &mut UnixStream) -> Result<Option<Vec<u8>>> {
+    let len = match stream.read_u32().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).context("failed to read control message"),
+    };
+
+    if len > MAX_MESSAGE_LEN {
+        bail!("control message too large: {} bytes", len);
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .context("failed to read control message")?;
+
+    Ok(Some(buf))
+}
+
+async fn write_message(stream: &mut UnixStream, message: &impl Serialize) -> Result<()> {
+    let buf = serde_json::to_vec(message).context("failed to serialize control message")?;
+
+    stream
+        .write_u32(buf.len() as u32)
+        .await
+        .context("failed to write control message")?;
+    stream
+        .write_all(&buf)
+        .await
+        .context("failed to write control message")?;
+
+    Ok(())
+}
@@ -1,42 +1,47 @@
+use std::ops::Deref;
+
+use anyhow::{Context, Result};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    image_cache::ImageCacheClient, logger::Logger, progress_router::ProgressRouterClient,
+    image_cache::ImageCacheClient,
+    logger::{LogRotation, Logger},
     vmm_dirs::VmmDirs,
 };
 
+/// Context for code that only needs the dirs and logger, like most CLI
+/// commands
 #[derive(Clone)]
-pub struct Ctx {
+pub struct BaseCtx {
     cancel_token: CancellationToken,
     dirs: VmmDirs,
     logger: Logger,
-    image_manager: Option<ImageCacheClient>,
-    progress_router: Option<ProgressRouterClient>,
 }
 
-impl Ctx {
-    pub fn new() -> Self {
-        let dirs = VmmDirs::new().expect("failed to initialize vmm dirs");
-        Self {
+impl BaseCtx {
+    pub fn new() -> Result<Self> {
+        let dirs = VmmDirs::new().context("failed to initialize vmm dirs")?;
+        let rotation = LogRotation::from_env()?;
+        Ok(Self {
             cancel_token: CancellationToken::new(),
             dirs: dirs.clone(),
-            logger: Logger::new(dirs),
-            image_manager: None,
-            progress_router: None,
-        }
+            logger: Logger::new(dirs, rotation),
+        })
     }
 
-    pub fn with_image_manager(self, image_manager: ImageCacheClient) -> Self {
+    #[cfg(test)]
+    pub fn from_dirs(dirs: VmmDirs) -> Self {
         Self {
-            image_manager: Some(image_manager),
-            ..self
+            cancel_token: CancellationToken::new(),
+            dirs: dirs.clone(),
+            logger: Logger::new(dirs, LogRotation::Utc),
         }
     }
 
-    pub fn with_progress_router(self, progress_router: ProgressRouterClient) -> Self {
-        Self {
-            progress_router: Some(progress_router),
-            ..self
+    pub fn builder(self) -> CtxBuilder {
+        CtxBuilder {
+            base: self,
+            image_manager: (),
         }
     }
 
@@ -51,16 +56,50 @@ impl Ctx {
     pub fn logger(&self) -> &Logger {
         &self.logger
     }
+}
+
+/// Fully-wired context for code that downloads images, which in practice
+/// means starting instances
+#[derive(Clone)]
+pub struct Ctx {
+    base: BaseCtx,
+    image_manager: ImageCacheClient,
+}
 
+impl Ctx {
     pub fn image_manager(&self) -> &ImageCacheClient {
-        self.image_manager
-            .as_ref()
-            .expect("image_mananger not set on context")
+        &self.image_manager
     }
+}
 
-    pub fn progress_router(&self) -> &ProgressRouterClient {
-        self.progress_router
-            .as_ref()
-            .expect("progress_tracker not set on context")
+impl Deref for Ctx {
+    type Target = BaseCtx;
+
+    fn deref(&self) -> &BaseCtx {
+        &self.base
+    }
+}
+
+/// Builds a `Ctx`, which can only be done once every service has been set
+pub struct CtxBuilder<I = ()> {
+    base: BaseCtx,
+    image_manager: I,
+}
+
+impl<I> CtxBuilder<I> {
+    pub fn image_manager(self, image_manager: ImageCacheClient) -> CtxBuilder<ImageCacheClient> {
+        CtxBuilder {
+            base: self.base,
+            image_manager,
+        }
+    }
+}
+
+impl CtxBuilder<ImageCacheClient> {
+    pub fn build(self) -> Ctx {
+        Ctx {
+            base: self.base,
+            image_manager: self.image_manager,
+        }
     }
 }
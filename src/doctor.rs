@@ -0,0 +1,35 @@
+use anyhow::{Result, anyhow};
+
+use crate::{
+    cmd::find_program,
+    instance::{check_kvm, find_qemu},
+    share_dir::find_virtiofsd,
+};
+
+/// Checks that the host has what starting instances needs, printing the
+/// result of each check. Returns whether they all passed.
+pub fn doctor() -> bool {
+    let checks: Vec<(&str, Result<()>)> = vec![
+        ("qemu", find_qemu().map(|_| ())),
+        (
+            "qemu-img",
+            find_program("qemu-img")
+                .map(|_| ())
+                .ok_or(anyhow!("qemu-img not found; install qemu-utils")),
+        ),
+        ("virtiofsd", find_virtiofsd().map(|_| ())),
+        ("kvm", check_kvm()),
+    ];
+
+    let mut ok = true;
+    for (name, result) in checks {
+        match result {
+            Ok(()) => println!("ok: {}", name),
+            Err(e) => {
+                println!("error: {}: {}", name, e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
@@ -0,0 +1,85 @@
+use std::{process::ExitStatus, time::Duration};
+
+use thiserror::Error;
+
+use crate::id::Id;
+
+pub type VmmResult<T> = Result<T, VmmError>;
+
+#[derive(Debug, Error)]
+pub enum VmmError {
+    #[error("machine not found: {0}")]
+    MachineNotFound(Id),
+
+    #[error("network not found: {0}")]
+    NetworkNotFound(Id),
+
+    #[error("instance not found: {0}")]
+    InstanceNotFound(Id),
+
+    #[error("instance is busy: {0}")]
+    InstanceBusy(Id),
+
+    #[error("instance is already running: {0}")]
+    InstanceRunning(Id),
+
+    #[error("image hash mismatch, expected {expected} but got {actual}")]
+    ImageHashMismatch { expected: String, actual: String },
+
+    #[error("failed to spawn qemu")]
+    QemuSpawn(#[source] std::io::Error),
+
+    #[error("command `{cmd}` failed with {status}: {stderr}")]
+    CommandFailed {
+        cmd: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+
+    #[error("command `{cmd}` timed out after {}s", .timeout.as_secs())]
+    CommandTimeout { cmd: String, timeout: Duration },
+
+    #[error("instance start timed out after {}s", .0.as_secs())]
+    StartTimeout(Duration),
+
+    /// An error that happened in the server, relayed over the control socket
+    #[error("{message}")]
+    Server { message: String, exit_code: i32 },
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl VmmError {
+    /// Process exit code for the CLI, so scripts can tell failures apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::MachineNotFound(_) | Self::NetworkNotFound(_) | Self::InstanceNotFound(_) => 2,
+            Self::ImageHashMismatch { .. } => 3,
+            Self::QemuSpawn(_) | Self::CommandFailed { .. } => 4,
+            Self::CommandTimeout { .. } | Self::StartTimeout(_) => 5,
+            Self::InstanceBusy(_) | Self::InstanceRunning(_) => 6,
+            Self::Server { exit_code, .. } => *exit_code,
+            Self::Other(e) => e.downcast_ref::<VmmError>().map_or(1, VmmError::exit_code),
+        }
+    }
+
+    /// Stable name of the error variant, for clients that need more than the
+    /// message
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::MachineNotFound(_) => "machine_not_found",
+            Self::NetworkNotFound(_) => "network_not_found",
+            Self::InstanceNotFound(_) => "instance_not_found",
+            Self::InstanceBusy(_) => "instance_busy",
+            Self::InstanceRunning(_) => "instance_running",
+            Self::ImageHashMismatch { .. } => "image_hash_mismatch",
+            Self::QemuSpawn(_) => "qemu_spawn",
+            Self::CommandFailed { .. } => "command_failed",
+            Self::CommandTimeout { .. } => "command_timeout",
+            Self::StartTimeout(_) => "start_timeout",
+            Self::Server { .. } => "server",
+            Self::Other(e) => e.downcast_ref::<VmmError>().map_or("other", VmmError::kind),
+        }
+    }
+}
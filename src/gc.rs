@@ -0,0 +1,151 @@
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    control::ControlClient,
+    ctx::BaseCtx,
+    id::Id,
+    instance::{Instance, InstanceState, QemuStatus, get_qemu_pid},
+    network::{Network, NetworkMode},
+};
+
+/// Sweeps up what crashed instances leave behind: sockets in the runtime dir,
+/// the pid files and tap devices of instances whose qemu is gone, and bridges
+/// and DHCP servers of networks no instance uses anymore. Anything belonging
+/// to an instance with a live qemu, or one the server is starting, is left
+/// alone.
+pub async fn collect_garbage(ctx: &BaseCtx) -> Result<()> {
+    let instance_ids = ctx.dirs().get_instance_state_ids()?;
+
+    let mut live_ids = HashSet::new();
+    for id in instance_ids.iter() {
+        if get_qemu_pid(ctx, *id)?.is_some() {
+            live_ids.insert(*id);
+        }
+    }
+
+    // An instance that's starting has no qemu yet, but already has its
+    // sockets, tap and bridge being set up
+    if let Some(mut client) = ControlClient::connect(ctx).await? {
+        for summary in client.list().await? {
+            if summary.running || summary.status == QemuStatus::Starting {
+                live_ids.insert(summary.id);
+            }
+        }
+    }
+
+    let mut live_network_ids = HashSet::new();
+    for id in live_ids.iter() {
+        match InstanceState::open(ctx, *id).await {
+            Ok(state) => live_network_ids.extend(state.network_id),
+            Err(e) => eprintln!("error: {:?}", e),
+        }
+    }
+
+    // Sockets of deleted instances are swept too, since they're matched by
+    // name rather than by looking up the instance
+    let runtime_dir = ctx.dirs().get_runtime_dir()?;
+    let entries = std::fs::read_dir(&runtime_dir)
+        .context("failed to read runtime dir")
+        .context(runtime_dir.display().to_string())?;
+
+    for entry in entries {
+        let path = entry.context("failed to read runtime dir")?.path();
+        let Some(id) = get_socket_instance_id(&path) else {
+            continue;
+        };
+        if live_ids.contains(&id) {
+            continue;
+        }
+        if remove_stale_file(&path)? {
+            println!("removed socket: {}", path.display());
+        }
+    }
+
+    for id in instance_ids {
+        if live_ids.contains(&id) {
+            continue;
+        }
+
+        let pid_path = ctx.dirs().get_instance_pid_path(id)?;
+        if remove_stale_file(&pid_path)? {
+            println!("removed pid file: {}", pid_path.display());
+        }
+
+        let instance = match Instance::open(ctx, id).await {
+            Ok(instance) => instance,
+            Err(e) => {
+                eprintln!("error: {:?}", e);
+                continue;
+            }
+        };
+
+        let Some(network) = instance.get_bridged_network() else {
+            continue;
+        };
+
+        let tap = network.get_tap_name(&instance);
+        if !PathBuf::from("/sys/class/net").join(&tap).exists() {
+            continue;
+        }
+
+        match network.delete_tap_device(&instance).await {
+            Ok(()) => println!("removed tap device: {}", tap),
+            Err(e) => eprintln!("error: failed to remove tap device {}: {:?}", tap, e),
+        }
+    }
+
+    for id in ctx.dirs().get_network_config_ids()? {
+        if live_network_ids.contains(&id) {
+            continue;
+        }
+
+        let network = match Network::open(ctx, id).await {
+            Ok(network) => network,
+            Err(e) => {
+                eprintln!("error: {:?}", e);
+                continue;
+            }
+        };
+        if network.config().mode != NetworkMode::Bridge {
+            continue;
+        }
+
+        match network.delete_bridge_if_unused(ctx).await {
+            Ok(true) => println!("removed bridge: {}", network.get_bridge_name()),
+            Ok(false) => {}
+            Err(e) => eprintln!("error: failed to remove bridge of network {}: {:?}", id, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes a file that may not exist. Returns whether there was one.
+pub fn remove_stale_file(path: &Path) -> Result<bool> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e)
+            .context("failed to remove stale file")
+            .context(path.display().to_string()),
+    }
+}
+
+/// Parses the instance id out of a socket named by `VmmDirs`, i.e.
+/// `{kind}-{id}.sock` or `virtiofs-{id}-{tag}.sock`
+fn get_socket_instance_id(path: &Path) -> Option<Id> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".sock")?;
+    let (kind, rest) = name.split_once('-')?;
+    let id = match kind {
+        "qmp" | "serial" | "hmp" => rest,
+        "virtiofs" => rest.split_once('-')?.0,
+        _ => return None,
+    };
+    id.parse().ok()
+}
@@ -0,0 +1,204 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result, anyhow, bail};
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{net::TcpListener, sync::Mutex};
+
+use crate::{
+    control::{ImagePullParams, ImagePullResult},
+    ctx::{BaseCtx, Ctx},
+    error::VmmError,
+    id::Id,
+    server::{self, InstanceSummary, MachineSummary, Server},
+};
+
+#[derive(Clone)]
+struct ApiState {
+    server: Arc<Mutex<Server>>,
+    ctx: Ctx,
+    token: Option<String>,
+}
+
+/// Serves the REST API until the ctx is cancelled. Requests need the bearer
+/// token from the config dir's `http-token` file if there is one, which is
+/// required unless the API is only bound to loopback.
+pub async fn serve_http_api(server: Arc<Mutex<Server>>, ctx: Ctx, addr: SocketAddr) -> Result<()> {
+    let token = read_token(&ctx).await?;
+    if token.is_none() {
+        if !addr.ip().is_loopback() {
+            bail!(
+                "http api needs a token in {} to listen on {}",
+                ctx.dirs().get_http_token_path()?.display(),
+                addr
+            );
+        }
+        eprintln!(
+            "warning: http api has no token, any local user can control vmm through {}",
+            addr
+        );
+    }
+
+    let state = ApiState {
+        server,
+        ctx: ctx.clone(),
+        token,
+    };
+
+    let app = Router::new()
+        .route("/machines", get(get_machines))
+        .route("/instances", get(get_instances))
+        .route("/instances/{id}/start", post(start_instance))
+        .route("/instances/{id}/stop", post(stop_instance))
+        .route("/images/pull", post(pull_image))
+        .layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state);
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .context("failed to bind http api address")
+        .context(addr)?;
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(ctx.cancel_token().clone().cancelled_owned())
+        .await
+        .context("http api server failed")?;
+
+    Ok(())
+}
+
+async fn read_token(ctx: &BaseCtx) -> Result<Option<String>> {
+    let path = ctx.dirs().get_http_token_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let token = tokio::fs::read_to_string(&path)
+        .await
+        .context("failed to read http api token")
+        .context(path.display().to_string())?;
+
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(anyhow!("http api token is empty")).context(path.display().to_string());
+    }
+
+    Ok(Some(token.to_string()))
+}
+
+async fn authorize(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    if let Some(token) = &state.token {
+        let authorized = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| value == token);
+
+        if !authorized {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                Json(json!({ "error": "unauthorized" })),
+            )
+                .into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+async fn get_machines(State(state): State<ApiState>) -> Json<Vec<MachineSummary>> {
+    Json(state.server.lock().await.list_machines())
+}
+
+async fn get_instances(State(state): State<ApiState>) -> Json<Vec<InstanceSummary>> {
+    Json(server::list_instances(&state.server).await)
+}
+
+#[derive(Deserialize)]
+struct StartQuery {
+    #[serde(default)]
+    paused: bool,
+}
+
+async fn start_instance(
+    State(state): State<ApiState>,
+    Path(id): Path<Id>,
+    Query(query): Query<StartQuery>,
+) -> Result<StatusCode, ApiError> {
+    server::start_instance(&state.server, &state.ctx, id, query.paused).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn stop_instance(
+    State(state): State<ApiState>,
+    Path(id): Path<Id>,
+) -> Result<StatusCode, ApiError> {
+    server::stop_instance(&state.server, &state.ctx, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn pull_image(
+    State(state): State<ApiState>,
+    Json(params): Json<ImagePullParams>,
+) -> Result<Json<ImagePullResult>, ApiError> {
+    let ImagePullParams { url, hash } = params;
+    let result = state
+        .ctx
+        .image_manager()
+        .get_image_hash(&state.ctx, url.clone(), hash.clone())
+        .await?;
+    let hash = result.into_hash(&url, hash.as_ref())?;
+    Ok(Json(ImagePullResult { hash }))
+}
+
+struct ApiError(VmmError);
+
+impl From<VmmError> for ApiError {
+    fn from(e: VmmError) -> Self {
+        Self(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let kind = self.0.kind();
+        let exit_code = self.0.exit_code();
+        let status = get_status_code(&self.0);
+
+        let body = json!({
+            "error": format!("{:#}", anyhow!(self.0)),
+            "kind": kind,
+            "exit_code": exit_code,
+        });
+
+        (status, Json(body)).into_response()
+    }
+}
+
+fn get_status_code(e: &VmmError) -> StatusCode {
+    match e {
+        VmmError::MachineNotFound(_)
+        | VmmError::NetworkNotFound(_)
+        | VmmError::InstanceNotFound(_) => StatusCode::NOT_FOUND,
+        VmmError::InstanceBusy(_)
+        | VmmError::InstanceRunning(_)
+        | VmmError::ImageHashMismatch { .. } => StatusCode::CONFLICT,
+        VmmError::CommandTimeout { .. } | VmmError::StartTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        VmmError::QemuSpawn(_) | VmmError::CommandFailed { .. } | VmmError::Server { .. } => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        VmmError::Other(e) => e
+            .downcast_ref::<VmmError>()
+            .map_or(StatusCode::INTERNAL_SERVER_ERROR, get_status_code),
+    }
+}
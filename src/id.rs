@@ -5,6 +5,10 @@ use base_62::base62;
 use rand_core::{OsRng, TryRngCore};
 use serde::{Deserialize, Serialize};
 
+/// Ids are zero-padded to the length of the largest one so that they
+/// round-trip and sort lexicographically in the same order as numerically
+const ENCODED_LEN: usize = 22;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(try_from = "String", into = "String")]
 pub struct Id(u128);
@@ -17,17 +21,22 @@ impl Id {
             .map_err(|e| anyhow::anyhow!(e))?;
         Ok(Id(u128::from_be_bytes(bytes)))
     }
+
+    fn encode(&self) -> String {
+        let encoded = base62::encode(&self.0.to_be_bytes());
+        format!("{:0>width$}", encoded, width = ENCODED_LEN)
+    }
 }
 
 impl Into<String> for Id {
     fn into(self) -> String {
-        base62::encode(&self.0.to_be_bytes())
+        self.encode()
     }
 }
 
 impl Into<String> for &Id {
     fn into(self) -> String {
-        base62::encode(&self.0.to_be_bytes())
+        self.encode()
     }
 }
 
@@ -47,10 +56,32 @@ impl FromStr for Id {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let id = base62::decode(s).map_err(|e| anyhow::anyhow!(e))?;
-        let bytes: [u8; 16] = id
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("invalid base62 string"))?;
+        // Ids from before they were padded are shorter, and still decode to
+        // the same number once padded
+        let len = s.chars().count();
+        if len == 0 || len > ENCODED_LEN {
+            anyhow::bail!(
+                "expected base62 id of up to {} chars, got {} chars: {}",
+                ENCODED_LEN,
+                len,
+                s
+            );
+        }
+
+        if let Some(c) = s.chars().find(|c| !c.is_ascii_alphanumeric()) {
+            anyhow::bail!("invalid character in base62 id: {:?} in {}", c, s);
+        }
+
+        // Leading zero bytes aren't encoded, and leading zero digits from the
+        // padding don't decode to anything, so the decoded bytes are padded
+        // back out
+        let padded = format!("{:0>width$}", s, width = ENCODED_LEN);
+        let decoded = base62::decode(&padded).map_err(|e| anyhow::anyhow!(e))?;
+        if decoded.len() > 16 {
+            anyhow::bail!("base62 id out of range: {}", s);
+        }
+        let mut bytes = [0u8; 16];
+        bytes[16 - decoded.len()..].copy_from_slice(&decoded);
         let id = u128::from_be_bytes(bytes);
         Ok(Id(id))
     }
@@ -66,8 +97,57 @@ impl TryFrom<String> for Id {
 
 impl Display for Id {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let bytes: [u8; 16] = self.0.to_be_bytes();
-        let encoded = base62::encode(&bytes);
-        f.write_str(&encoded)
+        f.write_str(&self.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_string() {
+        let mut ids = vec![Id(0), Id(1), Id(61), Id(62), Id(u128::MAX)];
+
+        // Leading zero bytes, which base62 doesn't encode
+        for shift in (0..128).step_by(8) {
+            ids.push(Id(1 << shift));
+            ids.push(Id(u128::MAX >> shift));
+        }
+
+        for _ in 0..1000 {
+            ids.push(Id::new().unwrap());
+        }
+
+        for id in ids {
+            let encoded = id.to_string();
+            assert_eq!(encoded.len(), ENCODED_LEN, "{}", encoded);
+            assert_eq!(Id::from_str(&encoded).unwrap(), id, "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn parses_unpadded_ids() {
+        for _ in 0..1000 {
+            let id = Id::new().unwrap();
+            let unpadded = base62::encode(&id.0.to_be_bytes());
+            assert_eq!(Id::from_str(&unpadded).unwrap(), id, "{}", unpadded);
+        }
+
+        assert_eq!(Id::from_str("1").unwrap(), Id(1));
+        assert!(Id::from_str("").is_err());
+        assert!(Id::from_str(&"1".repeat(ENCODED_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn sorts_like_the_number() {
+        let mut ids: Vec<Id> = (0..1000).map(|_| Id::new().unwrap()).collect();
+        ids.extend([Id(0), Id(1), Id(u128::MAX)]);
+
+        let mut by_string = ids.clone();
+        by_string.sort_by_key(|id| id.to_string());
+        ids.sort_by_key(|id| id.0);
+
+        assert_eq!(by_string, ids);
     }
 }
@@ -1,14 +1,16 @@
 use std::{
     collections::HashMap,
+    path::Path,
     sync::{
         Arc,
         atomic::{AtomicUsize, Ordering},
     },
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 use futures::StreamExt;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tokio::{
     io::AsyncWriteExt,
@@ -18,16 +20,25 @@ use tokio_util::sync::CancellationToken;
 use url::Url;
 
 use crate::{
-    ctx::Ctx,
-    progress_router::ProgressMessage,
+    ctx::BaseCtx,
+    error::{VmmError, VmmResult},
+    progress_router::{ProgressMessage, ProgressRouterClient, ProgressUpdate},
+    qemu_img::{convert_to_qcow2, get_image_format},
     task_actor::{TaskActor, TaskActorEvent},
     task_group::{TaskGroup, TaskId},
 };
 
-pub fn create_image_cache(ctx: Ctx, task_group: &mut TaskGroup<Result<()>>) -> ImageCacheClient {
+pub fn create_image_cache(
+    ctx: BaseCtx,
+    progress_router: ProgressRouterClient,
+    config: ImageCacheConfig,
+    task_group: &mut TaskGroup<Result<()>>,
+) -> ImageCacheClient {
     let (sender, receiver) = mpsc::channel(100);
     let image_cache = ImageCache::new(
         ctx.clone(),
+        progress_router,
+        config,
         sender.clone(),
         receiver,
         ctx.cancel_token().clone(),
@@ -43,6 +54,36 @@ pub fn create_image_cache(ctx: Ctx, task_group: &mut TaskGroup<Result<()>>) -> I
 
 pub type ImageHash = String;
 
+#[derive(Debug, Clone)]
+pub struct ImageCacheConfig {
+    /// How long a url is trusted to still point at the image it was last
+    /// downloaded as before it's downloaded again
+    pub url_hash_ttl: Duration,
+    /// Downloads are aborted once no data has arrived for this long
+    pub download_inactivity_timeout: Duration,
+    /// Upper bound on a whole download, however much progress it's making
+    pub download_timeout: Duration,
+    /// Converts raw images to qcow2 when they're cached, at the cost of time
+    /// and temporary space. They're still keyed by the hash of the downloaded
+    /// bytes, so they can be verified against the upstream checksum.
+    pub convert_raw_images: bool,
+    /// How long in-flight downloads get to cancel cleanly on shutdown before
+    /// they're aborted
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ImageCacheConfig {
+    fn default() -> Self {
+        Self {
+            url_hash_ttl: Duration::from_secs(24 * 60 * 60),
+            download_inactivity_timeout: Duration::from_secs(60),
+            download_timeout: Duration::from_secs(12 * 60 * 60),
+            convert_raw_images: false,
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ImageCacheMessage {
     GetImageHash {
@@ -51,18 +92,156 @@ pub enum ImageCacheMessage {
         response: oneshot::Sender<GetImageHashResult>,
     },
     GetImageHashResult(Url, GetImageHashResult),
+    Cancel(Url),
 }
 
 #[derive(Debug, Clone)]
 pub enum GetImageHashResult {
     ImageCached(ImageHash),
-    DownloadNoContentLength,
     DownloadFailed(reqwest::StatusCode),
     DownloadFailedToReadChunk,
+    DownloadStalled,
     DownloadCancelled,
     UnknownError,
 }
 
+/// Durable record of completed downloads, so that a fresh process knows which
+/// urls are already cached without going to the network
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ImageCacheIndex {
+    urls: HashMap<Url, ImageCacheIndexEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImageCacheIndexEntry {
+    hash: ImageHash,
+    cached_at: u64,
+    last_access: u64,
+}
+
+impl ImageCacheIndex {
+    fn load(ctx: &BaseCtx) -> Result<Self> {
+        let index_path = ctx.dirs().get_image_cache_index_path()?;
+        if !index_path.exists() {
+            return Ok(Self::default());
+        }
+
+        let index_text = std::fs::read_to_string(&index_path)
+            .context("failed to read image cache index")
+            .context(index_path.display().to_string())?;
+
+        let index = serde_json::from_str(&index_text)
+            .context("failed to parse image cache index")
+            .context(index_path.display().to_string())?;
+
+        Ok(index)
+    }
+
+    async fn save(&self, ctx: &BaseCtx) -> Result<()> {
+        let index_path = ctx.dirs().get_image_cache_index_path()?;
+        let index_dir = index_path.parent().ok_or(anyhow!("invalid path"))?;
+
+        tokio::fs::create_dir_all(index_dir).await?;
+
+        let index_text = serde_json::to_string(&self)?;
+
+        // Written to the side and renamed so a crash never leaves a torn index
+        let temp_path = index_path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, index_text)
+            .await
+            .context("failed to write image cache index")
+            .context(temp_path.display().to_string())?;
+        tokio::fs::rename(&temp_path, &index_path)
+            .await
+            .context("failed to write image cache index")
+            .context(index_path.display().to_string())?;
+
+        Ok(())
+    }
+
+    /// Returns the hash cached for `url`, if its image is still on disk and
+    /// it was downloaded less than `ttl` ago
+    fn get(&mut self, ctx: &BaseCtx, url: &Url, ttl: Duration) -> Option<ImageHash> {
+        let entry = self.urls.get_mut(url)?;
+        if get_unix_time().saturating_sub(entry.cached_at) >= ttl.as_secs() {
+            return None;
+        }
+        let image_cache_path = ctx.dirs().get_image_cache_path(&entry.hash).ok()?;
+        if !image_cache_path.exists() {
+            self.urls.remove(url);
+            return None;
+        }
+        entry.last_access = get_unix_time();
+        Some(entry.hash.clone())
+    }
+
+    fn insert(&mut self, url: Url, hash: ImageHash) {
+        let now = get_unix_time();
+        let entry = ImageCacheIndexEntry {
+            hash,
+            cached_at: now,
+            last_access: now,
+        };
+        self.urls.insert(url, entry);
+    }
+}
+
+fn get_unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+impl GetImageHashResult {
+    /// Returns the hash of the cached image, or an error describing why the
+    /// download failed or how it differs from the expected hash
+    pub fn into_hash(self, url: &Url, expected_hash: Option<&ImageHash>) -> VmmResult<ImageHash> {
+        let message = match self {
+            Self::ImageCached(hash) => match expected_hash {
+                Some(expected_hash) if *expected_hash != hash => {
+                    return Err(VmmError::ImageHashMismatch {
+                        expected: expected_hash.clone(),
+                        actual: hash,
+                    });
+                }
+                _ => return Ok(hash),
+            },
+            Self::DownloadFailed(status_code) => format!("image download failed: {}", status_code),
+            Self::DownloadFailedToReadChunk => "image download failed to read chunk".into(),
+            Self::DownloadStalled => "image download stalled".into(),
+            Self::DownloadCancelled => "image download cancelled".into(),
+            Self::UnknownError => "image download unknown error".into(),
+        };
+        Err(anyhow!(message).context(url.clone()).into())
+    }
+}
+
+/// Images are downloaded with an http client, so that's all they can be
+/// fetched from
+const IMAGE_URL_SCHEMES: &[&str] = &["http", "https"];
+
+pub fn validate_image_url(url: &Url) -> Result<()> {
+    if !IMAGE_URL_SCHEMES.contains(&url.scheme()) {
+        bail!(
+            "unsupported image url scheme \"{}\", expected one of {}: {}",
+            url.scheme(),
+            IMAGE_URL_SCHEMES.join(", "),
+            url
+        );
+    }
+    Ok(())
+}
+
+/// Labels a download with the image's file name, or the whole url if it
+/// doesn't end in one
+fn get_progress_label(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .unwrap_or_else(|| url.to_string())
+}
+
 // TODO: move
 enum Either<T, U> {
     Left(T),
@@ -81,10 +260,10 @@ impl ImageCacheClient {
 
     pub async fn get_image_hash(
         &self,
-        ctx: &Ctx,
+        ctx: &BaseCtx,
         url: Url,
         expected_hash: Option<ImageHash>,
-    ) -> Result<GetImageHashResult> {
+    ) -> VmmResult<GetImageHashResult> {
         if let Some(expected_hash) = &expected_hash {
             let image_cache_path = ctx.dirs().get_image_cache_path(expected_hash)?;
             if image_cache_path.exists() {
@@ -92,6 +271,8 @@ impl ImageCacheClient {
             }
         }
 
+        validate_image_url(&url)?;
+
         let (response_sender, response_receiver) = oneshot::channel();
 
         let message = ImageCacheMessage::GetImageHash {
@@ -100,14 +281,32 @@ impl ImageCacheClient {
             response: response_sender,
         };
 
-        self.sender.send(message).await?;
+        self.sender
+            .send(message)
+            .await
+            .context("image cache is not running")?;
+
+        let result = response_receiver
+            .await
+            .context("image cache dropped the request")?;
 
-        Ok(response_receiver.await?)
+        Ok(result)
+    }
+
+    /// Cancels the in-progress download of `url`, if any, without affecting
+    /// other downloads. Everyone waiting on it gets `DownloadCancelled`.
+    pub async fn cancel(&self, url: Url) -> VmmResult<()> {
+        self.sender
+            .send(ImageCacheMessage::Cancel(url))
+            .await
+            .context("image cache is not running")?;
+        Ok(())
     }
 }
 
 struct Download {
     id: u64,
+    task_id: TaskId,
     subscribers: Vec<Subscriber>,
     timer_key: tokio_util::time::delay_queue::Key,
     hash: Option<ImageHash>,
@@ -125,29 +324,51 @@ enum Timer {
 }
 
 pub struct ImageCache {
-    ctx: Ctx,
+    ctx: BaseCtx,
+    progress_router: ProgressRouterClient,
+    config: ImageCacheConfig,
     sender: mpsc::Sender<ImageCacheMessage>,
     cancel_token: CancellationToken,
     downloads: HashMap<Url, Download>,
+    /// Which download each in-flight task is for
+    download_tasks: HashMap<TaskId, Url>,
+    index: ImageCacheIndex,
     next_download_id: u64,
     task_actor: TaskActor<ImageCacheMessage, Timer, ()>,
 }
 
 impl ImageCache {
     pub fn new(
-        ctx: Ctx,
+        ctx: BaseCtx,
+        progress_router: ProgressRouterClient,
+        config: ImageCacheConfig,
         sender: mpsc::Sender<ImageCacheMessage>,
         receiver: mpsc::Receiver<ImageCacheMessage>,
         cancel_token: CancellationToken,
     ) -> Self {
         let tasks_cancel_token = cancel_token.clone();
+
+        // A broken index only costs re-downloads, so it's not worth failing
+        // to start over
+        let index = ImageCacheIndex::load(&ctx).unwrap_or_else(|e| {
+            eprintln!("ignoring image cache index: {:?}", e);
+            ImageCacheIndex::default()
+        });
+
+        let task_actor = TaskActor::new(tasks_cancel_token, receiver)
+            .with_shutdown_timeout(config.shutdown_timeout);
+
         Self {
             ctx,
+            progress_router,
+            config,
             sender,
             cancel_token,
             downloads: HashMap::new(),
+            download_tasks: HashMap::new(),
+            index,
             next_download_id: 0,
-            task_actor: TaskActor::new(tasks_cancel_token, receiver),
+            task_actor,
         }
     }
 
@@ -165,6 +386,9 @@ impl ImageCache {
                     dbg!(&timer);
                     self.handle_timer(timer).await;
                 }
+                TaskActorEvent::TaskFailed(task_id) => {
+                    self.handle_task_failed(task_id).await;
+                }
                 TaskActorEvent::Stopped(reason) => {
                     println!("handle_stopped: {:?}", reason);
                     break;
@@ -187,6 +411,7 @@ impl ImageCache {
             }
             ImageCacheMessage::GetImageHashResult(download_id, result) => {
                 if let Some(download) = self.downloads.get_mut(&download_id) {
+                    self.download_tasks.remove(&download.task_id);
                     self.task_actor.remove_timer(download.timer_key);
 
                     for mut subscriber in download.subscribers.drain(..) {
@@ -196,14 +421,95 @@ impl ImageCache {
                     }
 
                     if let GetImageHashResult::ImageCached(hash) = result {
-                        download.hash = Some(hash);
+                        // Forget the url after a while so that urls which
+                        // always point at the latest image are revalidated
+                        download.timer_key = self.task_actor.insert_timer(
+                            Timer::UrlHashExpired(download_id.clone()),
+                            self.config.url_hash_ttl,
+                        );
+                        download.hash = Some(hash.clone());
+                        self.index.insert(download_id, hash);
+                        self.save_index().await;
                     }
                 }
             }
+            ImageCacheMessage::Cancel(url) => {
+                self.handle_cancel(url).await;
+            }
         }
         Ok(())
     }
 
+    async fn handle_cancel(&mut self, url: Url) {
+        // Finished downloads are only cached hashes, so there is nothing to
+        // cancel
+        if self
+            .downloads
+            .get(&url)
+            .is_none_or(|download| download.hash.is_some())
+        {
+            return;
+        }
+
+        let Some(mut download) = self.downloads.remove(&url) else {
+            return;
+        };
+
+        self.download_tasks.remove(&download.task_id);
+        self.task_actor.tasks().abort_task(download.task_id).await;
+        self.task_actor.remove_timer(download.timer_key);
+
+        // The task was aborted before it could clean up after itself
+        if let Ok(download_image_path) = self.ctx.dirs().get_image_download_path(download.id) {
+            let _ = tokio::fs::remove_file(download_image_path).await;
+        }
+
+        let progress_id = format!("download/{}", download.id);
+        self.progress_router
+            .send(ProgressMessage::Finish(progress_id))
+            .await;
+
+        for mut subscriber in download.subscribers.drain(..) {
+            if let Some(response) = subscriber.response.take() {
+                let _ = response.send(GetImageHashResult::DownloadCancelled);
+            }
+        }
+    }
+
+    async fn handle_task_failed(&mut self, task_id: TaskId) {
+        // The panic hook has already reported the panic
+        let Some(url) = self.download_tasks.remove(&task_id) else {
+            return;
+        };
+
+        let Some(mut download) = self.downloads.remove(&url) else {
+            return;
+        };
+
+        self.task_actor.remove_timer(download.timer_key);
+
+        if let Ok(download_image_path) = self.ctx.dirs().get_image_download_path(download.id) {
+            let _ = tokio::fs::remove_file(download_image_path).await;
+        }
+
+        let progress_id = format!("download/{}", download.id);
+        self.progress_router
+            .send(ProgressMessage::Finish(progress_id))
+            .await;
+
+        for mut subscriber in download.subscribers.drain(..) {
+            if let Some(response) = subscriber.response.take() {
+                let _ = response.send(GetImageHashResult::UnknownError);
+            }
+        }
+    }
+
+    async fn save_index(&self) {
+        if let Err(e) = self.index.save(&self.ctx).await {
+            eprintln!("error: {:?}", e);
+        }
+    }
+
     async fn handle_get_image_hash(
         &mut self,
         url: Url,
@@ -225,6 +531,7 @@ impl ImageCache {
                         } else {
                             // If the hashes don't match, invalidate the
                             // download and continue to start a new one
+                            self.task_actor.remove_timer(download.timer_key);
                             self.downloads.remove(&url);
                         }
                     }
@@ -241,6 +548,21 @@ impl ImageCache {
             }
         }
 
+        // Check if a previous process already downloaded the url
+        let cached_hash = self
+            .index
+            .get(&self.ctx, &url, self.config.url_hash_ttl)
+            .filter(|hash| {
+                expected_hash
+                    .as_ref()
+                    .is_none_or(|expected_hash| expected_hash == hash)
+            });
+        if let Some(hash) = cached_hash {
+            self.save_index().await;
+            let _ = response.send(GetImageHashResult::ImageCached(hash));
+            return Ok(());
+        }
+
         // Start new download
 
         let download_id = self.next_download_id;
@@ -248,12 +570,14 @@ impl ImageCache {
 
         let url2 = url.clone();
         let ctx = self.ctx.clone();
+        let progress_router = self.progress_router.clone();
         let cancel_token = self.cancel_token.clone();
         let sender = self.sender.clone();
+        let config = self.config.clone();
 
         let task_id = self.task_actor.tasks().spawn(async move {
             tokio::select! {
-                result = get_image_hash(&ctx, download_id, url2.clone()) => {
+                result = get_image_hash(&ctx, &progress_router, &config, download_id, url2.clone()) => {
                     match result {
                         Ok(result) => {
                             let msg = ImageCacheMessage::GetImageHashResult(url2.clone(), result);
@@ -269,6 +593,11 @@ impl ImageCache {
                     }
                 }
                 _ = cancel_token.cancelled() => {
+                    // The download was dropped midway, so remove what it wrote
+                    if let Ok(download_image_path) = ctx.dirs().get_image_download_path(download_id) {
+                        let _ = tokio::fs::remove_file(download_image_path).await;
+                    }
+
                     let msg = ImageCacheMessage::GetImageHashResult(url2.clone(), GetImageHashResult::DownloadCancelled);
                     let _ = sender.send(msg).await;
                 }
@@ -277,11 +606,12 @@ impl ImageCache {
 
         let timer_key = self.task_actor.insert_timer(
             Timer::DownloadTimeout(task_id, url.clone()),
-            Duration::from_secs(60),
+            self.config.download_timeout,
         );
 
         let download = Download {
             id: download_id,
+            task_id,
             subscribers: vec![Subscriber {
                 expected_hash,
                 response: Some(response),
@@ -290,6 +620,7 @@ impl ImageCache {
             hash: None,
         };
 
+        self.download_tasks.insert(task_id, url.clone());
         self.downloads.insert(url.clone(), download);
 
         Ok(())
@@ -298,28 +629,43 @@ impl ImageCache {
     async fn handle_timer(&mut self, timer: Timer) {
         match timer {
             Timer::DownloadTimeout(task_id, url) => {
+                self.download_tasks.remove(&task_id);
                 self.task_actor.tasks().abort_task(task_id).await;
                 self.downloads.remove(&url);
             }
             Timer::UrlHashExpired(url) => {
-                self.downloads.remove(&url);
+                if self
+                    .downloads
+                    .get(&url)
+                    .is_some_and(|download| download.hash.is_some())
+                {
+                    self.downloads.remove(&url);
+                }
             }
         }
     }
 }
 
-async fn get_image_hash(ctx: &Ctx, download_id: u64, url: Url) -> Result<GetImageHashResult> {
+async fn get_image_hash(
+    ctx: &BaseCtx,
+    progress_router: &ProgressRouterClient,
+    config: &ImageCacheConfig,
+    download_id: u64,
+    url: Url,
+) -> Result<GetImageHashResult> {
+    let inactivity_timeout = config.download_inactivity_timeout;
     let client = reqwest::Client::new();
-    let response = client
-        .get(url.clone())
-        .send()
-        .await
-        .context("failed to download image")
-        .context(url.clone())?;
+    let response =
+        match tokio::time::timeout(inactivity_timeout, client.get(url.clone()).send()).await {
+            Ok(response) => response
+                .context("failed to download image")
+                .context(url.clone())?,
+            Err(_) => return Ok(GetImageHashResult::DownloadStalled),
+        };
 
-    let Some(content_length) = response.content_length() else {
-        return Ok(GetImageHashResult::DownloadFailed(response.status()));
-    };
+    // Mirrors that use chunked encoding don't send a length, in which case
+    // the download proceeds with indeterminate progress
+    let content_length = response.content_length();
 
     let status = response.status();
     if !status.is_success() {
@@ -349,22 +695,29 @@ async fn get_image_hash(ctx: &Ctx, download_id: u64, url: Url) -> Result<GetImag
 
     let progress_id = format!("download/{}", download_id);
 
-    ctx.progress_router()
-        .send(ProgressMessage::Start(
-            progress_id.clone(),
-            Some(content_length),
-        ))
+    progress_router
+        .send(ProgressMessage::Start {
+            id: progress_id.clone(),
+            label: get_progress_label(&url),
+            total: content_length,
+        })
         .await;
 
-    while let Some(chunk_result) = stream.next().await {
+    loop {
+        let chunk_result = match tokio::time::timeout(inactivity_timeout, stream.next()).await {
+            Ok(Some(chunk_result)) => chunk_result,
+            Ok(None) => break,
+            Err(_) => return Ok(GetImageHashResult::DownloadStalled),
+        };
+
         let Some(chunk) = chunk_result.ok() else {
             return Ok(GetImageHashResult::DownloadFailedToReadChunk);
         };
 
-        ctx.progress_router()
+        progress_router
             .send(ProgressMessage::Update(
                 progress_id.clone(),
-                chunk.len() as u64,
+                ProgressUpdate::new(chunk.len() as u64),
             ))
             .await;
 
@@ -375,7 +728,15 @@ async fn get_image_hash(ctx: &Ctx, download_id: u64, url: Url) -> Result<GetImag
             .context("failed to write chunk to file")?;
     }
 
-    ctx.progress_router()
+    // Cached images are trusted by file name, so a crash must not be able to
+    // leave a truncated one behind under its hash
+    file.sync_all()
+        .await
+        .context("failed to sync image download")
+        .context(download_id)?;
+    drop(file);
+
+    progress_router
         .send(ProgressMessage::Finish(progress_id))
         .await;
 
@@ -384,9 +745,53 @@ async fn get_image_hash(ctx: &Ctx, download_id: u64, url: Url) -> Result<GetImag
 
     let image_cache_path = ctx.dirs().get_image_cache_path(&hash)?;
 
-    tokio::fs::create_dir_all(image_cache_path.parent().ok_or(anyhow!("invalid path"))?).await?;
+    // The same image may already be cached under another url, in which case
+    // the copy just downloaded is redundant. The index is pointed at the
+    // existing file when the result is handled.
+    if image_cache_path.exists() {
+        tokio::fs::remove_file(&download_image_path)
+            .await
+            .context("failed to remove duplicate image download")
+            .context(download_id)?;
+
+        return Ok(GetImageHashResult::ImageCached(hash));
+    }
+
+    let image_cache_dir = image_cache_path.parent().ok_or(anyhow!("invalid path"))?;
+    tokio::fs::create_dir_all(image_cache_dir).await?;
+
+    if config.convert_raw_images && get_image_format(&download_image_path).await? == "raw" {
+        let converted_image_path = download_image_path.with_extension("qcow2");
 
-    tokio::fs::rename(download_image_path, image_cache_path).await?;
+        convert_to_qcow2(&download_image_path, &converted_image_path)
+            .await
+            .context(download_id)?;
+
+        sync_file(&converted_image_path).await?;
+
+        tokio::fs::remove_file(&download_image_path).await?;
+        tokio::fs::rename(converted_image_path, &image_cache_path).await?;
+        sync_file(image_cache_dir).await?;
+
+        return Ok(GetImageHashResult::ImageCached(hash));
+    }
+
+    tokio::fs::rename(download_image_path, &image_cache_path).await?;
+    sync_file(image_cache_dir).await?;
 
     return Ok(GetImageHashResult::ImageCached(hash));
 }
+
+/// Flushes a file or directory to disk. Directories have to be synced for a
+/// rename within them to be durable.
+async fn sync_file(path: &Path) -> Result<()> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .context("failed to open for sync")
+        .context(path.display().to_string())?;
+
+    file.sync_all()
+        .await
+        .context("failed to sync")
+        .context(path.display().to_string())
+}
@@ -1,46 +1,260 @@
-use std::process::Stdio;
+use std::{
+    collections::BTreeSet,
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
+use byte_unit::{Byte, UnitType};
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
     process::{Child, Command},
+    sync::{mpsc, oneshot},
     task::JoinHandle,
 };
 
 use crate::{
-    ctx::Ctx,
+    cgroup::InstanceCgroup,
+    cmd::{cmd, cmd_success, find_program},
+    ctx::{BaseCtx, Ctx},
+    error::{VmmError, VmmResult},
+    gc::remove_stale_file,
     id::Id,
+    instance_events::{InstanceEvent, record_instance_event},
     logger::{LogLine, LogSource, LogStream},
-    machine::Machine,
-    network::Network,
-    share_dir::ShareDir,
+    machine::{CloudInitSeed, Display, Machine, MachineNumaNodeConfig},
+    network::{Network, NetworkMode},
+    qemu_img::{Preallocation, create_qcow2_image, get_image_format},
+    qmp::QmpClient,
+    seed_server::{SeedClient, SeedServer},
+    share_dir::{ShareDir, find_virtiofsd},
+    vmm_dirs::VmmDirs,
 };
 
+const DEFAULT_START_TIMEOUT_SECS: u64 = 120;
+const INSTALL_CDROM_ID: &str = "install-cdrom";
+const DEFAULT_KERNEL_CMDLINE: &str = "root=/dev/vda1 rw console=ttyS0";
+const DEFAULT_GRACEFUL_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MEMORY_SLOTS: u8 = 8;
+const MEMORY_ALIGN: u64 = 2 * 1024 * 1024;
+const MIN_MEMORY: u64 = 64 * 1024 * 1024;
+const VNC_BASE_PORT: u16 = 5900;
+const DEFAULT_VNC_PORTS: RangeInclusive<u16> = 5900..=5999;
+const DEFAULT_SPICE_PORTS: RangeInclusive<u16> = 5930..=5999;
+
 #[derive(Debug, Serialize, Deserialize)]
-struct InstanceState {
-    id: Id,
-    boot_seq: u64,
-    machine_id: Id,
-    network_id: Id,
+pub struct InstanceState {
+    pub id: Id,
+    pub boot_seq: u64,
+    pub machine_id: Id,
+    /// Unset for instances of machines without a network
+    #[serde(default)]
+    pub network_id: Option<Id>,
+    /// Guest memory as last resized, when different from the machine config
+    pub memory: Option<Byte>,
+    /// Disks attached on top of the machine's, reattached on every boot
+    #[serde(default)]
+    pub disks: Vec<InstanceDisk>,
+    /// Port of the VNC display while the instance is running
+    #[serde(default)]
+    pub display_port: Option<u16>,
+    #[serde(default)]
+    pub status: QemuStatus,
+}
+
+/// What became of the instance's qemu. Kept in the instance state so that a
+/// crash is still visible after the fact.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum QemuStatus {
+    #[default]
+    Stopped,
+    /// Only reported by the server while it boots the instance, never saved
+    Starting,
+    Running,
+    /// Exited without being stopped through vmm. The code is unset if qemu
+    /// was killed by a signal.
+    Exited(Option<i32>),
+}
+
+impl std::fmt::Display for QemuStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QemuStatus::Stopped => write!(f, "stopped"),
+            QemuStatus::Starting => write!(f, "starting"),
+            QemuStatus::Running => write!(f, "running"),
+            QemuStatus::Exited(Some(code)) => write!(f, "exited ({})", code),
+            QemuStatus::Exited(None) => write!(f, "exited (signal)"),
+        }
+    }
+}
+
+impl InstanceState {
+    pub async fn open(ctx: &BaseCtx, id: Id) -> Result<Self> {
+        let instance_state_path = ctx.dirs().get_instance_state_file_path(id)?;
+
+        if !instance_state_path.exists() {
+            bail!(
+                "instance state file not found: {}",
+                instance_state_path.display()
+            );
+        }
+
+        let state_text = tokio::fs::read_to_string(&instance_state_path)
+            .await
+            .context("failed to read instance state")
+            .context(id)?;
+
+        let state = serde_json::from_str(&state_text)
+            .context("failed to parse instance state")
+            .context(id)?;
+
+        Ok(state)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InstanceDisk {
+    pub id: String,
+    pub path: PathBuf,
+    pub format: DiskFormat,
+    #[serde(default)]
+    pub iops_limit: Option<u64>,
+    #[serde(default)]
+    pub bps_limit: Option<u64>,
+}
+
+/// How `attach_disk` creates and throttles a disk
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AttachDiskOptions {
+    /// Creates a missing qcow2 image of this size
+    #[serde(default)]
+    pub size: Option<Byte>,
+    #[serde(default)]
+    pub preallocation: Preallocation,
+    #[serde(default)]
+    pub iops_limit: Option<u64>,
+    #[serde(default)]
+    pub bps_limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskFormat {
+    Qcow2,
+    Raw,
+}
+
+impl DiskFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Qcow2 => "qcow2",
+            Self::Raw => "raw",
+        }
+    }
+}
+
+impl InstanceDisk {
+    fn get_node_name(&self) -> String {
+        format!("node-{}", self.id)
+    }
+
+    fn get_qemu_args(&self) -> Vec<String> {
+        let blockdev = format!(
+            "driver={},node-name={},file.driver=file,file.filename={}",
+            self.format.as_str(),
+            self.get_node_name(),
+            self.path.to_string_lossy()
+        );
+
+        let device = format!(
+            "virtio-blk-pci,id={},drive={}",
+            self.id,
+            self.get_node_name()
+        );
+
+        #[rustfmt::skip]
+        let args = vec![
+            "-blockdev".into(), blockdev,
+            "-device".into(), device,
+        ];
+
+        args
+    }
+}
+
+/// The sockets qemu serves for an instance, resolved up front so that
+/// getting them can't fail
+struct InstanceSocketPaths {
+    qmp: PathBuf,
+    serial: PathBuf,
+    monitor: PathBuf,
+}
+
+impl InstanceSocketPaths {
+    fn new(dirs: &VmmDirs, id: Id) -> Result<Self> {
+        Ok(Self {
+            qmp: dirs.get_instance_qmp_socket_path(id)?,
+            serial: dirs.get_instance_serial_socket_path(id)?,
+            monitor: dirs.get_instance_monitor_socket_path(id)?,
+        })
+    }
+}
+
+/// A running qemu. The child is owned by a monitor task that waits on it, so
+/// an exit is noticed even while nothing is looking at the instance.
+struct QemuProcess {
+    kill: oneshot::Sender<()>,
+    /// Receives the exit status before the exit notifier is told, so whoever
+    /// is notified always finds it here
+    exit: oneshot::Receiver<Result<ExitStatus>>,
 }
 
 pub struct Instance {
     id: Id,
     boot_seq: u64,
+    socket_paths: InstanceSocketPaths,
     machine: Machine,
-    network: Network,
+    network: Option<Network>,
     share_dirs: Vec<ShareDir>,
-    qemu: Option<(Child, Vec<JoinHandle<()>>)>,
+    shared_memory: bool,
+    memory: Option<Byte>,
+    memory_dimms: Vec<u64>,
+    disks: Vec<InstanceDisk>,
+    display_port: Option<u16>,
+    qemu: Option<QemuProcess>,
+    status: QemuStatus,
+    /// Told the instance's id whenever its qemu exits, for whoever owns the
+    /// instance to react to
+    exit_notifier: Option<mpsc::UnboundedSender<Id>>,
+    started_at: Option<Instant>,
+    cgroup: Option<InstanceCgroup>,
+    seed_server: Option<SeedServer>,
+    /// Whether the vcpus are stopped at boot, until resumed
+    paused: bool,
 }
 
 impl Instance {
-    pub async fn new(ctx: &Ctx, id: Id, machine: Machine, network: Network) -> Result<Self> {
+    pub async fn new(
+        ctx: &BaseCtx,
+        id: Id,
+        machine: Machine,
+        network: Option<Network>,
+    ) -> Result<Self> {
         let state = InstanceState {
             id,
             boot_seq: 0,
             machine_id: machine.id().clone(),
-            network_id: network.id().clone(),
+            network_id: network.as_ref().map(|network| *network.id()),
+            memory: None,
+            disks: vec![],
+            display_port: None,
+            status: QemuStatus::Stopped,
         };
 
         let instance_state_path = ctx.dirs().get_instance_state_file_path(id)?;
@@ -65,75 +279,135 @@ impl Instance {
             .context("failed to write instance state")
             .context(id)?;
 
-        let share_dirs = Self::init_share_dirs(&machine, id, 0)?;
+        let socket_paths = InstanceSocketPaths::new(ctx.dirs(), id)?;
+        let share_dirs = Self::init_share_dirs(ctx.dirs(), &machine, id, 0)?;
+
+        record_instance_event(ctx, id, InstanceEvent::Created).await;
 
         Ok(Self {
             id,
             boot_seq: 0,
+            socket_paths,
             machine,
             network,
             share_dirs,
+            shared_memory: false,
+            memory: None,
+            memory_dimms: vec![],
+            disks: vec![],
+            display_port: None,
             qemu: None,
+            status: QemuStatus::Stopped,
+            exit_notifier: None,
+            started_at: None,
+            cgroup: None,
+            seed_server: None,
+            paused: false,
         })
     }
 
-    pub async fn read(ctx: &Ctx, id: Id) -> Result<Self> {
-        let instance_state_path = ctx.dirs().get_instance_state_file_path(id)?;
-
-        if !instance_state_path.exists() {
-            bail!(
-                "instance state file not found: {}",
-                instance_state_path.display()
-            );
-        }
-
-        let state_text = tokio::fs::read_to_string(&instance_state_path)
-            .await
-            .context("failed to read instance state")
-            .context(id)?;
-
-        let mut state: InstanceState = serde_json::from_str(&state_text)
-            .context("failed to parse instance state")
-            .context(id)?;
-
-        state.boot_seq += 1;
+    pub async fn open(ctx: &BaseCtx, id: Id) -> Result<Self> {
+        let state = InstanceState::open(ctx, id).await?;
         let boot_seq = state.boot_seq;
 
-        let state_text = serde_json::to_string(&state)
-            .context("failed to serialize instance state")
-            .context(id)?;
-
-        tokio::fs::write(instance_state_path, state_text)
-            .await
-            .context("failed to write instance state")
-            .context(id)?;
-
         let machine = Machine::open(ctx, state.machine_id)
             .await
             .context("failed to read instance machine")
             .context(id)?;
 
-        let network = Network::open(ctx, state.network_id)
-            .await
-            .context("failed to read instance network")
-            .context(id)?;
+        let network = match state.network_id {
+            Some(network_id) => Some(
+                Network::open(ctx, network_id)
+                    .await
+                    .context("failed to read instance network")
+                    .context(id)?,
+            ),
+            None => None,
+        };
 
-        let share_dirs = Self::init_share_dirs(&machine, id, boot_seq)?;
+        let socket_paths = InstanceSocketPaths::new(ctx.dirs(), id)?;
+        let share_dirs = Self::init_share_dirs(ctx.dirs(), &machine, id, boot_seq)?;
+
+        // Whatever qemu was running belonged to another process
+        let status = match state.status {
+            QemuStatus::Starting | QemuStatus::Running => QemuStatus::Stopped,
+            status => status,
+        };
 
         Ok(Self {
             id,
             boot_seq,
+            socket_paths,
             machine,
             network,
             share_dirs,
+            shared_memory: false,
+            memory: state.memory,
+            memory_dimms: vec![],
+            disks: state.disks,
+            display_port: state.display_port,
             qemu: None,
+            status,
+            exit_notifier: None,
+            started_at: None,
+            cgroup: None,
+            seed_server: None,
+            paused: false,
         })
     }
 
-    fn init_share_dirs(machine: &Machine, id: Id, boot_seq: u64) -> Result<Vec<ShareDir>> {
+    async fn save_state(&self, ctx: &BaseCtx) -> Result<()> {
+        let state = InstanceState {
+            id: self.id,
+            boot_seq: self.boot_seq,
+            machine_id: *self.machine.id(),
+            network_id: self.network.as_ref().map(|network| *network.id()),
+            memory: self.memory,
+            disks: self.disks.clone(),
+            display_port: self.display_port,
+            status: self.status,
+        };
+
+        let state_text = serde_json::to_string_pretty(&state)
+            .context("failed to serialize instance state")
+            .context(self.id)?;
+
+        tokio::fs::write(
+            ctx.dirs().get_instance_state_file_path(self.id)?,
+            state_text,
+        )
+        .await
+        .context("failed to write instance state")
+        .context(self.id)?;
+
+        Ok(())
+    }
+
+    /// Deletes the instance's state, including its root disk overlay and
+    /// logs. The instance has to be stopped first.
+    pub async fn delete(self, ctx: &BaseCtx) -> Result<()> {
+        if self.is_running() {
+            bail!("instance is still running: {}", self.id);
+        }
+
+        let state_dir = ctx.dirs().get_instance_state_dir(self.id)?;
+        tokio::fs::remove_dir_all(&state_dir)
+            .await
+            .context("failed to delete instance state")
+            .context(self.id)?;
+
+        Ok(())
+    }
+
+    fn init_share_dirs(
+        dirs: &VmmDirs,
+        machine: &Machine,
+        id: Id,
+        boot_seq: u64,
+    ) -> Result<Vec<ShareDir>> {
         let mut share_dirs = vec![];
         for path in machine.config().share_dirs.iter() {
-            let share_dir = ShareDir::new(id, boot_seq, &machine, path.clone())
+            let share_dir = ShareDir::new(dirs, id, boot_seq, path.clone())
                 .context("failed to create share dir")
                 .context(id)?;
             share_dirs.push(share_dir);
@@ -149,177 +423,2015 @@ impl Instance {
         &self.machine
     }
 
-    pub fn network(&self) -> &Network {
-        &self.network
+    pub fn network(&self) -> Option<&Network> {
+        self.network.as_ref()
+    }
+
+    /// Returns the network if the instance is attached to it through a tap on
+    /// the host, which user-mode networks don't need
+    pub fn get_bridged_network(&self) -> Option<&Network> {
+        self.network
+            .as_ref()
+            .filter(|network| network.config().mode == NetworkMode::Bridge)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.qemu.is_some()
+    }
+
+    pub fn status(&self) -> QemuStatus {
+        self.status
+    }
+
+    /// Has the instance's id sent on `notifier` whenever qemu exits, whether
+    /// or not it was asked to. Only applies to qemu started after this.
+    pub fn set_exit_notifier(&mut self, notifier: mpsc::UnboundedSender<Id>) {
+        self.exit_notifier = Some(notifier);
+    }
+
+    /// How long qemu has been running since it was last started
+    pub fn uptime(&self) -> Option<Duration> {
+        self.started_at.map(|started_at| started_at.elapsed())
+    }
+
+    pub fn get_qmp_socket_path(&self) -> PathBuf {
+        self.socket_paths.qmp.clone()
+    }
+
+    pub fn get_serial_socket_path(&self) -> PathBuf {
+        self.socket_paths.serial.clone()
+    }
+
+    pub fn get_monitor_socket_path(&self) -> PathBuf {
+        self.socket_paths.monitor.clone()
     }
 
     pub fn get_mac_address(&self) -> String {
-        let id: [u8; 16] = self.id.into();
-        let id = &id[id.len() - 3..];
-        format!("52:54:00:{:02x}:{:02x}:{:02x}", id[0], id[1], id[2])
+        get_mac_address(self.id)
     }
 
-    async fn get_qemu_args(&mut self, ctx: &Ctx) -> Result<Vec<String>> {
-        // TODO: could cache if the config has not changed
+    /// Direct kernel boot skips the firmware's boot order entirely, so the two
+    /// are mutually exclusive
+    fn get_boot_qemu_args(&self) -> Result<Vec<String>> {
+        let config = self.machine.config();
 
-        let memory = self.machine.config().memory.as_u64().to_string();
+        let Some(kernel) = &config.kernel else {
+            let boot_order = config.boot_order.clone().unwrap_or("c".into());
+            validate_boot_order(&boot_order)?;
 
-        let tap = self.network.get_tap_name(self);
-        let mac = self.get_mac_address();
-        let net_device = format!("virtio-net-pci,netdev={tap},mac={mac}");
-        let netdev = format!("tap,id={tap},ifname={tap},script=no");
+            // The install ISO is the first cdrom, and booting from it only
+            // once means the installer's reboot lands on the disk
+            let boot = match config.install_iso {
+                Some(_) => format!("order={boot_order},once=d"),
+                None => format!("order={boot_order}"),
+            };
 
-        let iso = self.machine.get_cloud_init_iso(ctx).await?;
-        let iso = iso.to_string_lossy();
+            return Ok(vec!["-boot".into(), boot]);
+        };
 
-        let iso_drive: String = format!("file={iso},media=cdrom");
+        if config.boot_order.is_some() {
+            eprintln!("warning: boot_order is ignored when booting a kernel directly");
+        }
 
-        let root_image = self.machine.get_root_image(ctx).await?;
-        let root_image = root_image.to_string_lossy();
-        let root_drive: String = format!(
-            "file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-            root_image
-        );
+        if !kernel.is_file() {
+            bail!("kernel not found: {}", kernel.display());
+        }
 
-        let qmp_socket = format!("/tmp/vmm-qmp-{}.sock", self.id.to_string());
-        let qmp_socket = format!("unix:{},server,nowait", qmp_socket);
+        let cmdline = config
+            .cmdline
+            .clone()
+            .unwrap_or(DEFAULT_KERNEL_CMDLINE.into());
 
         #[rustfmt::skip]
         let mut args = vec![
-            "-machine".into(), "type=pc,accel=kvm".into(),
-            "-boot".into(), "d".into(),
-            "-smp".into(), self.machine.config().cpus.to_string(),
-            "-m".into(), memory.clone() + "B",
-            "-device".into(), net_device,
-            "-netdev".into(), netdev,
-            "-drive".into(), iso_drive,
-            "-drive".into(), root_drive,
-            "-nographic".into(),
-            "-qmp".into(), qmp_socket,
+            "-kernel".into(), kernel.to_string_lossy().into(),
+            "-append".into(), cmdline,
         ];
 
-        for share_dir in self.share_dirs.iter() {
-            args.extend(share_dir.get_qemu_args());
+        if let Some(initrd) = &config.initrd {
+            if !initrd.is_file() {
+                bail!("initrd not found: {}", initrd.display());
+            }
+            args.extend(["-initrd".into(), initrd.to_string_lossy().into()]);
         }
 
         Ok(args)
     }
 
-    pub async fn start(&mut self, ctx: &Ctx) -> Result<()> {
-        // TODO: timeout?
+    /// Starts serving the cloud-init files to the guest and points its NoCloud
+    /// datasource at them
+    async fn get_smbios_seed_qemu_args(&mut self, ctx: &BaseCtx) -> Result<Vec<String>> {
+        self.machine
+            .config()
+            .validate_smbios_seed(self.network.as_ref().map(Network::config))?;
+
+        // Checked above
+        let network = self.network.as_ref().unwrap();
+        let host_addr = network.config().ip.addr();
 
-        // XXX
-        // self.network.set_bridge_up_or_create().await?;
-        // self.network.set_tap_up_or_create(self).await?;
+        // qemu forwards the user-mode host address to the host's loopback,
+        // while bridged guests reach the host on the bridge
+        let (bind_addr, client) = match network.config().mode {
+            NetworkMode::Bridge => {
+                let client = SeedClient::Bridge {
+                    bridge: network.get_bridge_name(),
+                    mac: self.get_mac_address(),
+                };
+                (host_addr, client)
+            }
+            NetworkMode::User => (Ipv4Addr::LOCALHOST, SeedClient::Loopback),
+        };
 
-        // XXX
-        // for share_dir in self.share_dirs.iter_mut() {
-        //     share_dir.start(ctx).await?;
-        // }
+        self.machine.write_cloud_init_config(ctx, self.id).await?;
 
-        let qemu_args = self.get_qemu_args(ctx).await?;
+        if let Some(seed_server) = self.seed_server.take() {
+            seed_server.stop();
+        }
 
-        dbg!(&qemu_args);
+        let cloud_init_dir = ctx.dirs().get_instance_cloud_init_dir(self.id)?;
+        let seed_server =
+            SeedServer::start(SocketAddr::from((bind_addr, 0)), cloud_init_dir, client).await?;
+        let port = seed_server.port();
+        self.seed_server = Some(seed_server);
 
-        // XXX
-        // if self.qemu.is_none() {
-        //     self.start_qemu(ctx, qemu_args).await?;
-        // }
+        // nocloud-net is understood by old and new cloud-init alike
+        #[rustfmt::skip]
+        let args = vec![
+            "-smbios".into(), format!("type=1,serial=ds=nocloud-net;s=http://{host_addr}:{port}/"),
+        ];
 
-        Ok(())
+        Ok(args)
     }
 
-    pub async fn stop(&mut self) -> Result<()> {
-        // TODO: timeout
+    /// The arguments `start` would run qemu with, for checking what a config
+    /// turns into. Nothing is started or saved, but the root disk and
+    /// cloud-init seed are still created since the arguments refer to them.
+    pub async fn get_dry_run_qemu_args(&mut self, ctx: &Ctx, paused: bool) -> Result<Vec<String>> {
+        self.paused = paused;
+        self.get_qemu_args(ctx).await
+    }
 
-        self.stop_qemu().await?;
+    async fn get_qemu_args(&mut self, ctx: &Ctx) -> Result<Vec<String>> {
+        // TODO: could cache if the config has not changed
 
-        for share_dir in self.share_dirs.iter_mut() {
-            share_dir.stop().await?;
+        if let Some(cpu_affinity) = &self.machine.config().cpu_affinity {
+            validate_cpu_affinity(self.machine.config().cpus, cpu_affinity)?;
         }
 
-        Ok(())
-    }
+        let machine_type = self
+            .machine
+            .config()
+            .machine_type
+            .clone()
+            .unwrap_or("pc".into());
 
-    async fn start_qemu(&mut self, ctx: &Ctx, args: Vec<String>) -> Result<()> {
-        assert!(self.qemu.is_none(), "qemu is already running");
+        warn_unknown_machine_type(&machine_type).await;
 
-        let mut child = Command::new("qemu-system-x86_64")
-            .args(args)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .context("failed to spawn qemu")
-            .context(self.id)?;
+        let boot_args = self.get_boot_qemu_args()?;
 
-        let mut tasks = Vec::new();
+        let memory = self.get_memory_arg()?;
 
-        if let Some(stdout) = child.stdout.take() {
-            let id = self.id.clone();
-            let boot_seq = self.boot_seq;
-            let mut reader = BufReader::new(stdout).lines();
-            let logger = ctx.logger().clone();
-            let stdout_task = tokio::spawn(async move {
-                while let Ok(Some(line)) = reader.next_line().await {
-                    let _ = logger.log(LogLine::instance(
-                        id,
-                        boot_seq,
-                        LogStream::Stdout,
-                        LogSource::Virtiofs,
-                        line,
-                    ));
-                }
-            });
-            tasks.push(stdout_task);
+        let network_args = self.get_network_qemu_args();
+
+        let root_disk = self.get_root_disk(ctx).await?;
+        let root_disk = root_disk.to_string_lossy();
+        let mut root_drive: String = format!(
+            "file={},id=root,node-name=root-disk,if=virtio,cache=writeback,discard=ignore,format=qcow2",
+            root_disk
+        );
+
+        let image_config = &self.machine.config().image;
+        root_drive += &get_throttle_drive_options(image_config.iops_limit, image_config.bps_limit)?;
+
+        let qmp_socket = self.get_qmp_socket_path();
+        let qmp_socket = format!("unix:{},server,nowait", qmp_socket.to_string_lossy());
+
+        let pid_path = ctx.dirs().get_instance_pid_path(self.id)?;
+
+        let serial_chardev = self.get_serial_chardev(ctx).await?;
+
+        let display_args = self.get_display_qemu_args()?;
+
+        let monitor = match self.machine.config().hmp_monitor {
+            true => format!(
+                "unix:{},server,nowait",
+                self.get_monitor_socket_path().to_string_lossy()
+            ),
+            false => "none".into(),
+        };
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "-machine".into(), format!("type={machine_type},accel=kvm"),
+            "-smp".into(), self.machine.config().cpus.to_string(),
+            "-m".into(), memory,
+            "-drive".into(), root_drive,
+            "-device".into(), "virtio-balloon-pci,id=balloon".into(),
+            "-chardev".into(), serial_chardev,
+            "-serial".into(), "chardev:serial0".into(),
+            "-monitor".into(), monitor,
+            "-qmp".into(), qmp_socket,
+            "-pidfile".into(), pid_path.to_string_lossy().into(),
+        ];
+
+        if self.paused {
+            args.push("-S".into());
         }
 
-        if let Some(stderr) = child.stderr.take() {
-            let id = self.id.clone();
-            let mut reader = BufReader::new(stderr).lines();
-            let logger = ctx.logger().clone();
-            let stderr_task = tokio::spawn(async move {
-                while let Ok(Some(line)) = reader.next_line().await {
-                    let _ = logger.log(LogLine::machine(
-                        id,
-                        LogStream::Stderr,
-                        LogSource::CloudInit,
-                        line,
-                    ));
+        // Ahead of the cloud-init seed so that it's the first cdrom
+        if let Some(install_iso) = &self.machine.config().install_iso {
+            if !install_iso.is_file() {
+                bail!("install iso not found: {}", install_iso.display());
+            }
+            let install_drive = format!(
+                "file={},id={INSTALL_CDROM_ID},media=cdrom,readonly=on",
+                install_iso.to_string_lossy()
+            );
+            args.extend(["-drive".into(), install_drive]);
+        }
+
+        args.extend(boot_args);
+
+        args.extend(network_args);
+        args.extend(display_args);
+
+        if self.machine.config().has_cloud_init() {
+            match self.machine.config().cloud_init_seed {
+                CloudInitSeed::Iso => {
+                    let iso = self.machine.get_cloud_init_iso(ctx, self.id).await?;
+                    let iso_drive = format!("file={},media=cdrom", iso.to_string_lossy());
+                    args.extend(["-drive".into(), iso_drive]);
                 }
-            });
-            tasks.push(stderr_task);
+                CloudInitSeed::Smbios => {
+                    args.extend(self.get_smbios_seed_qemu_args(ctx).await?);
+                }
+            }
+        } else {
+            self.machine
+                .config()
+                .validate_without_cloud_init(self.network.as_ref().map(Network::config))?;
         }
 
-        self.qemu = Some((child, tasks));
+        self.shared_memory = !self.share_dirs.is_empty();
 
-        Ok(())
+        args.extend(self.get_memory_qemu_args()?);
+        args.extend(self.get_memory_dimm_qemu_args()?);
+
+        for share_dir in self.share_dirs.iter() {
+            args.extend(share_dir.get_qemu_args());
+        }
+
+        for disk in self.disks.iter() {
+            args.extend(disk.get_qemu_args());
+        }
+
+        // Last, so they can override anything generated above
+        args.extend(self.machine.config().extra_qemu_args.iter().cloned());
+
+        Ok(args)
     }
 
-    async fn stop_qemu(&mut self) -> Result<()> {
-        let Some((mut child, mut tasks)) = self.qemu.take() else {
-            return Ok(());
+    fn get_network_qemu_args(&self) -> Vec<String> {
+        let Some(network) = &self.network else {
+            return vec!["-nic".into(), "none".into()];
         };
 
-        let status = child
-            .wait()
-            .await
-            .context("failed to wait for qemu")
-            .context(self.id)?;
+        let mac = self.get_mac_address();
+        let config = network.config();
 
-        for task in tasks.drain(..) {
-            let _ = task.await;
-        }
+        let (net_device, netdev) = match config.mode {
+            NetworkMode::Bridge => {
+                let tap = network.get_tap_name(self);
+                let net_device = format!("virtio-net-pci,netdev={tap},mac={mac}");
+                let netdev = format!("tap,id={tap},ifname={tap},script=no");
+                (net_device, netdev)
+            }
+            NetworkMode::User => {
+                let net_device = format!("virtio-net-pci,netdev=net0,mac={mac}");
+                let mut netdev = format!(
+                    "user,id=net0,net={},host={}",
+                    config.ip.trunc(),
+                    config.ip.addr()
+                );
+                if let Some(machine_network) = &self.machine.config().network {
+                    for host_forward in machine_network.host_forwards.iter() {
+                        netdev += &format!(",hostfwd={}", host_forward.to_qemu_arg());
+                    }
+                }
+                (net_device, netdev)
+            }
+        };
 
-        if !status.success() {
-            anyhow::bail!("qemu exited with {}", status);
+        #[rustfmt::skip]
+        let args = vec![
+            "-device".into(), net_device,
+            "-netdev".into(), netdev,
+        ];
+
+        args
+    }
+
+    fn get_display_qemu_args(&mut self) -> Result<Vec<String>> {
+        if let Some(port) = self.display_port.take() {
+            release_port(port);
         }
 
-        Ok(())
+        match &self.machine.config().display {
+            Display::None => Ok(vec!["-nographic".into()]),
+            Display::Vnc { ports } => {
+                let ports = ports.clone().unwrap_or(DEFAULT_VNC_PORTS);
+                if *ports.start() < VNC_BASE_PORT {
+                    bail!("vnc ports must be at least {}", VNC_BASE_PORT);
+                }
+
+                let port = reserve_free_port(ports.clone()).ok_or(anyhow!(
+                    "no free vnc port in {}-{}",
+                    ports.start(),
+                    ports.end()
+                ))?;
+
+                self.display_port = Some(port);
+
+                // qemu takes the display number rather than the port
+                let vnc = format!("127.0.0.1:{}", port - VNC_BASE_PORT);
+
+                Ok(vec!["-vnc".into(), vnc])
+            }
+            Display::Spice { ports } => {
+                let ports = ports.clone().unwrap_or(DEFAULT_SPICE_PORTS);
+
+                let port = reserve_free_port(ports.clone()).ok_or(anyhow!(
+                    "no free spice port in {}-{}",
+                    ports.start(),
+                    ports.end()
+                ))?;
+
+                self.display_port = Some(port);
+
+                let spice = format!("port={port},addr=127.0.0.1,disable-ticketing=on");
+
+                // The vdagent channel gives the guest clipboard sharing and
+                // resizing to the client's window
+                #[rustfmt::skip]
+                let args = vec![
+                    "-spice".into(), spice,
+                    "-display".into(), "none".into(),
+                    "-vga".into(), "none".into(),
+                    "-device".into(), "qxl-vga".into(),
+                    "-device".into(), "virtio-serial-pci".into(),
+                    "-chardev".into(), "spicevmc,id=vdagent,name=vdagent".into(),
+                    "-device".into(), "virtserialport,chardev=vdagent,name=com.redhat.spice.0".into(),
+                ];
+
+                Ok(args)
+            }
+        }
     }
-}
 
-impl Drop for Instance {
-    fn drop(&mut self) {
-        assert!(self.qemu.is_none(), "qemu is still running");
+    /// The serial console is served on a socket for `instance console` to
+    /// attach to, and is optionally also logged to a file
+    async fn get_serial_chardev(&self, ctx: &BaseCtx) -> Result<String> {
+        let mut chardev = format!(
+            "socket,id=serial0,path={},server=on,wait=off",
+            self.get_serial_socket_path().display()
+        );
+
+        if self.machine.config().console_log {
+            let console_log_path = ctx.dirs().get_instance_console_log_path(self.id)?;
+            let log_dir = console_log_path.parent().ok_or(anyhow!("invalid path"))?;
+
+            tokio::fs::create_dir_all(log_dir).await?;
+
+            chardev += &format!(",logfile={},logappend=on", console_log_path.display());
+        }
+
+        Ok(chardev)
+    }
+
+    /// Gets the instance's copy-on-write overlay on top of the machine's root
+    /// image, creating it on first boot. Writes and snapshots go to the
+    /// overlay so the cached image stays pristine and shareable.
+    async fn get_root_disk(&mut self, ctx: &Ctx) -> Result<PathBuf> {
+        let root_disk_path = ctx.dirs().get_instance_root_disk_path(self.id)?;
+        if root_disk_path.exists() {
+            return Ok(root_disk_path);
+        }
+
+        let root_image = self.machine.get_root_image(ctx).await?;
+
+        if let Some(state_dir) = root_disk_path.parent() {
+            tokio::fs::create_dir_all(state_dir).await?;
+        }
+
+        // The overlay is always qcow2, but the image it's backed by is
+        // whatever was downloaded
+        let root_image_format = get_image_format(&root_image).await.context(self.id)?;
+
+        let root_image = root_image.to_string_lossy();
+        let root_disk = root_disk_path.to_string_lossy();
+
+        #[rustfmt::skip]
+        let args = [
+            "create", "-f", "qcow2",
+            "-F", &root_image_format, "-b", &root_image,
+            &root_disk,
+        ];
+
+        cmd_success("qemu-img", &args).await.context(self.id)?;
+
+        Ok(root_disk_path)
+    }
+
+    fn get_memory_qemu_args(&self) -> Result<Vec<String>> {
+        let config = self.machine.config();
+
+        // vhost-user devices like virtiofs map guest memory into another
+        // process, so it has to live in a shareable backend
+        if !self.shared_memory && !config.hugepages && config.numa_nodes.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let backend = |id: &str, size: u64| self.get_memory_backend_arg(id, size);
+
+        let mut args = vec![];
+
+        if config.numa_nodes.is_empty() {
+            #[rustfmt::skip]
+            args.extend([
+                "-object".into(), backend("mem", self.get_guest_memory()?),
+                "-numa".into(), "node,memdev=mem".into(),
+            ]);
+            return Ok(args);
+        }
+
+        validate_numa_nodes(config.cpus, config.memory, &config.numa_nodes)?;
+
+        for (i, node) in config.numa_nodes.iter().enumerate() {
+            let id = format!("mem{}", i);
+            let mut numa = format!("node,nodeid={}", i);
+            for cpu in node.cpus.iter() {
+                numa += &format!(",cpus={}", cpu);
+            }
+            numa += &format!(",memdev={}", id);
+
+            #[rustfmt::skip]
+            args.extend([
+                "-object".into(), backend(&id, align_memory_size(node.memory.as_u64())),
+                "-numa".into(), numa,
+            ]);
+        }
+
+        Ok(args)
+    }
+
+    /// Boot memory as given to qemu. It's rounded up so that it's always a
+    /// valid backend size, and with NUMA nodes it's their rounded sizes
+    /// added up, since `-m` has to match the backends exactly.
+    fn get_guest_memory(&self) -> Result<u64> {
+        let config = self.machine.config();
+
+        if config.memory.as_u64() < MIN_MEMORY {
+            bail!(
+                "guest memory {} is less than the minimum of {}",
+                config.memory,
+                Byte::from_u64(MIN_MEMORY).get_appropriate_unit(UnitType::Binary)
+            );
+        }
+
+        if config.numa_nodes.is_empty() {
+            return Ok(align_memory_size(config.memory.as_u64()));
+        }
+
+        validate_numa_nodes(config.cpus, config.memory, &config.numa_nodes)?;
+
+        let memory = config
+            .numa_nodes
+            .iter()
+            .map(|node| align_memory_size(node.memory.as_u64()))
+            .sum();
+
+        Ok(memory)
+    }
+
+    /// Gets the `-m` argument, reserving hotplug slots when the machine has a
+    /// max memory
+    fn get_memory_arg(&self) -> Result<String> {
+        let config = self.machine.config();
+        let memory = self.get_guest_memory()?;
+
+        let Some(max_memory) = config.max_memory else {
+            return Ok(format!("{}B", memory));
+        };
+
+        let max_memory = align_memory_size(max_memory.as_u64());
+
+        if max_memory < memory {
+            bail!(
+                "max memory {} is less than guest memory {}",
+                Byte::from_u64(max_memory),
+                Byte::from_u64(memory)
+            );
+        }
+
+        Ok(format!(
+            "{}B,slots={},maxmem={}B",
+            memory,
+            config.memory_slots.unwrap_or(DEFAULT_MEMORY_SLOTS),
+            max_memory
+        ))
+    }
+
+    /// Gets the arguments for a dimm that restores the memory the instance
+    /// was last resized to
+    fn get_memory_dimm_qemu_args(&mut self) -> Result<Vec<String>> {
+        self.memory_dimms.clear();
+
+        let config = self.machine.config();
+
+        let (Some(memory), Some(max_memory)) = (self.memory, config.max_memory) else {
+            return Ok(vec![]);
+        };
+
+        if memory <= config.memory || memory > max_memory {
+            return Ok(vec![]);
+        }
+
+        let guest_memory = self.get_guest_memory()?;
+        if memory.as_u64() <= guest_memory {
+            return Ok(vec![]);
+        }
+
+        let size = align_memory_size(memory.as_u64() - guest_memory);
+
+        #[rustfmt::skip]
+        let args = vec![
+            "-object".into(), self.get_memory_backend_arg("mem-dimm0", size),
+            "-device".into(), "pc-dimm,id=dimm0,memdev=mem-dimm0".into(),
+        ];
+
+        self.memory_dimms.push(size);
+
+        Ok(args)
+    }
+
+    /// Properties of a guest memory backend object, shared between the
+    /// command line and QMP `object-add`
+    fn get_memory_backend_props(&self, id: &str, size: u64) -> (&'static str, Value) {
+        let shared = self.shared_memory;
+        if self.machine.config().hugepages {
+            let props = json!({ "id": id, "size": size, "hugetlb": true, "share": shared });
+            ("memory-backend-memfd", props)
+        } else if shared {
+            let props = json!({ "id": id, "size": size, "mem-path": "/dev/shm", "share": true });
+            ("memory-backend-file", props)
+        } else {
+            ("memory-backend-ram", json!({ "id": id, "size": size }))
+        }
+    }
+
+    fn get_memory_backend_arg(&self, id: &str, size: u64) -> String {
+        let (qom_type, props) = self.get_memory_backend_props(id, size);
+
+        let mut arg = qom_type.to_string();
+        for (key, value) in props.as_object().into_iter().flatten() {
+            let value = match value {
+                Value::Bool(true) => "on".into(),
+                Value::Bool(false) => "off".into(),
+                Value::Number(n) if key == "size" => format!("{}B", n),
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+            arg += &format!(",{}={}", key, value);
+        }
+        arg
+    }
+
+    /// Grows or shrinks guest memory of a running instance between the
+    /// configured memory and max memory. Growing hotplugs a dimm, and
+    /// shrinking unplugs dimms that are no longer needed and lets the balloon
+    /// reclaim the rest, since dimms can only be removed whole.
+    pub async fn set_memory(&mut self, ctx: &BaseCtx, target: Byte) -> Result<()> {
+        if !self.is_running() {
+            bail!("instance is not running");
+        }
+
+        let config = self.machine.config();
+
+        let Some(max_memory) = config.max_memory else {
+            bail!(
+                "machine has no max memory configured: {}",
+                self.machine.id()
+            );
+        };
+
+        if target < config.memory || target > max_memory {
+            bail!(
+                "memory target {} is outside of {} to {}",
+                target,
+                config.memory,
+                max_memory
+            );
+        }
+
+        let slots = config.memory_slots.unwrap_or(DEFAULT_MEMORY_SLOTS) as usize;
+        let target_bytes = target.as_u64();
+        let mut current = self.get_guest_memory()? + self.memory_dimms.iter().sum::<u64>();
+
+        let mut qmp = QmpClient::connect(&self.get_qmp_socket_path())
+            .await
+            .context(self.id)?;
+
+        while let Some(&size) = self.memory_dimms.last() {
+            if current - size < target_bytes {
+                break;
+            }
+
+            let index = self.memory_dimms.len() - 1;
+            let dimm_id = format!("dimm{}", index);
+            let backend_id = format!("mem-dimm{}", index);
+
+            qmp.execute("device_del", Some(json!({ "id": dimm_id })))
+                .await
+                .context(self.id)?;
+
+            // The backend stays in use until the guest has released the dimm
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+            loop {
+                match qmp
+                    .execute("object-del", Some(json!({ "id": backend_id })))
+                    .await
+                {
+                    Ok(_) => break,
+                    Err(e) if tokio::time::Instant::now() >= deadline => {
+                        return Err(e)
+                            .context("guest did not release memory dimm")
+                            .context(self.id);
+                    }
+                    Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+                }
+            }
+
+            self.memory_dimms.pop();
+            current -= size;
+        }
+
+        if current < target_bytes {
+            if self.memory_dimms.len() >= slots {
+                bail!("no free memory slots: {}", self.id);
+            }
+
+            let index = self.memory_dimms.len();
+            let dimm_id = format!("dimm{}", index);
+            let backend_id = format!("mem-dimm{}", index);
+
+            let size = align_memory_size(target_bytes - current);
+            let (qom_type, mut props) = self.get_memory_backend_props(&backend_id, size);
+            props["qom-type"] = Value::from(qom_type);
+
+            qmp.execute("object-add", Some(props))
+                .await
+                .context(self.id)?;
+
+            let device_result = qmp
+                .execute(
+                    "device_add",
+                    Some(json!({ "driver": "pc-dimm", "id": dimm_id, "memdev": backend_id })),
+                )
+                .await;
+
+            if let Err(e) = device_result {
+                let _ = qmp
+                    .execute("object-del", Some(json!({ "id": backend_id })))
+                    .await;
+                return Err(e).context(self.id);
+            }
+
+            self.memory_dimms.push(size);
+        }
+
+        // Dimms are aligned, so the balloon takes back whatever they overshoot
+        qmp.execute("balloon", Some(json!({ "value": target_bytes })))
+            .await
+            .context(self.id)?;
+
+        self.memory = Some(target);
+        self.save_state(ctx).await?;
+
+        Ok(())
+    }
+
+    /// Hotplugs a disk into a running instance and records it so that it's
+    /// attached again on every boot. A missing qcow2 image is created with the
+    /// given size and preallocation. Returns the disk id used to detach it.
+    pub async fn attach_disk(
+        &mut self,
+        ctx: &BaseCtx,
+        path: PathBuf,
+        format: DiskFormat,
+        options: AttachDiskOptions,
+    ) -> Result<String> {
+        if !self.is_running() {
+            bail!("instance is not running");
+        }
+
+        validate_throttle_limits(options.iops_limit, options.bps_limit)?;
+
+        if self.disks.iter().any(|disk| disk.path == path) {
+            bail!("disk is already attached: {}", path.display());
+        }
+
+        if !path.exists() {
+            let (DiskFormat::Qcow2, Some(size)) = (format, options.size) else {
+                bail!("disk does not exist: {}", path.display());
+            };
+            create_qcow2_image(&path, size, options.preallocation).await?;
+        }
+
+        let index = (0..)
+            .find(|i| {
+                !self
+                    .disks
+                    .iter()
+                    .any(|disk| disk.id == format!("disk{}", i))
+            })
+            .unwrap();
+
+        let disk = InstanceDisk {
+            id: format!("disk{}", index),
+            path,
+            format,
+            iops_limit: options.iops_limit,
+            bps_limit: options.bps_limit,
+        };
+
+        let mut qmp = QmpClient::connect(&self.get_qmp_socket_path())
+            .await
+            .context(self.id)?;
+
+        qmp.execute(
+            "blockdev-add",
+            Some(json!({
+                "driver": disk.format.as_str(),
+                "node-name": disk.get_node_name(),
+                "file": { "driver": "file", "filename": disk.path },
+            })),
+        )
+        .await
+        .context(self.id)?;
+
+        let device_result = qmp
+            .execute(
+                "device_add",
+                Some(json!({
+                    "driver": "virtio-blk-pci",
+                    "id": disk.id,
+                    "drive": disk.get_node_name(),
+                })),
+            )
+            .await;
+
+        if let Err(e) = device_result {
+            let _ = qmp
+                .execute(
+                    "blockdev-del",
+                    Some(json!({ "node-name": disk.get_node_name() })),
+                )
+                .await;
+            return Err(e).context(self.id);
+        }
+
+        let throttle_result = set_disk_throttle(&mut qmp, &disk).await;
+
+        let id = disk.id.clone();
+        self.disks.push(disk);
+        self.save_state(ctx).await?;
+
+        throttle_result
+            .context("disk was attached without its throttle")
+            .context(self.id)?;
+
+        Ok(id)
+    }
+
+    /// Unplugs a disk attached with `attach_disk`. The guest has to release
+    /// the device first, and if it's busy the unplug stays pending, in which
+    /// case this fails and can be called again to finish detaching.
+    pub async fn detach_disk(&mut self, ctx: &BaseCtx, id: &str) -> Result<()> {
+        if !self.is_running() {
+            bail!("instance is not running");
+        }
+
+        let Some(index) = self.disks.iter().position(|disk| disk.id == id) else {
+            bail!("disk is not attached: {}", id);
+        };
+
+        let node_name = self.disks[index].get_node_name();
+
+        let mut qmp = QmpClient::connect(&self.get_qmp_socket_path())
+            .await
+            .context(self.id)?;
+
+        // Fails when an earlier unplug is still pending, which is fine since
+        // the node can be deleted once that completes
+        let device_result = qmp.execute("device_del", Some(json!({ "id": id }))).await;
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            let result = qmp
+                .execute("blockdev-del", Some(json!({ "node-name": node_name })))
+                .await;
+
+            match result {
+                Ok(_) => break,
+                Err(e) if tokio::time::Instant::now() >= deadline => {
+                    return Err(device_result.err().unwrap_or(e))
+                        .context("disk unplug is pending, the guest has not released it")
+                        .context(self.id);
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+
+        self.disks.remove(index);
+        self.save_state(ctx).await?;
+
+        Ok(())
+    }
+
+    /// Boots the instance. When `paused` the vcpus stay stopped until
+    /// `resume`, so early boot can be inspected through the monitor.
+    pub async fn start(&mut self, ctx: &Ctx, paused: bool) -> VmmResult<()> {
+        // A failed start tears everything down, which would take a running
+        // instance with it
+        if self.qemu.is_some() {
+            return Err(VmmError::InstanceRunning(self.id));
+        }
+
+        self.remove_stale_files(ctx)?;
+
+        self.paused = paused;
+
+        let start_timeout = Duration::from_secs(
+            self.machine
+                .config()
+                .start_timeout_secs
+                .unwrap_or(DEFAULT_START_TIMEOUT_SECS),
+        );
+
+        // Large images can take far longer to download than to boot, and the
+        // image cache has its own download timeouts, so the image is fetched
+        // before the start timeout begins
+        self.get_root_disk(ctx).await?;
+
+        let e =
+            match tokio::time::timeout(start_timeout, self.start_inner(ctx, start_timeout)).await {
+                Ok(Ok(())) => {
+                    self.started_at = Some(Instant::now());
+                    self.status = QemuStatus::Running;
+                    if let Err(e) = self.save_state(ctx).await {
+                        eprintln!("error: {:?}", e);
+                    }
+                    let event = InstanceEvent::Started {
+                        boot_seq: self.boot_seq,
+                        paused,
+                    };
+                    record_instance_event(ctx, self.id, event).await;
+                    return Ok(());
+                }
+                Ok(Err(e)) => e,
+                Err(_) => VmmError::StartTimeout(start_timeout),
+            };
+
+        self.tear_down(ctx).await;
+
+        Err(e)
+    }
+
+    /// Ejects the install ISO from a running instance's cdrom drive and drops
+    /// it from the machine config, so the installed system boots from disk
+    /// from now on
+    pub async fn eject_install_iso(&mut self, ctx: &BaseCtx) -> Result<()> {
+        if self.machine.config().install_iso.is_none() {
+            bail!("machine has no install iso: {}", self.machine.id());
+        }
+
+        if self.is_running() {
+            let mut qmp = QmpClient::connect(&self.get_qmp_socket_path())
+                .await
+                .context(self.id)?;
+
+            qmp.execute(
+                "eject",
+                Some(json!({ "device": INSTALL_CDROM_ID, "force": true })),
+            )
+            .await
+            .context(self.id)?;
+        }
+
+        self.machine.clear_install_iso(ctx).await
+    }
+
+    /// Starts the vcpus of an instance that was started paused
+    pub async fn resume(&mut self) -> Result<()> {
+        if !self.is_running() {
+            bail!("instance is not running");
+        }
+
+        let mut qmp = QmpClient::connect(&self.get_qmp_socket_path())
+            .await
+            .context(self.id)?;
+
+        qmp.execute("cont", None).await.context(self.id)?;
+
+        Ok(())
+    }
+
+    async fn start_inner(&mut self, ctx: &Ctx, start_timeout: Duration) -> VmmResult<()> {
+        self.machine
+            .config()
+            .validate()
+            .context("invalid machine config")
+            .context(*self.machine.id())?;
+
+        // Fail before touching the host network if qemu can't run at all
+        find_qemu()?;
+
+        if !self.share_dirs.is_empty() {
+            find_virtiofsd()?;
+        }
+
+        for share_dir in self.share_dirs.iter() {
+            share_dir.validate()?;
+        }
+
+        check_kvm()?;
+
+        // Each boot gets its own sequence number so its logs don't mix with
+        // earlier boots'
+        self.boot_seq += 1;
+        for share_dir in self.share_dirs.iter_mut() {
+            share_dir.set_boot_seq(self.boot_seq);
+        }
+        self.save_state(ctx).await?;
+
+        if let Some(network) = self.get_bridged_network() {
+            network.set_bridge_up_or_create(ctx).await?;
+            network.set_tap_up_or_create(self).await?;
+        }
+
+        for share_dir in self.share_dirs.iter_mut() {
+            share_dir.start(ctx).await?;
+        }
+
+        let qemu_args = self.get_qemu_args(ctx).await?;
+
+        // Records the display port
+        self.save_state(ctx).await?;
+
+        self.start_qemu(ctx, qemu_args).await?;
+
+        // qemu has finished starting up once it accepts QMP connections
+        let mut qmp = QmpClient::connect_with_retry(&self.get_qmp_socket_path(), start_timeout)
+            .await
+            .context(self.id)?;
+
+        // -blockdev has no throttling options, unlike the root drive's -drive
+        for disk in self.disks.iter() {
+            set_disk_throttle(&mut qmp, disk)
+                .await
+                .context(disk.id.clone())
+                .context(self.id)?;
+        }
+
+        Ok(())
+    }
+
+    /// qemu won't bind a server socket that already exists, so sockets left
+    /// behind by a qemu that crashed or was killed have to go first. Done
+    /// ahead of the start so that a failure doesn't tear down the network of
+    /// a qemu that is still running.
+    fn remove_stale_files(&self, ctx: &BaseCtx) -> Result<()> {
+        if let Some(pid) = get_qemu_pid(ctx, self.id)? {
+            bail!("qemu is already running with pid {}: {}", pid, self.id);
+        }
+
+        let pid_path = ctx.dirs().get_instance_pid_path(self.id)?;
+
+        let paths = [
+            &self.socket_paths.qmp,
+            &self.socket_paths.serial,
+            &self.socket_paths.monitor,
+            &pid_path,
+        ];
+
+        for path in paths {
+            remove_stale_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Best-effort teardown of whatever a failed start or stop left running,
+    /// so that the next attempt begins from scratch
+    async fn tear_down(&mut self, ctx: &BaseCtx) {
+        if let Err(e) = self.stop_qemu(Duration::ZERO).await {
+            eprintln!("error: {:?}", e);
+        }
+
+        if let Some(cgroup) = self.cgroup.take() {
+            let result = cgroup.remove().await;
+            if let Err(e) = result {
+                eprintln!("error: {:?}", e);
+            }
+        }
+
+        for share_dir in self.share_dirs.iter_mut() {
+            if let Err(e) = share_dir.stop(Duration::ZERO).await {
+                eprintln!("error: {:?}", e);
+            }
+        }
+
+        if let Some(seed_server) = self.seed_server.take() {
+            seed_server.stop();
+        }
+
+        if let Some(network) = self.get_bridged_network() {
+            // Fails if the tap was never created
+            let _ = network.delete_tap_device(self).await;
+
+            if let Err(e) = network.delete_bridge_if_unused(ctx).await {
+                eprintln!("error: {:?}", e);
+            }
+        }
+    }
+
+    /// Powers the guest down, killing qemu and its daemons if they don't exit
+    /// within the machine's graceful timeout. Returns whether anything had to
+    /// be killed.
+    pub async fn stop(&mut self, ctx: &BaseCtx) -> VmmResult<bool> {
+        let graceful_timeout = Duration::from_secs(
+            self.machine
+                .config()
+                .graceful_timeout_secs
+                .unwrap_or(DEFAULT_GRACEFUL_TIMEOUT_SECS),
+        );
+
+        let was_running = self.is_running();
+
+        let mut killed = match self.stop_qemu(graceful_timeout).await {
+            Ok(killed) => killed,
+            Err(e) => {
+                // Rather than leave the daemons and network of an instance
+                // that's half-stopped
+                self.tear_down(ctx).await;
+                return Err(e.into());
+            }
+        };
+
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove().await?;
+        }
+
+        for share_dir in self.share_dirs.iter_mut() {
+            killed |= share_dir.stop(graceful_timeout).await?;
+        }
+
+        if let Some(seed_server) = self.seed_server.take() {
+            seed_server.stop();
+        }
+
+        if let Some(network) = self.get_bridged_network() {
+            // Fails if the instance was never started
+            let _ = network.delete_tap_device(self).await;
+        }
+
+        // Also clears the status of a qemu that exited on its own
+        let had_display = self
+            .display_port
+            .take()
+            .inspect(|port| release_port(*port))
+            .is_some();
+        if had_display || self.status != QemuStatus::Stopped {
+            self.status = QemuStatus::Stopped;
+            self.save_state(ctx).await?;
+        }
+
+        if let Some(network) = self.get_bridged_network() {
+            network.delete_bridge_if_unused(ctx).await?;
+        }
+
+        if was_running {
+            record_instance_event(ctx, self.id, InstanceEvent::Stopped { killed }).await;
+        }
+
+        Ok(killed)
+    }
+
+    async fn start_qemu(&mut self, ctx: &BaseCtx, args: Vec<String>) -> VmmResult<()> {
+        assert!(self.qemu.is_none(), "qemu is already running");
+
+        let qemu = find_qemu()?;
+
+        let mut child = Command::new(qemu)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(VmmError::QemuSpawn)?;
+
+        if let Err(e) = self.init_cgroup(&child).await {
+            let _ = child.kill().await;
+            return Err(e.into());
+        }
+
+        if let Err(e) = self.pin_vcpus().await {
+            eprintln!("error: failed to pin vcpus: {:?}", e);
+        }
+
+        let mut tasks = Vec::new();
+
+        if let Some(stdout) = child.stdout.take() {
+            let id = self.id.clone();
+            let boot_seq = self.boot_seq;
+            let mut reader = BufReader::new(stdout).lines();
+            let logger = ctx.logger().clone();
+            let stdout_task = tokio::spawn(async move {
+                while let Ok(Some(line)) = reader.next_line().await {
+                    let _ = logger.log(LogLine::instance(
+                        id,
+                        boot_seq,
+                        LogStream::Stdout,
+                        LogSource::Qemu,
+                        line,
+                    ));
+                }
+            });
+            tasks.push(stdout_task);
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            let id = self.id.clone();
+            let boot_seq = self.boot_seq;
+            let mut reader = BufReader::new(stderr).lines();
+            let logger = ctx.logger().clone();
+            let stderr_task = tokio::spawn(async move {
+                while let Ok(Some(line)) = reader.next_line().await {
+                    let _ = logger.log(LogLine::instance(
+                        id,
+                        boot_seq,
+                        LogStream::Stderr,
+                        LogSource::Qemu,
+                        line,
+                    ));
+                }
+            });
+            tasks.push(stderr_task);
+        }
+
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let (exit_tx, exit_rx) = oneshot::channel();
+        tokio::spawn(monitor_qemu(
+            self.id,
+            child,
+            tasks,
+            kill_rx,
+            exit_tx,
+            self.exit_notifier.clone(),
+        ));
+
+        self.qemu = Some(QemuProcess {
+            kill: kill_tx,
+            exit: exit_rx,
+        });
+
+        Ok(())
+    }
+
+    /// Changes the throttle of a running instance's disk, the root disk when
+    /// `disk_id` is `None`. Limits set in the machine config or when attaching
+    /// only apply at boot, so live changes must go through QMP and last until
+    /// the instance is next started. A limit of `None` removes that throttle.
+    pub async fn set_io_throttle(
+        &self,
+        disk_id: Option<&str>,
+        iops_limit: Option<u64>,
+        bps_limit: Option<u64>,
+    ) -> Result<()> {
+        if !self.is_running() {
+            bail!("instance is not running");
+        }
+
+        validate_throttle_limits(iops_limit, bps_limit)?;
+
+        // The root disk is a -drive, which has a block backend name, while
+        // attached disks are only reachable through their device
+        let mut arguments = match disk_id {
+            None => json!({ "device": "root" }),
+            Some(disk_id) => {
+                if !self.disks.iter().any(|disk| disk.id == disk_id) {
+                    bail!("disk is not attached: {}", disk_id);
+                }
+                json!({ "id": disk_id })
+            }
+        };
+        set_throttle_arguments(&mut arguments, iops_limit, bps_limit);
+
+        let mut qmp = QmpClient::connect(&self.get_qmp_socket_path())
+            .await
+            .context(self.id)?;
+
+        qmp.execute("block_set_io_throttle", Some(arguments))
+            .await
+            .context(self.id)?;
+
+        Ok(())
+    }
+
+    /// Attaches a new virtiofs share to a running instance and returns its
+    /// tag, which the guest mounts it by. vhost-user needs guest memory in a
+    /// shared backend, and that is only set up at boot when the machine has
+    /// share dirs, so an instance started without any can't take one live.
+    pub async fn add_share_dir(&mut self, ctx: &BaseCtx, path: PathBuf) -> Result<String> {
+        if !self.is_running() {
+            bail!("instance is not running");
+        }
+
+        if !self.shared_memory {
+            bail!(
+                "instance was started without shared memory, add a share dir to the machine and restart it: {}",
+                self.id
+            );
+        }
+
+        let mut share_dir = ShareDir::new(ctx.dirs(), self.id, self.boot_seq, path)?;
+        share_dir.validate()?;
+        share_dir.start(ctx).await?;
+
+        if let Err(e) = self.attach_share_dir(&share_dir).await {
+            share_dir.stop(Duration::ZERO).await?;
+            return Err(e);
+        }
+
+        let tag = share_dir.tag().to_string();
+        self.share_dirs.push(share_dir);
+
+        Ok(tag)
+    }
+
+    async fn attach_share_dir(&self, share_dir: &ShareDir) -> Result<()> {
+        share_dir.wait_for_socket(Duration::from_secs(5)).await?;
+
+        let mut qmp = QmpClient::connect(&self.get_qmp_socket_path())
+            .await
+            .context(self.id)?;
+
+        qmp.execute(
+            "chardev-add",
+            Some(json!({
+                "id": share_dir.get_chardev_id(),
+                "backend": {
+                    "type": "socket",
+                    "data": {
+                        "addr": {
+                            "type": "unix",
+                            "data": { "path": share_dir.get_socket_path() },
+                        },
+                        "server": false,
+                    },
+                },
+            })),
+        )
+        .await
+        .context(self.id)?;
+
+        let device_result = qmp
+            .execute(
+                "device_add",
+                Some(json!({
+                    "driver": "vhost-user-fs-pci",
+                    "id": share_dir.get_device_id(),
+                    "chardev": share_dir.get_chardev_id(),
+                    "tag": share_dir.tag(),
+                    "queue-size": 1024,
+                })),
+            )
+            .await;
+
+        if let Err(e) = device_result {
+            let _ = qmp
+                .execute(
+                    "chardev-remove",
+                    Some(json!({ "id": share_dir.get_chardev_id() })),
+                )
+                .await;
+            return Err(e).context(self.id);
+        }
+
+        Ok(())
+    }
+
+    /// Detaches a virtiofs share from a running instance. The guest should
+    /// unmount it first, since I/O to a mounted share fails once it's gone.
+    pub async fn remove_share_dir(&mut self, tag: &str) -> Result<()> {
+        if !self.is_running() {
+            bail!("instance is not running");
+        }
+
+        let Some(index) = self.share_dirs.iter().position(|s| s.tag() == tag) else {
+            bail!("share dir is not attached: {}", tag);
+        };
+
+        let mut qmp = QmpClient::connect(&self.get_qmp_socket_path())
+            .await
+            .context(self.id)?;
+
+        let share_dir = &self.share_dirs[index];
+
+        qmp.execute(
+            "device_del",
+            Some(json!({ "id": share_dir.get_device_id() })),
+        )
+        .await
+        .context(self.id)?;
+
+        // The device is only released once the guest acknowledges the unplug,
+        // and until then the chardev is still in use and can't be removed
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            let result = qmp
+                .execute(
+                    "chardev-remove",
+                    Some(json!({ "id": share_dir.get_chardev_id() })),
+                )
+                .await;
+
+            match result {
+                Ok(_) => break,
+                Err(e) if tokio::time::Instant::now() >= deadline => {
+                    return Err(e)
+                        .context("guest did not release share dir")
+                        .context(self.id);
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+
+        let mut share_dir = self.share_dirs.remove(index);
+        share_dir.stop(Duration::from_secs(5)).await?;
+
+        Ok(())
+    }
+
+    async fn pin_vcpus(&self) -> Result<()> {
+        let Some(cpu_affinity) = &self.machine.config().cpu_affinity else {
+            return Ok(());
+        };
+
+        let mut qmp =
+            QmpClient::connect_with_retry(&self.get_qmp_socket_path(), Duration::from_secs(5))
+                .await
+                .context(self.id)?;
+
+        let cpus = qmp.execute("query-cpus-fast", None).await?;
+
+        for cpu in cpus.as_array().into_iter().flatten() {
+            let index = cpu.get("cpu-index").and_then(Value::as_u64);
+            let thread_id = cpu.get("thread-id").and_then(Value::as_i64);
+
+            let (Some(index), Some(thread_id)) = (index, thread_id) else {
+                continue;
+            };
+
+            let Some(host_cpu) = cpu_affinity.get(index as usize) else {
+                continue;
+            };
+
+            if let Err(e) = set_thread_affinity(thread_id as libc::pid_t, *host_cpu) {
+                eprintln!(
+                    "error: failed to pin vcpu {} (thread {}) to host cpu {}: {}",
+                    index, thread_id, host_cpu, e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn init_cgroup(&mut self, child: &Child) -> Result<()> {
+        let config = self.machine.config();
+        let cpu_quota = config.cpu_quota;
+        let memory_limit = config.memory_limit;
+
+        if cpu_quota.is_none() && memory_limit.is_none() {
+            return Ok(());
+        }
+
+        let pid = child
+            .id()
+            .context("qemu exited before it could be placed in a cgroup")?;
+
+        let cgroup = InstanceCgroup::create(self.id).await?;
+
+        if let Some(cpu_quota) = cpu_quota {
+            cgroup.set_cpu_quota(cpu_quota).await?;
+        }
+
+        if let Some(memory_limit) = memory_limit {
+            cgroup.set_memory_limit(memory_limit).await?;
+        }
+
+        cgroup.add_process(pid).await?;
+
+        self.cgroup = Some(cgroup);
+
+        Ok(())
+    }
+
+    /// Checks whether qemu has exited on its own, and if so cleans up after it
+    /// and returns its exit status
+    pub async fn try_wait(&mut self, ctx: &BaseCtx) -> Result<Option<ExitStatus>> {
+        let Some(process) = &mut self.qemu else {
+            return Ok(None);
+        };
+
+        let status = match process.exit.try_recv() {
+            Ok(status) => status,
+            Err(oneshot::error::TryRecvError::Empty) => return Ok(None),
+            Err(oneshot::error::TryRecvError::Closed) => Err(anyhow!("qemu monitor task failed")),
+        };
+
+        self.qemu = None;
+
+        let status = status.context(self.id)?;
+
+        self.started_at = None;
+        self.status = QemuStatus::Exited(status.code());
+        self.save_state(ctx).await?;
+
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove().await?;
+        }
+
+        for share_dir in self.share_dirs.iter_mut() {
+            share_dir.stop(Duration::from_secs(5)).await?;
+        }
+
+        Ok(Some(status))
+    }
+
+    /// Asks the guest to power down and kills qemu if it's still running
+    /// after `graceful_timeout`. Returns whether it had to be killed.
+    async fn stop_qemu(&mut self, graceful_timeout: Duration) -> Result<bool> {
+        let Some(mut process) = self.qemu.take() else {
+            return Ok(false);
+        };
+
+        // Without QMP there's no way to ask the guest, so only wait if the
+        // powerdown request went through
+        let powerdown_result = async {
+            let mut qmp = QmpClient::connect(&self.get_qmp_socket_path()).await?;
+            qmp.execute("system_powerdown", None).await
+        }
+        .await;
+
+        let graceful_timeout = match powerdown_result {
+            Ok(_) => graceful_timeout,
+            Err(_) => Duration::ZERO,
+        };
+
+        let exit = &mut process.exit;
+        let (status, killed) = match tokio::time::timeout(graceful_timeout, &mut *exit).await {
+            Ok(status) => (status, false),
+            Err(_) => {
+                // Fails if qemu exited in the meantime
+                let _ = process.kill.send(());
+                (exit.await, true)
+            }
+        };
+
+        let status = status
+            .context("qemu monitor task failed")
+            .context(self.id)??;
+
+        if !killed && !status.success() {
+            anyhow::bail!("qemu exited with {}", status);
+        }
+
+        Ok(killed)
+    }
+}
+
+/// Waits for qemu to exit, killing it if asked to, then waits for its output
+/// to be logged, hands over the exit status and notifies the instance's owner
+async fn monitor_qemu(
+    id: Id,
+    mut child: Child,
+    tasks: Vec<JoinHandle<()>>,
+    mut kill: oneshot::Receiver<()>,
+    exit: oneshot::Sender<Result<ExitStatus>>,
+    exit_notifier: Option<mpsc::UnboundedSender<Id>>,
+) {
+    let status = tokio::select! {
+        status = child.wait() => status.context("failed to wait for qemu"),
+        Ok(()) = &mut kill => match child.kill().await {
+            Ok(()) => child.wait().await.context("failed to wait for qemu"),
+            Err(e) => Err(e).context("failed to kill qemu"),
+        },
+    };
+
+    for task in tasks {
+        let _ = task.await;
+    }
+
+    // Nobody is waiting if the instance gave up on qemu
+    let _ = exit.send(status.context(id));
+
+    if let Some(exit_notifier) = exit_notifier {
+        let _ = exit_notifier.send(id);
+    }
+}
+
+/// Resolves the qemu system emulator for the host architecture
+pub fn find_qemu() -> Result<PathBuf> {
+    let name = format!("qemu-system-{}", std::env::consts::ARCH);
+    find_program(&name).ok_or(anyhow!("{} not found; install qemu", name))
+}
+
+/// Checks that `/dev/kvm` exists and the current user can open it, which
+/// qemu otherwise reports with a long and unclear error
+pub fn check_kvm() -> Result<()> {
+    let kvm = Path::new("/dev/kvm");
+
+    if !kvm.exists() {
+        bail!(
+            "KVM not available; /dev/kvm does not exist, enable virtualization in the BIOS or load the kvm module"
+        );
+    }
+
+    let kvm_path = std::ffi::CString::new("/dev/kvm")?;
+    // SAFETY: kvm_path is a NUL-terminated string that outlives the call, and
+    // access only reads it
+    if unsafe { libc::access(kvm_path.as_ptr(), libc::R_OK | libc::W_OK) } != 0 {
+        bail!("KVM not available; no read/write access to /dev/kvm, add yourself to the kvm group");
+    }
+
+    Ok(())
+}
+
+/// Asks a running instance's qemu whether the vcpus are stopped, which is the
+/// only reliable source since they can also be stopped or resumed through the
+/// monitor
+pub async fn query_paused(qmp_socket_path: &Path) -> Result<bool> {
+    let mut qmp = QmpClient::connect(qmp_socket_path).await?;
+
+    let status = qmp.execute("query-status", None).await?;
+
+    // `prelaunch` is how qemu reports having been started with -S
+    let paused = matches!(
+        status.get("status").and_then(|status| status.as_str()),
+        Some("paused" | "prelaunch")
+    );
+
+    Ok(paused)
+}
+
+/// Derives an instance's MAC address from its id, in qemu's locally
+/// administered range
+pub fn get_mac_address(id: Id) -> String {
+    let id: [u8; 16] = id.into();
+    let id = &id[id.len() - 3..];
+    format!("52:54:00:{:02x}:{:02x}:{:02x}", id[0], id[1], id[2])
+}
+
+/// Returns the pid of the instance's qemu if it's running, whichever process
+/// started it
+pub fn get_qemu_pid(ctx: &BaseCtx, id: Id) -> Result<Option<libc::pid_t>> {
+    let pid_path = ctx.dirs().get_instance_pid_path(id)?;
+    let Ok(pid_text) = std::fs::read_to_string(&pid_path) else {
+        return Ok(None);
+    };
+
+    let pid: libc::pid_t = pid_text
+        .trim()
+        .parse()
+        .context("invalid qemu pid file")
+        .context(pid_path.display().to_string())?;
+
+    // kill treats 0 and negative pids as process groups
+    if pid <= 0 {
+        bail!("invalid qemu pid file: {}", pid_path.display());
+    }
+
+    // qemu removes the file when it exits cleanly, but not when it's killed.
+    // SAFETY: signal 0 only checks that the process exists and delivers
+    // nothing, and pid is positive so it names a single process
+    if unsafe { libc::kill(pid, 0) } != 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(pid))
+}
+
+async fn warn_unknown_machine_type(machine_type: &str) {
+    let Ok(qemu) = find_qemu() else {
+        return;
+    };
+
+    let output = cmd(&qemu.to_string_lossy(), &["-machine", "help"]).await;
+
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        _ => {
+            eprintln!("warning: could not list qemu machine types");
+            return;
+        }
+    };
+
+    // The first line is a "Supported machines are:" header, and every other
+    // line starts with a machine type name
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let known = stdout
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|name| name == machine_type);
+
+    if !known {
+        eprintln!("warning: unknown qemu machine type: {}", machine_type);
+    }
+}
+
+fn validate_boot_order(boot_order: &str) -> Result<()> {
+    if boot_order.is_empty() {
+        bail!("boot order is empty");
+    }
+    // a-b are floppies, c the first disk, d the first cdrom and n-p network
+    if let Some(c) = boot_order
+        .chars()
+        .find(|c| !matches!(c, 'a'..='d' | 'n'..='p'))
+    {
+        bail!("invalid boot device {:?} in boot order: {}", c, boot_order);
+    }
+    Ok(())
+}
+
+fn validate_cpu_affinity(cpus: u8, cpu_affinity: &[usize]) -> Result<()> {
+    if cpu_affinity.len() != cpus as usize {
+        bail!(
+            "cpu_affinity lists {} host cpus but the machine has {} vcpus",
+            cpu_affinity.len(),
+            cpus
+        );
+    }
+
+    // available_parallelism would be limited by our own affinity mask and
+    // cgroup quota, but qemu's vcpus can be pinned to any online cpu
+    let online_cpus_path = "/sys/devices/system/cpu/online";
+    let online_cpus_text = std::fs::read_to_string(online_cpus_path)
+        .context("failed to read online host cpus")
+        .context(online_cpus_path)?;
+    let online_cpus_text = online_cpus_text.trim();
+    let online_cpus = parse_cpu_list(online_cpus_text)
+        .context("failed to parse online host cpus")
+        .context(online_cpus_path)?;
+
+    if let Some(cpu) = cpu_affinity.iter().find(|cpu| !online_cpus.contains(cpu)) {
+        bail!(
+            "cpu_affinity host cpu {} is not online (online cpus: {})",
+            cpu,
+            online_cpus_text
+        );
+    }
+
+    Ok(())
+}
+
+/// Parses a kernel cpu list like `0-3,6,8-9`
+fn parse_cpu_list(text: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in text.split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.parse().context(range.to_string())?;
+                let end: usize = end.parse().context(range.to_string())?;
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(range.parse().context(range.to_string())?),
+        }
+    }
+    Ok(cpus)
+}
+
+fn set_thread_affinity(thread_id: libc::pid_t, cpu: usize) -> std::io::Result<()> {
+    // SAFETY: cpu_set_t is a plain bitmask, so an all-zero value is a valid
+    // empty set, and sched_setaffinity only reads from the set we pass it
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        let ret = libc::sched_setaffinity(thread_id, size_of::<libc::cpu_set_t>(), &set);
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn validate_numa_nodes(cpus: u8, memory: Byte, nodes: &[MachineNumaNodeConfig]) -> Result<()> {
+    let mut assigned = vec![false; cpus as usize];
+    let mut total_memory = 0u64;
+
+    for (i, node) in nodes.iter().enumerate() {
+        if node.cpus.is_empty() {
+            bail!("numa node {} has no cpus", i);
+        }
+
+        for cpu in node.cpus.iter() {
+            let Some(slot) = assigned.get_mut(*cpu as usize) else {
+                bail!(
+                    "numa node {} cpu {} is out of range (cpus: {})",
+                    i,
+                    cpu,
+                    cpus
+                );
+            };
+            if *slot {
+                bail!("cpu {} is assigned to more than one numa node", cpu);
+            }
+            *slot = true;
+        }
+
+        total_memory += node.memory.as_u64();
+    }
+
+    if let Some(cpu) = assigned.iter().position(|assigned| !assigned) {
+        bail!("cpu {} is not assigned to any numa node", cpu);
+    }
+
+    if total_memory != memory.as_u64() {
+        bail!(
+            "numa node memory adds up to {} but the machine has {}",
+            Byte::from_u64(total_memory),
+            memory
+        );
+    }
+
+    Ok(())
+}
+
+/// Rounds a memory size up to 2 MiB, the largest page size a backend may use.
+/// qemu refuses backends that aren't a multiple of their page size.
+fn align_memory_size(size: u64) -> u64 {
+    size.next_multiple_of(MEMORY_ALIGN)
+}
+
+fn validate_throttle_limits(iops_limit: Option<u64>, bps_limit: Option<u64>) -> Result<()> {
+    if iops_limit == Some(0) {
+        bail!("iops_limit must be positive");
+    }
+    if bps_limit == Some(0) {
+        bail!("bps_limit must be positive");
+    }
+    Ok(())
+}
+
+/// Applies an attached disk's limits, which qemu otherwise knows nothing about
+async fn set_disk_throttle(qmp: &mut QmpClient, disk: &InstanceDisk) -> Result<()> {
+    if disk.iops_limit.is_none() && disk.bps_limit.is_none() {
+        return Ok(());
+    }
+
+    let mut arguments = json!({ "id": disk.id });
+    set_throttle_arguments(&mut arguments, disk.iops_limit, disk.bps_limit);
+
+    qmp.execute("block_set_io_throttle", Some(arguments))
+        .await
+        .context("failed to set io throttle")?;
+
+    Ok(())
+}
+
+/// `block_set_io_throttle` requires every limit, where 0 means unlimited
+fn set_throttle_arguments(arguments: &mut Value, iops_limit: Option<u64>, bps_limit: Option<u64>) {
+    arguments["iops"] = json!(iops_limit.unwrap_or(0));
+    arguments["iops_rd"] = json!(0);
+    arguments["iops_wr"] = json!(0);
+    arguments["bps"] = json!(bps_limit.unwrap_or(0));
+    arguments["bps_rd"] = json!(0);
+    arguments["bps_wr"] = json!(0);
+}
+
+fn get_throttle_drive_options(iops_limit: Option<u64>, bps_limit: Option<u64>) -> Result<String> {
+    validate_throttle_limits(iops_limit, bps_limit)?;
+
+    let mut options = String::new();
+    if let Some(iops_limit) = iops_limit {
+        options += &format!(",throttling.iops-total={}", iops_limit);
+    }
+    if let Some(bps_limit) = bps_limit {
+        options += &format!(",throttling.bps-total={}", bps_limit);
+    }
+
+    Ok(options)
+}
+
+impl Drop for Instance {
+    /// Instances are meant to be stopped first, but qemu is killed rather
+    /// than left running unsupervised if one isn't, e.g. on an early return
+    fn drop(&mut self) {
+        if let Some(qemu) = self.qemu.take() {
+            eprintln!(
+                "error: instance {} dropped while running, killing qemu",
+                self.id
+            );
+            let _ = qemu.kill.send(());
+        }
+
+        if let Some(port) = self.display_port.take() {
+            release_port(port);
+        }
+    }
+}
+
+/// Display ports handed out to instances of this process. Instances start
+/// concurrently, and one that's starting hasn't bound its port yet.
+static RESERVED_PORTS: std::sync::Mutex<BTreeSet<u16>> = std::sync::Mutex::new(BTreeSet::new());
+
+/// Finds and reserves a port that nothing is listening on yet and no other
+/// instance has been given. qemu binds it shortly after, so this can still
+/// race with other programs. Released with `release_port`.
+fn reserve_free_port(ports: RangeInclusive<u16>) -> Option<u16> {
+    let mut reserved = RESERVED_PORTS.lock().unwrap();
+    let port = ports
+        .into_iter()
+        .find(|port| !reserved.contains(port) && TcpListener::bind(("127.0.0.1", *port)).is_ok())?;
+    reserved.insert(port);
+    Some(port)
+}
+
+fn release_port(port: u16) {
+    RESERVED_PORTS.lock().unwrap().remove(&port);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::{MachineConfig, test_machine_config};
+
+    const MIB: u64 = 1024 * 1024;
+
+    async fn create_test_instance(ctx: &BaseCtx, config: MachineConfig) -> Instance {
+        let machine = Machine::new(ctx, Id::new().unwrap(), config).await.unwrap();
+        Instance::new(ctx, Id::new().unwrap(), machine, None)
+            .await
+            .unwrap()
+    }
+
+    fn get_backend_sizes(args: &[String]) -> Vec<u64> {
+        args.iter()
+            .filter(|arg| arg.starts_with("memory-backend-"))
+            .map(|arg| {
+                let size = arg.split(',').find_map(|prop| prop.strip_prefix("size="));
+                size.unwrap().trim_end_matches('B').parse().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_cpu_list_expands_ranges() {
+        assert_eq!(parse_cpu_list("0").unwrap(), vec![0]);
+        assert_eq!(
+            parse_cpu_list("0-3,6,8-9").unwrap(),
+            vec![0, 1, 2, 3, 6, 8, 9]
+        );
+        assert!(parse_cpu_list("0-x").is_err());
+    }
+
+    #[test]
+    fn throttle_arguments_set_every_limit() {
+        let mut arguments = json!({ "id": "disk0" });
+        set_throttle_arguments(&mut arguments, None, Some(1000));
+
+        let expected = json!({
+            "id": "disk0",
+            "iops": 0,
+            "iops_rd": 0,
+            "iops_wr": 0,
+            "bps": 1000,
+            "bps_rd": 0,
+            "bps_wr": 0,
+        });
+        assert_eq!(arguments, expected);
+    }
+
+    #[test]
+    fn disk_saved_without_limits_is_unthrottled() {
+        let disk: InstanceDisk = serde_json::from_value(json!({
+            "id": "disk0",
+            "path": "/tmp/disk0.qcow2",
+            "format": "qcow2",
+        }))
+        .unwrap();
+
+        assert!(disk.iops_limit.is_none());
+        assert!(disk.bps_limit.is_none());
+    }
+
+    #[test]
+    fn align_memory_size_rounds_up_to_2mib() {
+        assert_eq!(align_memory_size(0), 0);
+        assert_eq!(align_memory_size(1), 2 * MIB);
+        assert_eq!(align_memory_size(1536 * MIB), 1536 * MIB);
+        assert_eq!(align_memory_size(1536 * MIB + 1), 1538 * MIB);
+        assert_eq!(align_memory_size(1537 * MIB), 1538 * MIB);
+    }
+
+    #[tokio::test]
+    async fn guest_memory_below_minimum_is_rejected() {
+        let root = tempfile::tempdir().unwrap();
+        let ctx = BaseCtx::from_dirs(VmmDirs::from_root(root.path()));
+
+        let mut config = test_machine_config();
+        config.memory = Byte::from_u64(MIN_MEMORY - 1);
+        let instance = create_test_instance(&ctx, config).await;
+
+        assert!(instance.get_guest_memory().is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_arg_matches_backend_size() {
+        let root = tempfile::tempdir().unwrap();
+        let ctx = BaseCtx::from_dirs(VmmDirs::from_root(root.path()));
+
+        let mut config = test_machine_config();
+        config.memory = Byte::from_u64(1536 * MIB + 1);
+        config.hugepages = true;
+        let instance = create_test_instance(&ctx, config).await;
+
+        let backend_sizes = get_backend_sizes(&instance.get_memory_qemu_args().unwrap());
+        assert_eq!(backend_sizes, vec![1538 * MIB]);
+        assert_eq!(
+            instance.get_memory_arg().unwrap(),
+            format!("{}B", 1538 * MIB)
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_arg_matches_numa_backend_sizes() {
+        let root = tempfile::tempdir().unwrap();
+        let ctx = BaseCtx::from_dirs(VmmDirs::from_root(root.path()));
+
+        let mut config = test_machine_config();
+        config.cpus = 2;
+        config.memory = Byte::from_u64(1536 * MIB + 1);
+        config.numa_nodes = vec![
+            MachineNumaNodeConfig {
+                cpus: vec![0],
+                memory: Byte::from_u64(1024 * MIB + 1),
+            },
+            MachineNumaNodeConfig {
+                cpus: vec![1],
+                memory: Byte::from_u64(512 * MIB),
+            },
+        ];
+        let instance = create_test_instance(&ctx, config).await;
+
+        let backend_sizes = get_backend_sizes(&instance.get_memory_qemu_args().unwrap());
+        assert_eq!(backend_sizes, vec![1026 * MIB, 512 * MIB]);
+
+        let memory: u64 = backend_sizes.iter().sum();
+        assert_eq!(instance.get_memory_arg().unwrap(), format!("{}B", memory));
+    }
+
+    #[tokio::test]
+    async fn open_leaves_boot_seq_unchanged() {
+        let root = tempfile::tempdir().unwrap();
+        let ctx = BaseCtx::from_dirs(VmmDirs::from_root(root.path()));
+
+        let machine = Machine::new(&ctx, Id::new().unwrap(), test_machine_config())
+            .await
+            .unwrap();
+        let id = Id::new().unwrap();
+        Instance::new(&ctx, id, machine, None).await.unwrap();
+
+        for _ in 0..2 {
+            let instance = Instance::open(&ctx, id).await.unwrap();
+            assert_eq!(instance.boot_seq, 0);
+        }
+
+        let state = InstanceState::open(&ctx, id).await.unwrap();
+        assert_eq!(state.boot_seq, 0);
+    }
+
+    #[tokio::test]
+    async fn open_loads_display_port() {
+        let root = tempfile::tempdir().unwrap();
+        let ctx = BaseCtx::from_dirs(VmmDirs::from_root(root.path()));
+
+        let mut instance = create_test_instance(&ctx, test_machine_config()).await;
+        instance.display_port = Some(5901);
+        instance.save_state(&ctx).await.unwrap();
+
+        let instance = Instance::open(&ctx, instance.id).await.unwrap();
+        assert_eq!(instance.display_port, Some(5901));
     }
 }
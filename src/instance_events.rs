@@ -0,0 +1,98 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{ctx::BaseCtx, id::Id};
+
+/// A state transition of an instance, as recorded in its events file
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum InstanceEvent {
+    Created,
+    Started {
+        boot_seq: u64,
+        paused: bool,
+    },
+    /// qemu exited without being stopped through vmm
+    QemuExited {
+        status: String,
+    },
+    Stopped {
+        killed: bool,
+    },
+    SnapshotTaken {
+        name: String,
+    },
+    RestartedByPolicy {
+        retries: u32,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InstanceEventEntry {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: InstanceEvent,
+}
+
+/// Appends an event to the instance's events file. The file is only an audit
+/// trail, so failing to write it is reported but doesn't fail the caller.
+pub async fn record_instance_event(ctx: &BaseCtx, instance_id: Id, event: InstanceEvent) {
+    if let Err(e) = append_instance_event(ctx, instance_id, event).await {
+        eprintln!("error: failed to record instance event: {:?}", e);
+    }
+}
+
+async fn append_instance_event(ctx: &BaseCtx, instance_id: Id, event: InstanceEvent) -> Result<()> {
+    let entry = InstanceEventEntry {
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        event,
+    };
+
+    let mut line = serde_json::to_string(&entry).context("failed to serialize instance event")?;
+    line.push('\n');
+
+    let events_path = ctx.dirs().get_instance_events_path(instance_id)?;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&events_path)
+        .await
+        .context("failed to open instance events file")
+        .context(instance_id)?;
+
+    file.write_all(line.as_bytes())
+        .await
+        .context("failed to write instance event")
+        .context(instance_id)?;
+
+    Ok(())
+}
+
+/// Reads the instance's events, oldest first. Lines that don't parse, like a
+/// partial line from a crash mid-write, are skipped.
+pub async fn read_instance_events(
+    ctx: &BaseCtx,
+    instance_id: Id,
+) -> Result<Vec<InstanceEventEntry>> {
+    let events_path = ctx.dirs().get_instance_events_path(instance_id)?;
+    if !events_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let text = tokio::fs::read_to_string(&events_path)
+        .await
+        .context("failed to read instance events file")
+        .context(instance_id)?;
+
+    let entries = text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+
+    Ok(entries)
+}
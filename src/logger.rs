@@ -4,7 +4,7 @@ use std::{
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 
 use crate::{id::Id, vmm_dirs::VmmDirs};
 
@@ -79,14 +79,71 @@ impl AsRef<str> for LogSource {
     }
 }
 
+/// Which midnight log files roll over at
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogRotation {
+    #[default]
+    Utc,
+    Local,
+}
+
+impl LogRotation {
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("VMM_LOG_ROTATION") {
+            Ok(value) => value.parse().context("invalid VMM_LOG_ROTATION"),
+            Err(std::env::VarError::NotPresent) => Ok(Self::default()),
+            Err(e) => Err(e).context("invalid VMM_LOG_ROTATION"),
+        }
+    }
+
+    fn days_since_epoch(&self, when: SystemTime) -> Result<i64> {
+        let secs = when.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let offset = match self {
+            LogRotation::Utc => 0,
+            LogRotation::Local => get_utc_offset(secs)?,
+        };
+        Ok(get_day(secs, offset))
+    }
+}
+
+impl std::str::FromStr for LogRotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "utc" => Ok(LogRotation::Utc),
+            "local" => Ok(LogRotation::Local),
+            _ => bail!("invalid log rotation: {s} (expected utc or local)"),
+        }
+    }
+}
+
+/// Day number of a unix time, in a timezone `offset` seconds east of UTC
+fn get_day(secs: i64, offset: i64) -> i64 {
+    (secs + offset).div_euclid(86_400)
+}
+
+fn get_utc_offset(secs: i64) -> Result<i64> {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        bail!(
+            "failed to get local time: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(tm.tm_gmtoff as i64)
+}
+
 #[derive(Debug, Clone)]
 pub struct Logger {
     dirs: VmmDirs,
+    rotation: LogRotation,
 }
 
 impl Logger {
-    pub fn new(dirs: VmmDirs) -> Self {
-        Self { dirs }
+    pub fn new(dirs: VmmDirs, rotation: LogRotation) -> Self {
+        Self { dirs, rotation }
     }
 
     pub fn log(&self, log: LogLine) -> Result<()> {
@@ -105,7 +162,7 @@ impl Logger {
             }
         };
 
-        let days_since_epoch = log.when.duration_since(UNIX_EPOCH)?.as_secs() / 86_400;
+        let days_since_epoch = self.rotation.days_since_epoch(log.when)?;
 
         let mut file = match seq {
             Some(boot_seq) => OpenOptions::new()
@@ -135,3 +192,46 @@ impl Logger {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 86_400;
+
+    #[test]
+    fn day_rolls_over_at_utc_midnight() {
+        assert_eq!(get_day(0, 0), 0);
+        assert_eq!(get_day(DAY - 1, 0), 0);
+        assert_eq!(get_day(DAY, 0), 1);
+    }
+
+    #[test]
+    fn day_rolls_over_at_local_midnight() {
+        // East of UTC, local midnight comes an hour before UTC midnight
+        assert_eq!(get_day(DAY - 3601, 3600), 0);
+        assert_eq!(get_day(DAY - 3600, 3600), 1);
+
+        // West of UTC it comes an hour after, and the first hour after the
+        // epoch is still the previous day
+        assert_eq!(get_day(0, -3600), -1);
+        assert_eq!(get_day(DAY + 3599, -3600), 0);
+        assert_eq!(get_day(DAY + 3600, -3600), 1);
+    }
+
+    #[test]
+    fn utc_rotation_ignores_timezone() {
+        let when = UNIX_EPOCH + std::time::Duration::from_secs(DAY as u64 - 1);
+        assert_eq!(LogRotation::Utc.days_since_epoch(when).unwrap(), 0);
+
+        let when = UNIX_EPOCH + std::time::Duration::from_secs(DAY as u64);
+        assert_eq!(LogRotation::Utc.days_since_epoch(when).unwrap(), 1);
+    }
+
+    #[test]
+    fn parses_rotation() {
+        assert_eq!("utc".parse::<LogRotation>().unwrap(), LogRotation::Utc);
+        assert_eq!("local".parse::<LogRotation>().unwrap(), LogRotation::Local);
+        assert!("UTC+1".parse::<LogRotation>().is_err());
+    }
+}
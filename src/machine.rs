@@ -1,55 +1,342 @@
-use std::{net::Ipv4Addr, path::PathBuf, process::Stdio};
+use std::{
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+};
 
 use anyhow::{Context, Result, anyhow, bail};
+use base64::prelude::{BASE64_STANDARD, Engine};
 use byte_unit::Byte;
 use futures::StreamExt;
 use ipnet::Ipv4Net;
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, BufReader},
     process::Command,
 };
 use url::Url;
 
 use crate::{
-    ctx::Ctx,
+    cmd::{DEFAULT_CMD_TIMEOUT, cmd_success, find_program},
+    config_format::{ConfigFormat, interpolate_value},
+    ctx::{BaseCtx, Ctx},
+    error::{VmmError, VmmResult},
     id::Id,
-    image_cache::GetImageHashResult,
+    image_cache::validate_image_url,
+    instance::get_mac_address,
     logger::{LogLine, LogSource, LogStream},
+    network::{NetworkConfig, NetworkMode},
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct MachineConfig {
+    /// Machine whose config this one extends. Fields that are missing or null
+    /// here are taken from the template, and structs are merged field by field,
+    /// but an enum, like `restart` or a network `interface`, replaces the
+    /// template's as a whole.
+    pub template: Option<Id>,
     pub name: String,
+    pub machine_type: Option<String>,
+    /// qemu boot order as drive letters, e.g. `c` for the disk, `d` for the
+    /// cdrom or `n` for the network. Defaults to `c`. The cloud-init seed is
+    /// attached as a cdrom but isn't bootable, cloud-init finds it on its own
+    /// regardless of the boot order.
+    pub boot_order: Option<String>,
+    /// Installer ISO attached as a cdrom. It's booted from once per start,
+    /// and the guest reboots into the disk, so the installer isn't re-entered.
+    /// Cleared by `vmm instance eject-cdrom` once the install is done.
+    pub install_iso: Option<PathBuf>,
+    /// Boots this kernel directly instead of going through the image's
+    /// bootloader, with the root disk as the rootfs. Overrides `boot_order`.
+    pub kernel: Option<PathBuf>,
+    /// Only used with `kernel`
+    pub initrd: Option<PathBuf>,
+    /// Kernel command line, only used with `kernel`. Defaults to
+    /// `root=/dev/vda1 rw console=ttyS0`.
+    pub cmdline: Option<String>,
     pub cpus: u8,
+    pub cpu_quota: Option<f64>,
+    pub cpu_affinity: Option<Vec<usize>>,
     pub memory: Byte,
+    /// Upper bound for hotplugging memory into a running instance, which is
+    /// only possible when this is set
+    pub max_memory: Option<Byte>,
+    /// Number of hotplug slots, defaults to 8
+    pub memory_slots: Option<u8>,
+    pub memory_limit: Option<Byte>,
+    /// How long starting an instance may take once its image has been
+    /// fetched before it's torn down. Defaults to 120 seconds.
+    pub start_timeout_secs: Option<u64>,
+    /// How long the guest gets to power down when stopped before qemu is
+    /// killed. Defaults to 30 seconds.
+    pub graceful_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// Also writes the guest's serial console to `console.log` in the
+    /// instance's log dir
+    #[serde(default)]
+    pub console_log: bool,
+    /// Exposes qemu's human monitor on a unix socket for `vmm instance
+    /// monitor`
+    #[serde(default)]
+    pub hmp_monitor: bool,
+    #[serde(default)]
+    pub display: Display,
+    #[serde(default)]
+    pub hugepages: bool,
+    #[serde(default)]
+    pub numa_nodes: Vec<MachineNumaNodeConfig>,
     pub image: MachineImageConfig,
     pub share_dirs: Vec<PathBuf>,
     pub user: MachineUserConfig,
-    pub network: MachineNetworkConfig,
+    /// Used verbatim as the cloud-init user-data instead of generating it from
+    /// `user`, which is then ignored
+    pub user_data_file: Option<PathBuf>,
+    /// Whether to attach a cloud-init seed that sets up the user and network.
+    /// Turn off to boot images that are already set up. Defaults to true.
+    pub cloud_init: Option<bool>,
+    #[serde(default)]
+    pub cloud_init_seed: CloudInitSeed,
+    #[serde(default)]
+    pub ntp_servers: Vec<String>,
+    /// Machines without a network are fully isolated and get no network
+    /// interface at all
+    #[serde(default)]
+    pub network: Option<MachineNetworkConfig>,
+    /// Appended verbatim to the end of the qemu command line, after all the
+    /// generated arguments so they can override them. They aren't validated,
+    /// so a typo only shows up as qemu failing to start.
+    #[serde(default)]
+    pub extra_qemu_args: Vec<String>,
+    /// Created by `vmm run` for a throwaway instance, and safe to delete once
+    /// that instance is gone
+    #[serde(default)]
+    pub ephemeral: bool,
 }
 
 impl MachineConfig {
-    pub async fn open(ctx: &Ctx, id: Id) -> Result<Self> {
+    pub fn has_cloud_init(&self) -> bool {
+        self.cloud_init.unwrap_or(true)
+    }
+
+    /// Without cloud-init nothing configures the guest's network, so it has to
+    /// either already be set up with the static address or use DHCP
+    pub fn validate_without_cloud_init(&self, network: Option<&NetworkConfig>) -> Result<()> {
+        if self.user_data_file.is_some() {
+            bail!("user_data_file requires cloud_init");
+        }
+
+        let (Some(machine_network), Some(network)) = (&self.network, network) else {
+            return Ok(());
+        };
+
+        match &machine_network.interface {
+            MachineInterfaceConfig::Static(config) => {
+                eprintln!(
+                    "warning: cloud-init is off, so the guest must already be configured with {}",
+                    config.ip
+                );
+            }
+            MachineInterfaceConfig::Dhcp(_) => {
+                // qemu always serves DHCP on user-mode networks
+                if !network.dhcp && network.mode != NetworkMode::User {
+                    bail!(
+                        "machine uses dhcp without cloud-init, but dhcp is off for network: {}",
+                        network.name
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The guest fetches an SMBIOS seed over the network before anything has
+    /// configured it, so that only works with DHCP
+    pub fn validate_smbios_seed(&self, network: Option<&NetworkConfig>) -> Result<()> {
+        let (Some(machine_network), Some(network)) = (&self.network, network) else {
+            bail!("smbios cloud-init seed requires a network");
+        };
+
+        match &machine_network.interface {
+            MachineInterfaceConfig::Static(_) => {
+                bail!("smbios cloud-init seed requires a dhcp interface");
+            }
+            MachineInterfaceConfig::Dhcp(_) => {
+                if !network.dhcp && network.mode != NetworkMode::User {
+                    bail!(
+                        "smbios cloud-init seed requires dhcp, but it's off for network: {}",
+                        network.name
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn open(ctx: &BaseCtx, id: Id) -> Result<Self> {
+        Self::read(ctx, id, true).await
+    }
+
+    /// Loads the config as written, without resolving `${VAR}` references,
+    /// so it can be modified and saved back without baking in their values
+    pub async fn open_raw(ctx: &BaseCtx, id: Id) -> Result<Self> {
+        Self::read(ctx, id, false).await
+    }
+
+    async fn read(ctx: &BaseCtx, id: Id, interpolate: bool) -> Result<Self> {
+        let mut config_value = Self::read_merged_value(ctx, id).await?;
+
+        if interpolate {
+            interpolate_value(&mut config_value)
+                .context("failed to parse machine config")
+                .context(id)?;
+        }
+
+        let config: MachineConfig = serde_json::from_value(config_value)
+            .context("failed to parse machine config")
+            .context(id)?;
+
+        Ok(config)
+    }
+
+    /// Reads the config along with the chain of templates it extends, merging
+    /// them so that each config's fields override those of its template
+    async fn read_merged_value(ctx: &BaseCtx, id: Id) -> Result<serde_json::Value> {
+        let mut chain: Vec<(Id, serde_json::Value)> = vec![];
+        let mut next_id = Some(id);
+
+        while let Some(id) = next_id {
+            if chain.iter().any(|(chain_id, _)| *chain_id == id) {
+                bail!("machine template cycle: {}", id);
+            }
+
+            let config_value = Self::read_value(ctx, id).await?;
+
+            next_id = match config_value.get("template") {
+                None | Some(serde_json::Value::Null) => None,
+                Some(template) => Some(
+                    serde_json::from_value(template.clone())
+                        .context("invalid machine template id")
+                        .context(id)?,
+                ),
+            };
+
+            chain.push((id, config_value));
+        }
+
+        let mut merged_value = serde_json::Value::Object(Default::default());
+        for (_, config_value) in chain.into_iter().rev() {
+            merge_config_value(&mut merged_value, config_value);
+        }
+
+        Ok(merged_value)
+    }
+
+    async fn read_value(ctx: &BaseCtx, id: Id) -> Result<serde_json::Value> {
         let config_path = ctx.dirs().get_machine_config_file_path(id)?;
 
         if !config_path.exists() || !config_path.is_file() {
             bail!("machine config file not found: {}", config_path.display());
         }
 
+        let config_format = ConfigFormat::from_path(&config_path);
+
         let config_text = tokio::fs::read_to_string(config_path)
             .await
             .context("failed to read machine config")
             .context(id)?;
 
-        let config: MachineConfig = serde_json::from_str(&config_text)
+        let config_value = config_format
+            .parse(&config_text)
             .context("failed to parse machine config")
             .context(id)?;
 
-        Ok(config)
+        Ok(config_value)
+    }
+
+    /// Returns the id of the machine config with the given name, if any
+    pub async fn find_by_name(ctx: &BaseCtx, name: &str) -> Result<Option<Id>> {
+        for id in ctx.dirs().get_machine_config_ids()? {
+            if Self::open_raw(ctx, id).await?.name == name {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
     }
 
-    pub async fn save(&self, ctx: &Ctx, id: Id, create: bool) -> Result<()> {
+    /// Catches mistakes that would otherwise only show up once an instance
+    /// boots. Checked when the machine is created and again before each boot,
+    /// but not when it's opened, so that one bad config doesn't keep the rest
+    /// from loading. Values still containing `${VAR}` references are skipped
+    /// at creation, they're checked at boot with them resolved.
+    pub fn validate(&self) -> Result<()> {
+        for key in self.user.ssh_authorized_keys.iter() {
+            if key.contains("${") {
+                continue;
+            }
+            validate_ssh_public_key(key).context(format!("invalid ssh authorized key: {}", key))?;
+        }
+
+        validate_image_url(&self.image.url)?;
+
+        if self.kernel.is_none() {
+            if self.initrd.is_some() {
+                bail!("initrd requires kernel");
+            }
+            if self.cmdline.is_some() {
+                bail!("cmdline requires kernel");
+            }
+        }
+
+        if let Some(memory_limit) = self.memory_limit {
+            // Memory can be hotplugged up to max_memory, so that's what the
+            // guest may end up using
+            let max_memory = self.max_memory.unwrap_or(self.memory);
+            if memory_limit < max_memory {
+                bail!(
+                    "memory limit {} is less than guest max memory {}",
+                    memory_limit,
+                    max_memory
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies a change to the config file as written on disk
+    pub async fn update(ctx: &BaseCtx, id: Id, f: impl FnOnce(&mut Self)) -> Result<()> {
+        let mut config = Self::open_raw(ctx, id).await?;
+        f(&mut config);
+        config.save(ctx, id, false).await
+    }
+
+    fn to_user_cloud_init_config(&self, host_keys: &SshHostKeys) -> Result<String> {
+        use serde_yaml::{Mapping, Value};
+
+        let mut root = self.user.to_cloud_init_config(host_keys);
+
+        if !self.ntp_servers.is_empty() {
+            let mut ntp = Mapping::new();
+            ntp.insert(Value::from("enabled"), Value::from(true));
+            ntp.insert(
+                Value::from("servers"),
+                Value::from(self.ntp_servers.clone()),
+            );
+            root.insert(Value::from("ntp"), Value::from(ntp));
+        }
+
+        let config_text =
+            serde_yaml::to_string(&root).context("failed to serialize user cloud-init config")?;
+
+        Ok(config_text)
+    }
+
+    pub async fn save(&self, ctx: &BaseCtx, id: Id, create: bool) -> Result<()> {
         let config_path = ctx.dirs().get_machine_config_file_path(id)?;
         let config_dir = config_path.parent().ok_or(anyhow!("invalid path"))?;
 
@@ -59,9 +346,25 @@ impl MachineConfig {
 
         tokio::fs::create_dir_all(&config_dir).await?;
 
-        let config_text = serde_json::to_string_pretty(&self)
-            .context("failed to serialize machine config")
-            .context(id)?;
+        let config_format = ConfigFormat::from_path(&config_path);
+
+        let config_text = match self.template {
+            None => config_format.serialize(&self),
+            Some(template) => {
+                // Only write the fields that differ from the template so that
+                // later changes to the template are still inherited
+                let template_value = Self::read_merged_value(ctx, template).await?;
+                let mut config_value = serde_json::to_value(self)?;
+                if let Some(fields) = config_value.as_object_mut() {
+                    fields.retain(|key, value| {
+                        !value.is_null() && template_value.get(key) != Some(value)
+                    });
+                }
+                config_format.serialize(&config_value)
+            }
+        }
+        .context("failed to serialize machine config")
+        .context(id)?;
 
         tokio::fs::write(config_path, config_text)
             .await
@@ -72,20 +375,159 @@ impl MachineConfig {
     }
 }
 
+/// Merges `overrides` into `base`, recursing into structs. Everything else,
+/// including enums, replaces the base value wholesale, since fields of one
+/// variant mean nothing to another. Nulls are treated as unset and never
+/// override.
+fn merge_config_value(base: &mut serde_json::Value, overrides: serde_json::Value) {
+    use serde_json::Value;
+
+    match (base, overrides) {
+        (_, Value::Null) => {}
+        (Value::Object(base), Value::Object(overrides))
+            if !is_enum_variant(base) && !is_enum_variant(&overrides) =>
+        {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_config_value(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// Enums are externally tagged, so a variant with data is an object with the
+/// variant's name as its only key. Struct fields are snake_case, which tells
+/// the two apart.
+fn is_enum_variant(object: &serde_json::Map<String, serde_json::Value>) -> bool {
+    let mut keys = object.keys();
+    match (keys.next(), keys.next()) {
+        (Some(key), None) => key.starts_with(|c: char| c.is_ascii_uppercase()),
+        _ => false,
+    }
+}
+
+/// Writes a cloud-init file unless it already has this content, so that edits
+/// to the machine config reach the guest without needlessly invalidating the
+/// seed. Returns whether it was written.
+async fn write_cloud_init_file(path: &Path, text: &str) -> Result<bool> {
+    let current_text = tokio::fs::read_to_string(path).await.ok();
+    if current_text.as_deref() == Some(text) {
+        return Ok(false);
+    }
+
+    tokio::fs::write(path, text)
+        .await
+        .context("failed to write cloud-init config")
+        .context(path.display().to_string())?;
+
+    Ok(true)
+}
+
+/// What the server does when an instance's qemu exits without being stopped
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    /// Restart after a non-zero exit, giving up after `max_retries` restarts
+    /// that didn't stay up for long
+    OnFailure { max_retries: u32 },
+    /// Restart after any exit, including a clean poweroff from the guest
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn should_restart(&self, status: ExitStatus, retries: u32) -> bool {
+        match self {
+            Self::No => false,
+            Self::OnFailure { max_retries } => !status.success() && retries < *max_retries,
+            Self::Always => true,
+        }
+    }
+}
+
+/// How the cloud-init seed reaches the guest
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CloudInitSeed {
+    /// A NoCloud ISO attached as a cdrom, built with cloud-localds or
+    /// genisoimage
+    #[default]
+    Iso,
+    /// A seed url passed in the SMBIOS serial number, which the guest fetches
+    /// from a server vmm runs on the network's host address. Needs no
+    /// external tools or cdrom, but the network config isn't delivered, so
+    /// the guest falls back to DHCP.
+    Smbios,
+}
+
+/// How the guest's screen is exposed, if at all. Displays only listen on
+/// localhost.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub enum Display {
+    /// Serial console only
+    #[default]
+    None,
+    /// VNC on the first free port in `ports`, 5900 to 5999 by default
+    Vnc {
+        #[serde(default)]
+        ports: Option<RangeInclusive<u16>>,
+    },
+    /// SPICE on the first free port in `ports`, 5930 to 5999 by default, with
+    /// a qxl display and the vdagent channel
+    Spice {
+        #[serde(default)]
+        ports: Option<RangeInclusive<u16>>,
+    },
+}
+
+impl Display {
+    pub fn get_scheme(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Vnc { .. } => Some("vnc"),
+            Self::Spice { .. } => Some("spice"),
+        }
+    }
+}
+
+/// A machine config together with its id, as written by `machine export`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineExport {
+    pub id: Id,
+    pub machine: MachineConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MachineNumaNodeConfig {
+    pub cpus: Vec<u8>,
+    pub memory: Byte,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct MachineImageConfig {
     pub url: Url,
     pub hash: Option<String>,
+    pub iops_limit: Option<u64>,
+    pub bps_limit: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct MachineUserConfig {
     pub name: String,
     pub ssh_authorized_keys: Vec<String>,
 }
 
 impl MachineUserConfig {
-    fn to_cloud_init_config(&self) -> Result<String> {
+    fn to_cloud_init_config(&self, host_keys: &SshHostKeys) -> serde_yaml::Mapping {
         use serde_yaml::{Mapping, Sequence, Value};
 
         let mut initial_user = Mapping::new();
@@ -98,26 +540,150 @@ impl MachineUserConfig {
         let mut users = Sequence::new();
         users.push(Value::from(initial_user));
 
+        let mut ssh_keys = Mapping::new();
+        ssh_keys.insert(
+            Value::from("ed25519_private"),
+            Value::from(host_keys.private_key.clone()),
+        );
+        ssh_keys.insert(
+            Value::from("ed25519_public"),
+            Value::from(host_keys.public_key.trim()),
+        );
+
         let mut root = Mapping::new();
         root.insert(Value::from("users"), Value::from(users));
+        root.insert(Value::from("ssh_keys"), Value::from(ssh_keys));
 
-        let config_text =
-            serde_yaml::to_string(&root).context("failed to serialize user cloud-init config")?;
+        root
+    }
+}
 
-        Ok(config_text)
+/// An instance's SSH host key pair, baked into cloud-init so the guest keeps
+/// the same host key across reboots instead of regenerating it
+pub struct SshHostKeys {
+    pub private_key: String,
+    pub public_key: String,
+}
+
+impl SshHostKeys {
+    pub async fn open_or_create(ctx: &BaseCtx, instance_id: Id) -> Result<Self> {
+        let state_dir = ctx.dirs().get_instance_state_dir(instance_id)?;
+        let private_key_path = state_dir.join("ssh_host_ed25519_key");
+        let public_key_path = private_key_path.with_extension("pub");
+
+        if !private_key_path.exists() {
+            tokio::fs::create_dir_all(&state_dir).await?;
+
+            let private_key_path = private_key_path.to_string_lossy();
+
+            #[rustfmt::skip]
+            let args = [
+                "-q", "-t", "ed25519", "-N", "", "-C", "",
+                "-f", &private_key_path,
+            ];
+
+            cmd_success("ssh-keygen", &args)
+                .await
+                .context(instance_id)?;
+        }
+
+        let private_key = tokio::fs::read_to_string(&private_key_path)
+            .await
+            .context("failed to read ssh host private key")
+            .context(instance_id)?;
+
+        let public_key = tokio::fs::read_to_string(&public_key_path)
+            .await
+            .context("failed to read ssh host public key")
+            .context(instance_id)?;
+
+        Ok(Self {
+            private_key,
+            public_key,
+        })
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct MachineNetworkConfig {
     pub id: Id,
     pub interface: MachineInterfaceConfig,
+    pub rx_rate: Option<u64>,
+    pub tx_rate: Option<u64>,
+    /// Host ports forwarded to the guest, only on user-mode networks
+    #[serde(default)]
+    pub host_forwards: Vec<MachineHostForwardConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MachineHostForwardConfig {
+    #[serde(default)]
+    pub protocol: HostForwardProtocol,
+    /// Defaults to all host addresses
+    pub host_addr: Option<Ipv4Addr>,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+impl MachineHostForwardConfig {
+    /// Formats the forward as a qemu `hostfwd` option value
+    pub fn to_qemu_arg(&self) -> String {
+        let host_addr = self
+            .host_addr
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        format!(
+            "{}:{}:{}-:{}",
+            self.protocol.as_str(),
+            host_addr,
+            self.host_port,
+            self.guest_port
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HostForwardProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl HostForwardProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        }
+    }
 }
 
 impl MachineNetworkConfig {
-    fn to_cloud_init_config(&self) -> Result<String> {
+    /// Returns the guest's address, which is only known up front when it's
+    /// statically configured
+    pub fn get_ip_address(&self) -> Option<Ipv4Addr> {
+        match &self.interface {
+            MachineInterfaceConfig::Static(config) => Some(config.ip.addr()),
+            MachineInterfaceConfig::Dhcp(_) => None,
+        }
+    }
+
+    /// Returns the guest's gateway. DHCP always hands out the network's own
+    /// address, while static configs can point elsewhere.
+    pub fn get_gateway(&self, network: &NetworkConfig) -> Ipv4Addr {
         match &self.interface {
-            MachineInterfaceConfig::Static(config) => config.to_cloud_init_config(),
+            MachineInterfaceConfig::Static(config) => config.get_gateway(network),
+            MachineInterfaceConfig::Dhcp(_) => network.ip.addr(),
+        }
+    }
+
+    fn to_cloud_init_config(&self, network: &NetworkConfig, mac: &str) -> Result<String> {
+        match &self.interface {
+            MachineInterfaceConfig::Static(config) => config.to_cloud_init_config(network, mac),
+            MachineInterfaceConfig::Dhcp(config) => config.to_cloud_init_config(mac),
         }
     }
 }
@@ -125,19 +691,67 @@ impl MachineNetworkConfig {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum MachineInterfaceConfig {
     Static(MachineStaticNetworkConfig),
-    // Dhcp(MachineDhcpNetworkConfig),
+    Dhcp(MachineDhcpNetworkConfig),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MachineDhcpNetworkConfig {
+    /// Guest device to configure. Defaults to the nic with the instance's MAC
+    /// address, which doesn't depend on how the guest names its devices.
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+impl MachineDhcpNetworkConfig {
+    fn to_cloud_init_config(&self, mac: &str) -> Result<String> {
+        use serde_yaml::{Mapping, Value};
+
+        let mut interface = Mapping::new();
+        interface.insert(Value::from("dhcp4"), Value::from(true));
+
+        let mut ethernets = Mapping::new();
+        let name = get_netplan_interface_name(&self.interface, mac, &mut interface);
+        ethernets.insert(Value::from(name), Value::from(interface));
+
+        let mut network = Mapping::new();
+        network.insert(Value::from("version"), Value::from(2));
+        network.insert(Value::from("ethernets"), Value::from(ethernets));
+
+        let mut root = Mapping::new();
+        root.insert(Value::from("network"), Value::from(network));
+
+        let config_text = serde_yaml::to_string(&root)
+            .context("failed to serialize network cloud-init config")?;
+
+        Ok(config_text)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct MachineStaticNetworkConfig {
-    pub interface: String,
+    /// Guest device to configure. Defaults to the nic with the instance's MAC
+    /// address, which doesn't depend on how the guest names its devices.
+    #[serde(default)]
+    pub interface: Option<String>,
     pub ip: Ipv4Net,
-    pub gateway: Ipv4Net,
+    /// Defaults to the network's bridge address, which is conventionally the
+    /// first host of its subnet
+    #[serde(default)]
+    pub gateway: Option<Ipv4Net>,
     pub nameservers: Vec<Ipv4Addr>,
 }
 
 impl MachineStaticNetworkConfig {
-    fn to_cloud_init_config(&self) -> Result<String> {
+    fn get_gateway(&self, network: &NetworkConfig) -> Ipv4Addr {
+        match &self.gateway {
+            Some(gateway) => gateway.addr(),
+            None => network.ip.addr(),
+        }
+    }
+
+    fn to_cloud_init_config(&self, network: &NetworkConfig, mac: &str) -> Result<String> {
         use serde_yaml::{Mapping, Value};
 
         let mut interface = Mapping::new();
@@ -148,7 +762,7 @@ impl MachineStaticNetworkConfig {
         );
         interface.insert(
             Value::from("gateway4"),
-            Value::from(self.gateway.to_string()),
+            Value::from(self.get_gateway(network).to_string()),
         );
         interface.insert(
             Value::from("nameservers"),
@@ -161,7 +775,8 @@ impl MachineStaticNetworkConfig {
         );
 
         let mut ethernets = Mapping::new();
-        ethernets.insert(Value::from(self.interface.clone()), Value::from(interface));
+        let name = get_netplan_interface_name(&self.interface, mac, &mut interface);
+        ethernets.insert(Value::from(name), Value::from(interface));
 
         let mut network = Mapping::new();
         network.insert(Value::from("version"), Value::from(2));
@@ -177,6 +792,26 @@ impl MachineStaticNetworkConfig {
     }
 }
 
+/// Returns the netplan id for the guest's nic. Without a device name, the
+/// entry matches the nic by MAC address instead, and its id is only a label.
+fn get_netplan_interface_name(
+    interface: &Option<String>,
+    mac: &str,
+    config: &mut serde_yaml::Mapping,
+) -> String {
+    use serde_yaml::{Mapping, Value};
+
+    if let Some(interface) = interface {
+        return interface.clone();
+    }
+
+    let mut match_config = Mapping::new();
+    match_config.insert(Value::from("macaddress"), Value::from(mac));
+    config.insert(Value::from("match"), Value::from(match_config));
+
+    "primary".into()
+}
+
 #[derive(Debug, Clone)]
 pub struct Machine {
     id: Id,
@@ -184,16 +819,38 @@ pub struct Machine {
 }
 
 impl Machine {
-    pub async fn new(ctx: &Ctx, id: Id, config: MachineConfig) -> Result<Self> {
+    pub async fn new(ctx: &BaseCtx, id: Id, config: MachineConfig) -> Result<Self> {
+        config.validate().context("invalid machine config")?;
         config.save(ctx, id, true).await?;
         Ok(Self { id, config })
     }
 
-    pub async fn open(ctx: &Ctx, id: Id) -> Result<Self> {
+    pub async fn open(ctx: &BaseCtx, id: Id) -> Result<Self> {
         let config = MachineConfig::open(ctx, id).await?;
         Ok(Self { id, config })
     }
 
+    /// Deletes the machine's config, cache and logs. Instances of the machine
+    /// have to be deleted first.
+    pub async fn delete(self, ctx: &BaseCtx) -> Result<()> {
+        let dirs = [
+            ctx.dirs().get_machine_config_dir(self.id)?,
+            ctx.dirs().get_machine_cache_dir(self.id)?,
+            ctx.dirs().get_machine_log_dir(self.id)?,
+        ];
+
+        for dir in dirs {
+            if dir.exists() {
+                tokio::fs::remove_dir_all(&dir)
+                    .await
+                    .context("failed to delete machine dir")
+                    .context(dir.display().to_string())?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn id(&self) -> &Id {
         &self.id
     }
@@ -202,7 +859,15 @@ impl Machine {
         &self.config
     }
 
-    pub async fn get_root_image(&mut self, ctx: &Ctx) -> Result<PathBuf> {
+    /// Forgets the install ISO, on disk too, so later boots go straight to
+    /// the disk
+    pub async fn clear_install_iso(&mut self, ctx: &BaseCtx) -> Result<()> {
+        MachineConfig::update(ctx, self.id, |config| config.install_iso = None).await?;
+        self.config.install_iso = None;
+        Ok(())
+    }
+
+    pub async fn get_root_image(&mut self, ctx: &Ctx) -> VmmResult<PathBuf> {
         let url = self.config.image.url.clone();
         let expected_hash = self.config.image.hash.clone();
 
@@ -211,120 +876,138 @@ impl Machine {
             .get_image_hash(ctx, url.clone(), expected_hash.clone())
             .await?;
 
-        match result {
-            GetImageHashResult::ImageCached(hash) => {
-                if let Some(expected_hash) = &expected_hash {
-                    if hash != *expected_hash {
-                        return Err(anyhow!("image hash mismatch"))
-                            .context(url.clone())
-                            .context(expected_hash.clone())
-                            .context(hash);
-                    }
-                }
+        let hash = result.into_hash(&url, expected_hash.as_ref())?;
 
-                self.config.image.hash = Some(hash.clone());
+        self.config.image.hash = Some(hash.clone());
 
-                self.write_config(ctx).await?;
+        self.write_config(ctx).await?;
 
-                ctx.dirs().get_image_cache_path(&hash)
-            }
-            GetImageHashResult::DownloadNoContentLength => {
-                Err(anyhow!("image download no content length")).context(url.clone())
-            }
-            GetImageHashResult::DownloadFailed(status_code) => {
-                Err(anyhow!("image download failed: {}", status_code)).context(url.clone())
-            }
-            GetImageHashResult::DownloadFailedToReadChunk => {
-                Err(anyhow!("image download failed to read chunk")).context(url.clone())
-            }
-            GetImageHashResult::DownloadCancelled => {
-                Err(anyhow!("image download cancelled")).context(url.clone())
-            }
-            GetImageHashResult::UnknownError => {
-                Err(anyhow!("image download unknown error")).context(url.clone())
-            }
-        }
+        Ok(ctx.dirs().get_image_cache_path(&hash)?)
     }
 
-    async fn write_config(&self, ctx: &Ctx) -> Result<()> {
-        self.config.save(ctx, self.id, false).await?;
+    async fn write_config(&self, ctx: &BaseCtx) -> Result<()> {
+        // Only the resolved image hash is written back, since the loaded
+        // config may contain values interpolated from the environment
+        let hash = self.config.image.hash.clone();
+        MachineConfig::update(ctx, self.id, |config| config.image.hash = hash).await?;
         Ok(())
     }
 
-    async fn write_cloud_init_config(&self, ctx: &Ctx) -> Result<()> {
-        self.write_network_cloud_init_config(ctx).await?;
-        self.write_user_cloud_init_config(ctx).await?;
-        Ok(())
+    /// Writes the cloud-init files, rewriting those that are out of date.
+    /// Returns whether any was written, in which case a cached seed is out of
+    /// date too.
+    pub async fn write_cloud_init_config(&self, ctx: &BaseCtx, instance_id: Id) -> Result<bool> {
+        let mut changed = false;
+        if let Some(network) = &self.config.network {
+            changed |= self
+                .write_network_cloud_init_config(ctx, instance_id, network)
+                .await?;
+        } else {
+            // the network may have been removed from the machine since the
+            // seed was built
+            let config_path = ctx.dirs().get_instance_cloud_init_dir(instance_id)?;
+            let network_config_path = config_path.join("network-config.yaml");
+            if network_config_path.exists() {
+                tokio::fs::remove_file(&network_config_path)
+                    .await
+                    .context("failed to remove network cloud-init config")
+                    .context(network_config_path.display().to_string())?;
+                changed = true;
+            }
+        }
+        changed |= self.write_user_cloud_init_config(ctx, instance_id).await?;
+        changed |= self
+            .write_meta_data_cloud_init_config(ctx, instance_id)
+            .await?;
+        Ok(changed)
     }
 
-    async fn write_network_cloud_init_config(&self, ctx: &Ctx) -> Result<()> {
-        let config_path = ctx.dirs().get_machine_config_dir(self.id)?;
+    async fn write_meta_data_cloud_init_config(
+        &self,
+        ctx: &BaseCtx,
+        instance_id: Id,
+    ) -> Result<bool> {
+        let config_path = ctx.dirs().get_instance_cloud_init_dir(instance_id)?;
         tokio::fs::create_dir_all(&config_path).await?;
 
-        let network_config_path = config_path.join("network-config.yaml");
-        if network_config_path.exists() {
-            return Ok(());
-        }
-
-        let network_config_text = self.config.network.to_cloud_init_config()?;
-
-        let mut network_config_file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(network_config_path)
-            .await
-            .context("failed to open network cloud-init file")?;
+        use serde_yaml::{Mapping, Value};
 
-        network_config_file
-            .write_all("#cloud-config\n".as_bytes())
-            .await
-            .context("failed to write network cloud-init config")?;
+        // cloud-init only re-runs its per-instance modules when instance-id
+        // changes, so it must be unique to each instance
+        let mut meta_data = Mapping::new();
+        meta_data.insert(
+            Value::from("instance-id"),
+            Value::from(instance_id.to_string()),
+        );
+        meta_data.insert(
+            Value::from("local-hostname"),
+            Value::from(get_hostname(&self.config.name)),
+        );
 
-        network_config_file
-            .write_all(network_config_text.as_bytes())
-            .await
-            .context("failed to write network cloud-init config")?;
+        let meta_data_text = serde_yaml::to_string(&meta_data)
+            .context("failed to serialize meta-data cloud-init config")?;
 
-        Ok(())
+        write_cloud_init_file(&config_path.join("meta-data.yaml"), &meta_data_text).await
     }
 
-    async fn write_user_cloud_init_config(&self, ctx: &Ctx) -> Result<()> {
-        let config_path = ctx.dirs().get_machine_config_dir(self.id)?;
+    async fn write_network_cloud_init_config(
+        &self,
+        ctx: &BaseCtx,
+        instance_id: Id,
+        machine_network: &MachineNetworkConfig,
+    ) -> Result<bool> {
+        let config_path = ctx.dirs().get_instance_cloud_init_dir(instance_id)?;
         tokio::fs::create_dir_all(&config_path).await?;
 
-        let user_config_path = config_path.join("user-config.yaml");
-        if user_config_path.exists() {
-            return Ok(());
-        }
+        let network = NetworkConfig::open(ctx, machine_network.id).await?;
+        let mac = get_mac_address(instance_id);
+        let network_config_text = machine_network.to_cloud_init_config(&network, &mac)?;
 
-        let user_config_text = self.config.user.to_cloud_init_config()?;
+        let network_config_text = format!("#cloud-config\n{}", network_config_text);
 
-        let mut user_config_file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(user_config_path)
-            .await
-            .context("failed to open user cloud-init file")?;
+        write_cloud_init_file(
+            &config_path.join("network-config.yaml"),
+            &network_config_text,
+        )
+        .await
+    }
 
-        user_config_file
-            .write_all("#cloud-config\n".as_bytes())
-            .await
-            .context("failed to write user cloud-init config")?;
+    async fn write_user_cloud_init_config(&self, ctx: &BaseCtx, instance_id: Id) -> Result<bool> {
+        let config_path = ctx.dirs().get_instance_cloud_init_dir(instance_id)?;
+        tokio::fs::create_dir_all(&config_path).await?;
 
-        user_config_file
-            .write_all(user_config_text.as_bytes())
-            .await
-            .context("failed to write user cloud-init config")?;
+        let user_config_text = match &self.config.user_data_file {
+            Some(user_data_file) => {
+                let user_data = tokio::fs::read_to_string(user_data_file)
+                    .await
+                    .context("failed to read user-data file")
+                    .context(user_data_file.display().to_string())?;
+
+                if !user_data.starts_with("#cloud-config") && !user_data.starts_with("#!") {
+                    bail!(
+                        "user-data file must start with #cloud-config or #!: {}",
+                        user_data_file.display()
+                    );
+                }
 
-        Ok(())
+                user_data
+            }
+            None => {
+                let host_keys = SshHostKeys::open_or_create(ctx, instance_id).await?;
+                let user_config_text = self.config.to_user_cloud_init_config(&host_keys)?;
+                format!("#cloud-config\n{}", user_config_text)
+            }
+        };
+
+        write_cloud_init_file(&config_path.join("user-config.yaml"), &user_config_text).await
     }
 
-    pub async fn get_cloud_init_iso(&self, ctx: &Ctx) -> Result<PathBuf> {
-        let config_path = ctx.dirs().get_machine_config_dir(self.id)?;
+    pub async fn get_cloud_init_iso(&self, ctx: &BaseCtx, instance_id: Id) -> Result<PathBuf> {
+        let config_path = ctx.dirs().get_instance_cloud_init_dir(instance_id)?;
         let cloud_init_iso_path = config_path.join("cloud-init.iso");
-        if cloud_init_iso_path.exists() {
+
+        let changed = self.write_cloud_init_config(ctx, instance_id).await?;
+        if cloud_init_iso_path.exists() && !changed {
             println!(
                 "using cached cloud-init.iso: {}",
                 cloud_init_iso_path.display()
@@ -332,22 +1015,16 @@ impl Machine {
             return Ok(cloud_init_iso_path);
         }
 
-        self.write_cloud_init_config(ctx).await?;
-
-        let args = vec![
-            "-v",
-            "cloud-init.iso",
-            "--network=network-config.yaml",
-            "user-config.yaml",
-        ];
+        let seed_tool = SeedTool::find()?;
+        let (program, args) = seed_tool.get_command(self.config.network.is_some());
 
-        let mut child = Command::new("cloud-localds")
+        let mut child = Command::new(program)
             .args(args)
             .stderr(Stdio::piped())
             .stdout(Stdio::piped())
             .current_dir(&config_path)
             .spawn()
-            .context("failed to spawn cloud-localds")
+            .context(format!("failed to spawn {}", seed_tool.name()))
             .context(self.id)?;
 
         let mut tasks = Vec::new();
@@ -386,10 +1063,17 @@ impl Machine {
             tasks.push(stderr_task);
         }
 
-        let status = child
-            .wait()
-            .await
-            .context("failed to wait for cloud-localds")
+        let Ok(status) = tokio::time::timeout(DEFAULT_CMD_TIMEOUT, child.wait()).await else {
+            let _ = child.kill().await;
+            return Err(VmmError::CommandTimeout {
+                cmd: seed_tool.name().to_string(),
+                timeout: DEFAULT_CMD_TIMEOUT,
+            })
+            .context(self.id);
+        };
+
+        let status = status
+            .context(format!("failed to wait for {}", seed_tool.name()))
             .context(self.id)?;
 
         for task in tasks.drain(..) {
@@ -397,9 +1081,363 @@ impl Machine {
         }
 
         if !status.success() {
-            anyhow::bail!("cloud-localds exited with {}", status);
+            anyhow::bail!("{} exited with {}", seed_tool.name(), status);
         }
 
         Ok(cloud_init_iso_path)
     }
 }
+
+/// A tool able to build a NoCloud seed ISO from the generated cloud-init files
+enum SeedTool {
+    CloudLocalds(PathBuf, Vec<String>),
+    Genisoimage(PathBuf),
+}
+
+impl SeedTool {
+    fn find() -> Result<Self> {
+        // Extra arguments are passed before the file arguments, e.g.
+        // `--disk-format=raw`
+        let cloud_localds_args = std::env::var("VMM_CLOUD_LOCALDS_ARGS")
+            .map(|args| args.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+
+        if let Some(path) = std::env::var_os("VMM_CLOUD_LOCALDS") {
+            let path = PathBuf::from(path);
+            if !path.is_file() {
+                bail!("VMM_CLOUD_LOCALDS does not exist: {}", path.display());
+            }
+            return Ok(Self::CloudLocalds(path, cloud_localds_args));
+        }
+
+        if let Some(path) = find_program("cloud-localds") {
+            return Ok(Self::CloudLocalds(path, cloud_localds_args));
+        }
+
+        for name in ["genisoimage", "mkisofs"] {
+            if let Some(path) = find_program(name) {
+                return Ok(Self::Genisoimage(path));
+            }
+        }
+
+        bail!(
+            "no tool found to build the cloud-init seed ISO; install cloud-localds \
+            (cloud-image-utils) or genisoimage, or set VMM_CLOUD_LOCALDS to its path"
+        )
+    }
+
+    fn name(&self) -> String {
+        let path = match self {
+            Self::CloudLocalds(path, _) => path,
+            Self::Genisoimage(path) => path,
+        };
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Machines without a network have no network config to include
+    fn get_command(&self, has_network: bool) -> (&Path, Vec<&str>) {
+        match self {
+            Self::CloudLocalds(path, extra_args) => {
+                let mut args: Vec<&str> = extra_args.iter().map(String::as_str).collect();
+                args.extend(["-v", "cloud-init.iso"]);
+                if has_network {
+                    args.push("--network=network-config.yaml");
+                }
+                args.extend(["user-config.yaml", "meta-data.yaml"]);
+                (path, args)
+            }
+            Self::Genisoimage(path) => {
+                // NoCloud looks for these exact file names on a volume labeled
+                // "cidata"
+                #[rustfmt::skip]
+                let mut args = vec![
+                    "-output", "cloud-init.iso",
+                    "-volid", "cidata",
+                    "-joliet",
+                    "-rock",
+                    "-graft-points",
+                    "user-data=user-config.yaml",
+                    "meta-data=meta-data.yaml",
+                ];
+                if has_network {
+                    args.push("network-config=network-config.yaml");
+                }
+                (path, args)
+            }
+        }
+    }
+}
+
+const SSH_KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ssh-dss",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Checks that a key looks like an OpenSSH authorized key: optional options,
+/// a known type, then a base64 blob that itself starts with that type, then
+/// an optional comment
+fn validate_ssh_public_key(key: &str) -> Result<()> {
+    let key = key.trim_start();
+
+    // Options come first when there are any, and never start with a key type
+    let key = match key.split_whitespace().next() {
+        Some(key_type) if !SSH_KEY_TYPES.contains(&key_type) => skip_ssh_key_options(key),
+        _ => key,
+    };
+
+    let mut parts = key.split_whitespace();
+
+    let Some(key_type) = parts.next() else {
+        bail!("key is empty");
+    };
+    if !SSH_KEY_TYPES.contains(&key_type) {
+        bail!("unknown key type: {}", key_type);
+    }
+
+    let Some(blob) = parts.next() else {
+        bail!("key data is missing");
+    };
+    let blob = BASE64_STANDARD
+        .decode(blob)
+        .context("key data is not valid base64")?;
+
+    // The blob is a sequence of length-prefixed fields, the first of which
+    // repeats the key type
+    let embedded_type = blob
+        .get(..4)
+        .and_then(|len| len.try_into().ok())
+        .map(|len| u32::from_be_bytes(len) as usize)
+        .and_then(|len| blob.get(4..4 + len));
+    if embedded_type != Some(key_type.as_bytes()) {
+        bail!("key data does not match key type {}", key_type);
+    }
+
+    Ok(())
+}
+
+/// Returns what follows the options of an authorized key, e.g. after
+/// `from="10.0.0.0/8",command="echo hi"`. Options are separated by commas
+/// and end at the first whitespace outside of double quotes.
+fn skip_ssh_key_options(key: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in key.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => return key[i..].trim_start(),
+            _ => {}
+        }
+    }
+
+    ""
+}
+
+/// Turns a machine name into a valid hostname label
+fn get_hostname(name: &str) -> String {
+    let hostname: String = name
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    hostname.trim_matches('-').to_string()
+}
+
+/// Smallest config that passes validation, for tests to build on
+#[cfg(test)]
+pub fn test_machine_config() -> MachineConfig {
+    serde_json::from_value(serde_json::json!({
+        "name": "test",
+        "cpus": 1,
+        "memory": "1GiB",
+        "image": { "url": "https://example.com/image.qcow2" },
+        "share_dirs": [],
+        "user": { "name": "test", "ssh_authorized_keys": [] },
+    }))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmm_dirs::VmmDirs;
+
+    fn test_host_keys() -> SshHostKeys {
+        SshHostKeys {
+            private_key: "private".into(),
+            public_key: "ssh-ed25519 AAAA host\n".into(),
+        }
+    }
+
+    #[test]
+    fn merge_config_value_replaces_enum_variants() {
+        let mut merged = serde_json::json!({
+            "network": {
+                "interface": { "Dhcp": { "interface": "eth0" } },
+                "rx_rate": 1000,
+            },
+            "restart": { "OnFailure": { "max_retries": 3 } },
+        });
+        let overrides = serde_json::json!({
+            "network": {
+                "interface": {
+                    "Static": { "ip": "10.0.0.5/24", "nameservers": [] },
+                },
+            },
+            "restart": "Always",
+        });
+
+        merge_config_value(&mut merged, overrides);
+
+        let expected = serde_json::json!({
+            "network": {
+                "interface": {
+                    "Static": { "ip": "10.0.0.5/24", "nameservers": [] },
+                },
+                "rx_rate": 1000,
+            },
+            "restart": "Always",
+        });
+        assert_eq!(merged, expected);
+
+        let interface: MachineInterfaceConfig =
+            serde_json::from_value(merged["network"]["interface"].clone()).unwrap();
+        assert!(matches!(interface, MachineInterfaceConfig::Static(_)));
+    }
+
+    #[test]
+    fn user_cloud_init_config_has_ntp_servers() {
+        let mut config = test_machine_config();
+        config.ntp_servers = vec!["0.pool.ntp.org".into(), "10.0.0.1".into()];
+
+        let text = config.to_user_cloud_init_config(&test_host_keys()).unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&text).unwrap();
+
+        let expected: serde_yaml::Value = serde_yaml::from_str(
+            "
+            enabled: true
+            servers: [0.pool.ntp.org, 10.0.0.1]
+            ",
+        )
+        .unwrap();
+        assert_eq!(value["ntp"], expected);
+    }
+
+    #[test]
+    fn user_cloud_init_config_without_ntp_servers() {
+        let config = test_machine_config();
+
+        let text = config.to_user_cloud_init_config(&test_host_keys()).unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&text).unwrap();
+
+        assert!(value.get("ntp").is_none());
+    }
+
+    #[tokio::test]
+    async fn user_cloud_init_config_is_rewritten_when_changed() {
+        let root = tempfile::tempdir().unwrap();
+        let ctx = BaseCtx::from_dirs(VmmDirs::from_root(root.path()));
+
+        let user_data_file = root.path().join("user-data");
+        tokio::fs::write(&user_data_file, "#cloud-config\nhostname: a\n")
+            .await
+            .unwrap();
+
+        let mut config = test_machine_config();
+        config.user_data_file = Some(user_data_file.clone());
+        let machine = Machine::new(&ctx, Id::new().unwrap(), config)
+            .await
+            .unwrap();
+        let instance_id = Id::new().unwrap();
+
+        assert!(
+            machine
+                .write_cloud_init_config(&ctx, instance_id)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !machine
+                .write_cloud_init_config(&ctx, instance_id)
+                .await
+                .unwrap()
+        );
+
+        tokio::fs::write(&user_data_file, "#cloud-config\nhostname: b\n")
+            .await
+            .unwrap();
+        assert!(
+            machine
+                .write_cloud_init_config(&ctx, instance_id)
+                .await
+                .unwrap()
+        );
+
+        let user_config_path = ctx
+            .dirs()
+            .get_instance_cloud_init_dir(instance_id)
+            .unwrap()
+            .join("user-config.yaml");
+        let text = tokio::fs::read_to_string(user_config_path).await.unwrap();
+        assert_eq!(text, "#cloud-config\nhostname: b\n");
+    }
+
+    #[test]
+    fn dhcp_network_config_matches_nic_by_mac() {
+        let config = MachineDhcpNetworkConfig { interface: None };
+
+        let text = config.to_cloud_init_config("52:54:00:12:34:56").unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&text).unwrap();
+
+        let expected: serde_yaml::Value = serde_yaml::from_str(
+            "
+            dhcp4: true
+            match:
+              macaddress: '52:54:00:12:34:56'
+            ",
+        )
+        .unwrap();
+        assert_eq!(value["network"]["ethernets"]["primary"], expected);
+    }
+
+    fn test_ssh_key() -> String {
+        let mut blob = vec![0, 0, 0, 11];
+        blob.extend(b"ssh-ed25519");
+        blob.extend([0, 0, 0, 32]);
+        blob.extend([7; 32]);
+        format!("ssh-ed25519 {} user@host", BASE64_STANDARD.encode(blob))
+    }
+
+    #[test]
+    fn ssh_key_with_options_is_accepted() {
+        let key = test_ssh_key();
+        validate_ssh_public_key(&key).unwrap();
+        validate_ssh_public_key(&format!("no-pty {}", key)).unwrap();
+        validate_ssh_public_key(&format!(
+            r#"from="10.0.0.0/8",command="echo \"a b\"" {}"#,
+            key
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn ssh_key_with_bad_data_is_rejected() {
+        assert!(validate_ssh_public_key("ssh-ed25519 AAAA").is_err());
+        assert!(validate_ssh_public_key("ssh-rsa AAAAC3NzaC1lZDI1NTE5").is_err());
+        assert!(validate_ssh_public_key("no-pty").is_err());
+        assert!(
+            validate_ssh_public_key(&test_ssh_key().replace("ssh-ed25519", "ssh-rsa")).is_err()
+        );
+    }
+}
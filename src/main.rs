@@ -1,19 +1,39 @@
+use std::time::Duration;
+
 use anyhow::Result;
 
-use crate::cli::Cli;
+use crate::{cli::Cli, error::VmmError};
 
 mod args;
+mod cgroup;
 mod cli;
+mod cmd;
+mod config_format;
+mod console;
+mod control;
 mod ctx;
+mod doctor;
+mod error;
+mod gc;
+mod http_api;
 mod id;
 mod image_cache;
 mod instance;
+mod instance_events;
 mod logger;
 mod machine;
+mod metrics;
 mod network;
+mod probe;
+mod proc_stats;
 mod progress_router;
+mod progress_ui;
+mod qemu_img;
+mod qmp;
+mod seed_server;
 mod server;
 mod share_dir;
+mod snapshot;
 mod task_actor;
 mod task_group;
 mod text_table;
@@ -21,98 +41,45 @@ mod vmm_dirs;
 
 fn main() -> Result<()> {
     let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(async {
-        let cli = Cli::new();
-        if let Err(e) = cli.run().await {
-            eprintln!("{}", e);
-        }
-    });
-    Ok(())
-
-    /*
-    let ctx = Ctx::new();
-    let mut server = Server::new();
-    let mut task_group = TaskGroup::new(ctx.cancel_token().clone());
-
-    let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(async {
-        let ctx2 = ctx.clone();
-        ctrlc::set_handler(move || {
-            println!("CTRL-C");
-            ctx2.cancel_token().cancel();
-        })
-        .unwrap();
-
-        let ctx = ctx.with_progress_router(create_progress_router(&mut task_group));
-
-        let image_cache = create_image_cache(ctx.clone(), &mut task_group);
-        let ctx = ctx.with_image_manager(image_cache);
-
-        let network = Network::read(&ctx, Id::from_str("ACKonMM9amvHoRz5f8ucc8").unwrap())
-            .await
-            .unwrap();
-
-        let machine = Machine::read(&ctx, Id::from_str("kqko0nWNyvXNYG7DrIVhV9").unwrap())
-            .await
-            .unwrap();
-
-        let instance = Instance::read(&ctx, Id::from_str("Cu4HKxEJouYimccDrcAJE8").unwrap())
-            .await
-            .unwrap();
-
-        println!("network: {:?}", network);
-        println!("machine: {:?}", machine);
-        println!("instance: {:?}", instance.id());
+    let exit_code = rt.block_on(async {
+        let cli = match Cli::new() {
+            Ok(cli) => cli,
+            Err(e) => {
+                eprintln!("{:#}", e);
+                return 1;
+            }
+        };
 
-        let ctx2 = ctx.clone();
+        let cancel_token = cli.cancel_token().clone();
         tokio::spawn(async move {
-            let mut progress_bars = HashMap::new();
-            let mut progress_receiver = ctx2.progress_router().subscribe();
-
-            while let Ok(progress) = progress_receiver.recv().await {
-                match progress {
-                    ProgressMessage::Start(id, Some(total)) => {
-                        let pb = ProgressBar::new(total);
-
-                        pb.set_style(
-                            ProgressStyle::with_template(
-                                "[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({eta})",
-                            )
-                            .unwrap()
-                            .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
-                                write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
-                            })
-                            .progress_chars("#>-"),
-                        );
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
 
-                        pb.set_position(0);
+            // Give running work the chance to clean up, but exit anyway on a
+            // second Ctrl-C or if it doesn't finish in time
+            cancel_token.cancel();
 
-                        progress_bars.insert(id, (pb, 0u64));
-                    }
-                    ProgressMessage::Start(id, None) => {
-                        todo!();
-                    }
-                    ProgressMessage::Update(id, count) => {
-                        if let Some((pb, sofar)) = progress_bars.get_mut(&id) {
-                            *sofar += count;
-                            pb.set_position(*sofar);
-                        }
-                    }
-                    ProgressMessage::Finish(id) => {
-                        if let Some((pb, _)) = progress_bars.remove(&id) {
-                            pb.finish_with_message("downloaded");
-                        }
-                    }
-                }
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = tokio::time::sleep(Duration::from_secs(5)) => {}
             }
+
+            std::process::exit(130);
         });
 
-        server.read_all(&ctx).await.unwrap();
-        server.start_instance(&ctx, instance.id()).await.unwrap();
+        match cli.run().await {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.downcast_ref::<VmmError>().map_or(1, VmmError::exit_code)
+            }
+        }
+    });
 
-        println!("SHOULDNT THIS BE WAITING????");
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
 
-        task_group.wait().await;
-    });
-    */
+    Ok(())
 }
@@ -0,0 +1,299 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result};
+use axum::{
+    Router,
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde_json::Value;
+use tokio::{net::TcpListener, sync::Mutex};
+
+use crate::{id::Id, instance::Instance, qmp::QmpClient, server::Server};
+
+pub async fn serve_metrics(server: Arc<Mutex<Server>>, addr: SocketAddr) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(get_metrics))
+        .with_state(server);
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .context("failed to bind metrics address")
+        .context(addr)?;
+
+    axum::serve(listener, app)
+        .await
+        .context("metrics server failed")?;
+
+    Ok(())
+}
+
+async fn get_metrics(State(server): State<Arc<Mutex<Server>>>) -> Response {
+    // Snapshot what we need so the server isn't locked while talking to qemu
+    let mut samples = {
+        let server = server.lock().await;
+        server
+            .instances()
+            .map(InstanceSample::new)
+            .collect::<Vec<_>>()
+    };
+
+    // One broken instance only loses its own qemu metrics, which
+    // `vmm_instance_query_success` tells apart from an idle instance
+    for sample in samples.iter_mut() {
+        if let Err(e) = sample.query().await {
+            eprintln!("error: {:?}", e);
+            sample.query_failed = true;
+            sample.status = None;
+            sample.balloon_actual = None;
+            sample.blocks.clear();
+        }
+    }
+
+    let body = render_metrics(&samples);
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+struct InstanceSample {
+    id: Id,
+    machine: String,
+    cpus: u8,
+    memory: u64,
+    up: bool,
+    query_failed: bool,
+    qmp_socket: PathBuf,
+    status: Option<String>,
+    balloon_actual: Option<u64>,
+    blocks: Vec<BlockSample>,
+}
+
+/// Name, help text and how to read the value of a per-device metric
+type BlockMetric = (&'static str, &'static str, fn(&BlockSample) -> u64);
+
+struct BlockSample {
+    device: String,
+    rd_bytes: u64,
+    wr_bytes: u64,
+    rd_operations: u64,
+    wr_operations: u64,
+}
+
+impl InstanceSample {
+    fn new(instance: &Instance) -> Self {
+        let config = instance.machine().config();
+        Self {
+            id: *instance.id(),
+            machine: config.name.clone(),
+            cpus: config.cpus,
+            memory: config.memory.as_u64(),
+            up: instance.is_running(),
+            query_failed: false,
+            qmp_socket: instance.get_qmp_socket_path(),
+            status: None,
+            balloon_actual: None,
+            blocks: vec![],
+        }
+    }
+
+    async fn query(&mut self) -> Result<()> {
+        if !self.up {
+            return Ok(());
+        }
+
+        let mut qmp = QmpClient::connect(&self.qmp_socket)
+            .await
+            .context(self.id)?;
+
+        let status = qmp.execute("query-status", None).await?;
+        self.status = status
+            .get("status")
+            .and_then(Value::as_str)
+            .map(String::from);
+
+        let balloon = qmp.execute("query-balloon", None).await?;
+        self.balloon_actual = balloon.get("actual").and_then(Value::as_u64);
+
+        let blockstats = qmp.execute("query-blockstats", None).await?;
+        for entry in blockstats.as_array().into_iter().flatten() {
+            let device = entry
+                .get("device")
+                .and_then(Value::as_str)
+                .filter(|device| !device.is_empty())
+                .or(entry.get("node-name").and_then(Value::as_str));
+
+            let (Some(device), Some(stats)) = (device, entry.get("stats")) else {
+                continue;
+            };
+
+            let stat = |name: &str| stats.get(name).and_then(Value::as_u64).unwrap_or(0);
+
+            self.blocks.push(BlockSample {
+                device: device.to_string(),
+                rd_bytes: stat("rd_bytes"),
+                wr_bytes: stat("wr_bytes"),
+                rd_operations: stat("rd_operations"),
+                wr_operations: stat("wr_operations"),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn labels(&self) -> String {
+        format!(
+            "instance=\"{}\",machine=\"{}\"",
+            self.id,
+            escape_label_value(&self.machine)
+        )
+    }
+}
+
+fn render_metrics(samples: &[InstanceSample]) -> String {
+    let mut out = String::new();
+
+    metric_header(
+        &mut out,
+        "vmm_instance_up",
+        "gauge",
+        "Whether the instance's qemu process is running.",
+    );
+    for sample in samples {
+        metric_line(&mut out, "vmm_instance_up", &sample.labels(), sample.up);
+    }
+
+    metric_header(
+        &mut out,
+        "vmm_instance_query_success",
+        "gauge",
+        "Whether qemu answered the queries for the instance's other metrics.",
+    );
+    for sample in samples.iter().filter(|sample| sample.up) {
+        metric_line(
+            &mut out,
+            "vmm_instance_query_success",
+            &sample.labels(),
+            !sample.query_failed,
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "vmm_instance_status",
+        "gauge",
+        "Current qemu run state of the instance as reported by QMP.",
+    );
+    for sample in samples {
+        let status = sample.status.as_deref().unwrap_or("unknown");
+        let labels = format!(
+            "{},status=\"{}\"",
+            sample.labels(),
+            escape_label_value(status)
+        );
+        metric_line(&mut out, "vmm_instance_status", &labels, 1u64);
+    }
+
+    metric_header(
+        &mut out,
+        "vmm_instance_vcpus",
+        "gauge",
+        "Number of vCPUs configured for the instance.",
+    );
+    for sample in samples {
+        metric_line(
+            &mut out,
+            "vmm_instance_vcpus",
+            &sample.labels(),
+            sample.cpus,
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "vmm_instance_memory_configured_bytes",
+        "gauge",
+        "Guest memory configured for the instance.",
+    );
+    for sample in samples {
+        metric_line(
+            &mut out,
+            "vmm_instance_memory_configured_bytes",
+            &sample.labels(),
+            sample.memory,
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "vmm_instance_memory_actual_bytes",
+        "gauge",
+        "Guest memory currently available as reported by the balloon device.",
+    );
+    for sample in samples {
+        if let Some(actual) = sample.balloon_actual {
+            metric_line(
+                &mut out,
+                "vmm_instance_memory_actual_bytes",
+                &sample.labels(),
+                actual,
+            );
+        }
+    }
+
+    let block_metrics: [BlockMetric; 4] = [
+        (
+            "vmm_instance_block_read_bytes_total",
+            "Bytes read from the block device.",
+            |block| block.rd_bytes,
+        ),
+        (
+            "vmm_instance_block_written_bytes_total",
+            "Bytes written to the block device.",
+            |block| block.wr_bytes,
+        ),
+        (
+            "vmm_instance_block_read_operations_total",
+            "Read operations completed by the block device.",
+            |block| block.rd_operations,
+        ),
+        (
+            "vmm_instance_block_write_operations_total",
+            "Write operations completed by the block device.",
+            |block| block.wr_operations,
+        ),
+    ];
+
+    for (name, help, value) in block_metrics {
+        metric_header(&mut out, name, "counter", help);
+        for sample in samples {
+            for block in sample.blocks.iter() {
+                let labels = format!(
+                    "{},device=\"{}\"",
+                    sample.labels(),
+                    escape_label_value(&block.device)
+                );
+                metric_line(&mut out, name, &labels, value(block));
+            }
+        }
+    }
+
+    out
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!("# HELP {} {}\n", name, help));
+    out.push_str(&format!("# TYPE {} {}\n", name, kind));
+}
+
+fn metric_line(out: &mut String, name: &str, labels: &str, value: impl Into<u64>) {
+    out.push_str(&format!("{}{{{}}} {}\n", name, labels, value.into()));
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
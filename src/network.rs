@@ -1,39 +1,178 @@
-use std::{process::ExitStatus, time::Duration};
+use std::{io::ErrorKind, net::Ipv4Addr, path::PathBuf, process::Stdio, time::Duration};
 
 use anyhow::{Context, Result, anyhow, bail};
 use ipnet::Ipv4Net;
 use serde::{Deserialize, Serialize};
 use tokio::process::Command;
 
-use crate::{ctx::Ctx, id::Id, instance::Instance};
+use crate::{
+    cmd::{cmd, cmd_success},
+    config_format::{ConfigFormat, interpolate_value},
+    ctx::BaseCtx,
+    id::Id,
+    instance::Instance,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct NetworkConfig {
     pub name: String,
     pub ip: Ipv4Net,
+    /// Runs a DHCP server on the bridge, handing out the upper half of the
+    /// subnet so that the lower half stays free for static addresses
+    #[serde(default)]
+    pub dhcp: bool,
+    #[serde(default)]
+    pub mode: NetworkMode,
+    /// Host interfaces enslaved to the bridge so guests can reach the
+    /// physical LAN. Addresses on them stop working while they're enslaved,
+    /// so they're typically dedicated to this or already unaddressed.
+    #[serde(default)]
+    pub uplinks: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// Guests are attached to a host bridge through taps, which needs root
+    #[default]
+    Bridge,
+    /// Guests get qemu's built-in NAT and DHCP, with `ip` as its subnet and
+    /// host address. Needs no privileges, but guests are only reachable
+    /// through their machine's host forwards.
+    User,
 }
 
 impl NetworkConfig {
-    pub async fn open(ctx: &Ctx, id: Id) -> Result<Self> {
+    pub async fn open(ctx: &BaseCtx, id: Id) -> Result<Self> {
+        Self::read(ctx, id, true).await
+    }
+
+    /// Loads the config as written, without resolving `${VAR}` references,
+    /// so it can be modified and saved back without baking in their values
+    pub async fn open_raw(ctx: &BaseCtx, id: Id) -> Result<Self> {
+        Self::read(ctx, id, false).await
+    }
+
+    async fn read(ctx: &BaseCtx, id: Id, interpolate: bool) -> Result<Self> {
         let config_path = ctx.dirs().get_network_config_file_path(id)?;
 
         if !config_path.exists() || !config_path.is_file() {
             bail!("network config file not found: {}", config_path.display());
         }
 
+        let config_format = ConfigFormat::from_path(&config_path);
+
         let config_text = tokio::fs::read_to_string(config_path)
             .await
             .context("failed to read network config")
             .context(id)?;
 
-        let config: NetworkConfig = serde_json::from_str(&config_text)
+        let mut config_value: serde_json::Value = config_format
+            .parse(&config_text)
+            .context("failed to parse network config")
+            .context(id)?;
+
+        if interpolate {
+            interpolate_value(&mut config_value)
+                .context("failed to parse network config")
+                .context(id)?;
+        }
+
+        let config: NetworkConfig = serde_json::from_value(config_value)
             .context("failed to parse network config")
             .context(id)?;
 
         Ok(config)
     }
 
-    pub async fn save(&self, ctx: &Ctx, id: Id, create: bool) -> Result<()> {
+    /// Returns the id of the network config with the given name, if any
+    pub async fn find_by_name(ctx: &BaseCtx, name: &str) -> Result<Option<Id>> {
+        for id in ctx.dirs().get_network_config_ids()? {
+            if Self::open_raw(ctx, id).await?.name == name {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fails if the subnet overlaps that of another network, or an address
+    /// already assigned on the host outside of our bridges
+    pub async fn check_subnet_available(&self, ctx: &BaseCtx, id: Id) -> Result<()> {
+        for other_id in ctx.dirs().get_network_config_ids()? {
+            if other_id == id {
+                continue;
+            }
+            let other = Self::open(ctx, other_id).await?;
+            if other.mode == NetworkMode::User {
+                continue;
+            }
+            if subnets_overlap(&self.ip, &other.ip) {
+                bail!(
+                    "network subnet {} overlaps network {} ({}): {}",
+                    self.ip.trunc(),
+                    other.name,
+                    other_id,
+                    other.ip.trunc()
+                );
+            }
+        }
+
+        let output = cmd_success("ip", &["-o", "-4", "addr", "show"]).await?;
+        let output = String::from_utf8_lossy(&output.stdout);
+
+        for line in output.lines() {
+            // e.g. `2: eth0    inet 192.168.1.10/24 brd ... scope global eth0`
+            let mut fields = line.split_whitespace();
+            let Some(device) = fields.nth(1) else {
+                continue;
+            };
+            // Bridging an uplink is meant to put guests on its subnet
+            if device.starts_with("vmmbr-") || self.uplinks.iter().any(|uplink| uplink == device) {
+                continue;
+            }
+            let Some(addr) = fields
+                .skip_while(|field| *field != "inet")
+                .nth(1)
+                .and_then(|addr| addr.parse::<Ipv4Net>().ok())
+            else {
+                continue;
+            };
+            if subnets_overlap(&self.ip, &addr) {
+                bail!(
+                    "network subnet {} overlaps address {} on host device {}",
+                    self.ip.trunc(),
+                    addr,
+                    device
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails on settings that can't be used together. Checked when the network
+    /// is created and again before its bridge is brought up, so that a
+    /// hand-edited config can still be opened and fixed.
+    pub fn validate(&self) -> Result<()> {
+        // dnsmasq would answer DHCP requests from the whole physical LAN
+        if self.dhcp && !self.uplinks.is_empty() {
+            bail!(
+                "network {} can't run a dhcp server while it has uplinks",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    /// Applies a change to the config file as written on disk
+    pub async fn update(ctx: &BaseCtx, id: Id, f: impl FnOnce(&mut Self)) -> Result<()> {
+        let mut config = Self::open_raw(ctx, id).await?;
+        f(&mut config);
+        config.save(ctx, id, false).await
+    }
+
+    pub async fn save(&self, ctx: &BaseCtx, id: Id, create: bool) -> Result<()> {
         let config_path = ctx.dirs().get_network_config_file_path(id)?;
         let config_dir = config_path.parent().ok_or(anyhow!("invalid path"))?;
 
@@ -43,7 +182,8 @@ impl NetworkConfig {
 
         tokio::fs::create_dir_all(&config_dir).await?;
 
-        let config_text = serde_json::to_string_pretty(&self)
+        let config_text = ConfigFormat::from_path(&config_path)
+            .serialize(&self)
             .context("failed to serialize network config")
             .context(id)?;
 
@@ -63,12 +203,17 @@ pub struct Network {
 }
 
 impl Network {
-    pub async fn new(ctx: &Ctx, id: Id, config: NetworkConfig) -> Result<Self> {
+    pub async fn new(ctx: &BaseCtx, id: Id, config: NetworkConfig) -> Result<Self> {
+        config.validate()?;
+        // User-mode networks only exist inside qemu
+        if config.mode == NetworkMode::Bridge {
+            config.check_subnet_available(ctx, id).await?;
+        }
         config.save(ctx, id, true).await?;
         Ok(Self { id, config })
     }
 
-    pub async fn open(ctx: &Ctx, id: Id) -> Result<Self> {
+    pub async fn open(ctx: &BaseCtx, id: Id) -> Result<Self> {
         let config = NetworkConfig::open(ctx, id).await?;
         Ok(Self { id, config })
     }
@@ -81,18 +226,24 @@ impl Network {
         &self.config
     }
 
-    pub async fn set_bridge_up_or_create(&self) -> Result<()> {
+    pub async fn set_bridge_up_or_create(&self, ctx: &BaseCtx) -> Result<()> {
+        self.config.validate().context(self.id)?;
+
         let bridge = self.get_bridge_name();
 
         // TODO: can set and check a flag instead to speed up calling this many
         // times in sequence
 
-        if !cmd("ip", &["link", "show", &bridge]).await?.success() {
+        if !cmd("ip", &["link", "show", &bridge])
+            .await?
+            .status
+            .success()
+        {
             cmd_success("ip", &["link", "add", &bridge, "type", "bridge"]).await?;
 
             loop {
                 let ret = cmd("ip", &["link", "show", &bridge]).await?;
-                if ret.success() {
+                if ret.status.success() {
                     break;
                 }
                 tokio::time::sleep(Duration::from_millis(50)).await;
@@ -107,9 +258,163 @@ impl Network {
 
         cmd_success("ip", &["link", "set", "up", "dev", &bridge]).await?;
 
+        for uplink in self.config.uplinks.iter() {
+            if !cmd("ip", &["link", "show", uplink]).await?.status.success() {
+                bail!("network uplink does not exist: {}", uplink);
+            }
+            cmd_success("ip", &["link", "set", uplink, "master", &bridge]).await?;
+            cmd_success("ip", &["link", "set", "up", "dev", uplink]).await?;
+        }
+
+        if self.config.dhcp {
+            self.start_dhcp_server(ctx).await?;
+        }
+
         Ok(())
     }
 
+    async fn start_dhcp_server(&self, ctx: &BaseCtx) -> Result<()> {
+        if self.get_dhcp_server_pid(ctx)?.is_some() {
+            return Ok(());
+        }
+
+        let (range_start, range_end) = self.get_dhcp_range()?;
+        let bridge_ip = self.config.ip.addr();
+
+        let state_dir = ctx.dirs().get_network_state_dir(self.id)?;
+        tokio::fs::create_dir_all(&state_dir).await?;
+
+        let interface = format!("--interface={}", self.get_bridge_name());
+        let pid_file = format!("--pid-file={}", self.get_dhcp_pid_path(ctx)?.display());
+        let lease_file = format!(
+            "--dhcp-leasefile={}",
+            state_dir.join("dnsmasq.leases").display()
+        );
+        let range = format!(
+            "--dhcp-range={},{},{},12h",
+            range_start,
+            range_end,
+            self.config.ip.netmask()
+        );
+        let router = format!("--dhcp-option=option:router,{}", bridge_ip);
+        let dns_server = format!("--dhcp-option=option:dns-server,{}", bridge_ip);
+
+        // dnsmasq daemonizes once it's bound, so this returns as soon as it's
+        // ready to serve
+        #[rustfmt::skip]
+        let args = [
+            "--conf-file=/dev/null",
+            "--bind-interfaces", "--except-interface=lo",
+            &interface, &pid_file, &lease_file,
+            &range, &router, &dns_server,
+        ];
+
+        cmd_success("dnsmasq", &args).await?;
+
+        Ok(())
+    }
+
+    /// Stops the DHCP server and deletes the bridge, releasing its uplinks,
+    /// once no taps are left on it. Returns whether there was anything to
+    /// tear down.
+    pub async fn delete_bridge_if_unused(&self, ctx: &BaseCtx) -> Result<bool> {
+        let bridge = self.get_bridge_name();
+        let bridge_exists = cmd("ip", &["link", "show", &bridge])
+            .await?
+            .status
+            .success();
+
+        if bridge_exists && self.has_taps().await? {
+            return Ok(false);
+        }
+
+        // A DHCP server can outlive its bridge if the bridge was deleted by
+        // hand
+        let stopped_dhcp_server = self.stop_dhcp_server(ctx).await?;
+
+        if bridge_exists {
+            self.delete_bridge_device().await?;
+        }
+
+        Ok(bridge_exists || stopped_dhcp_server)
+    }
+
+    async fn has_taps(&self) -> Result<bool> {
+        let bridge = self.get_bridge_name();
+        let output = cmd_success("ip", &["-o", "link", "show", "master", &bridge]).await?;
+        let output = String::from_utf8_lossy(&output.stdout);
+
+        // e.g. `5: vmmtap-0abc: <BROADCAST,...> mtu 1500 master vmmbr-0def ...`
+        let has_taps = output.lines().any(|line| {
+            line.split(':')
+                .nth(1)
+                .map(|device| device.trim().split('@').next().unwrap_or_default())
+                .is_some_and(|device| !self.config.uplinks.iter().any(|uplink| uplink == device))
+        });
+
+        Ok(has_taps)
+    }
+
+    /// Returns whether the DHCP server was running
+    async fn stop_dhcp_server(&self, ctx: &BaseCtx) -> Result<bool> {
+        let Some(pid) = self.get_dhcp_server_pid(ctx)? else {
+            return Ok(false);
+        };
+
+        if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("failed to stop dnsmasq")
+                .context(self.id);
+        }
+
+        let _ = tokio::fs::remove_file(self.get_dhcp_pid_path(ctx)?).await;
+
+        Ok(true)
+    }
+
+    /// Returns the pid of the network's dnsmasq if it's running
+    fn get_dhcp_server_pid(&self, ctx: &BaseCtx) -> Result<Option<libc::pid_t>> {
+        let pid_path = self.get_dhcp_pid_path(ctx)?;
+        let Ok(pid_text) = std::fs::read_to_string(&pid_path) else {
+            return Ok(None);
+        };
+
+        let pid: libc::pid_t = pid_text
+            .trim()
+            .parse()
+            .context("invalid dnsmasq pid file")
+            .context(pid_path.display().to_string())?;
+
+        // Signal 0 only checks that the process exists
+        if unsafe { libc::kill(pid, 0) } != 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(pid))
+    }
+
+    fn get_dhcp_pid_path(&self, ctx: &BaseCtx) -> Result<PathBuf> {
+        let path = ctx
+            .dirs()
+            .get_network_state_dir(self.id)?
+            .join("dnsmasq.pid");
+        Ok(path)
+    }
+
+    fn get_dhcp_range(&self) -> Result<(Ipv4Addr, Ipv4Addr)> {
+        if self.config.ip.prefix_len() > 30 {
+            bail!("network subnet too small for dhcp: {}", self.config.ip);
+        }
+
+        let network = u32::from(self.config.ip.network());
+        let broadcast = u32::from(self.config.ip.broadcast());
+
+        let start = network + (broadcast - network) / 2 + 1;
+        let end = broadcast - 1;
+
+        Ok((Ipv4Addr::from(start), Ipv4Addr::from(end)))
+    }
+
     pub async fn set_tap_up_or_create(&self, instance: &Instance) -> Result<()> {
         let bridge = self.get_bridge_name();
         let tap = self.get_tap_name(instance);
@@ -117,12 +422,12 @@ impl Network {
         // TODO: can set and check a flag instead to speed up calling this many
         // times in sequence
 
-        if !cmd("ip", &["link", "show", &tap]).await?.success() {
+        if !cmd("ip", &["link", "show", &tap]).await?.status.success() {
             cmd_success("ip", &["tuntap", "add", &tap, "mode", "tap"]).await?;
 
             loop {
                 let ret = cmd("ip", &["link", "show", &tap]).await?;
-                if ret.success() {
+                if ret.status.success() {
                     break;
                 }
                 tokio::time::sleep(Duration::from_millis(50)).await;
@@ -132,11 +437,84 @@ impl Network {
         cmd_success("ip", &["link", "set", &tap, "up"]).await?;
         cmd_success("ip", &["link", "set", &tap, "master", &bridge]).await?;
 
+        self.set_tap_rate_limits(instance).await?;
+
         Ok(())
     }
 
-    async fn delete_tap_device(&self, instance: &Instance) -> Result<()> {
+    async fn set_tap_rate_limits(&self, instance: &Instance) -> Result<()> {
+        let Some(config) = &instance.machine().config().network else {
+            return Ok(());
+        };
+        if config.rx_rate.is_none() && config.tx_rate.is_none() {
+            return Ok(());
+        }
+
+        ensure_tc_available().await?;
+
         let tap = self.get_tap_name(instance);
+
+        // Traffic towards the guest leaves the host through the tap, so the
+        // guest's receive rate is shaped on the tap's egress
+        if let Some(rx_rate) = config.rx_rate {
+            let rate = format!("{}bit", rx_rate);
+            let burst = get_burst_bytes(rx_rate).to_string();
+
+            #[rustfmt::skip]
+            let args = [
+                "qdisc", "replace", "dev", &tap, "root",
+                "tbf", "rate", &rate, "burst", &burst, "latency", "50ms",
+            ];
+
+            cmd_success("tc", &args).await?;
+        }
+
+        // Traffic from the guest enters the host through the tap's ingress,
+        // which can only be policed, not shaped
+        if let Some(tx_rate) = config.tx_rate {
+            let rate = format!("{}bit", tx_rate);
+            let burst = get_burst_bytes(tx_rate).to_string();
+
+            #[rustfmt::skip]
+            let qdisc_args = [
+                "qdisc", "replace", "dev", &tap, "handle", "ffff:", "ingress",
+            ];
+
+            #[rustfmt::skip]
+            let filter_args = [
+                "filter", "replace", "dev", &tap, "parent", "ffff:",
+                "protocol", "all", "prio", "1", "handle", "800::1",
+                "u32", "match", "u32", "0", "0",
+                "police", "rate", &rate, "burst", &burst, "drop",
+            ];
+
+            cmd_success("tc", &qdisc_args).await?;
+            cmd_success("tc", &filter_args).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete_tap_rate_limits(&self, instance: &Instance) -> Result<()> {
+        let Some(config) = &instance.machine().config().network else {
+            return Ok(());
+        };
+        let tap = self.get_tap_name(instance);
+
+        if config.rx_rate.is_some() {
+            cmd("tc", &["qdisc", "del", "dev", &tap, "root"]).await?;
+        }
+
+        if config.tx_rate.is_some() {
+            cmd("tc", &["qdisc", "del", "dev", &tap, "ingress"]).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn delete_tap_device(&self, instance: &Instance) -> Result<()> {
+        let tap = self.get_tap_name(instance);
+        self.delete_tap_rate_limits(instance).await?;
         cmd_success("ip", &["link", "set", &tap, "down"]).await?;
         cmd_success("ip", &["link", "delete", &tap]).await?;
         Ok(())
@@ -144,6 +522,13 @@ impl Network {
 
     async fn delete_bridge_device(&self) -> Result<()> {
         let name = self.get_bridge_name();
+
+        // Uplinks go back to being plain host interfaces. One that has since
+        // disappeared isn't worth failing over.
+        for uplink in self.config.uplinks.iter() {
+            cmd("ip", &["link", "set", uplink, "nomaster"]).await?;
+        }
+
         cmd_success("ip", &["link", "set", &name, "down"]).await?;
         cmd_success("ip", &["link", "delete", &name]).await?;
         Ok(())
@@ -162,17 +547,31 @@ impl Network {
     }
 }
 
-// TODO: move to cmd.rs?
-async fn cmd(cmd: &str, args: &[&str]) -> Result<ExitStatus> {
-    let ecode = Command::new(cmd).args(args).spawn()?.wait().await?;
-    Ok(ecode)
+/// CIDR blocks either nest or are disjoint, so they overlap exactly when one
+/// contains the other
+fn subnets_overlap(a: &Ipv4Net, b: &Ipv4Net) -> bool {
+    a.contains(&b.trunc()) || b.contains(&a.trunc())
 }
 
-// TODO: move to cmd.rs?
-async fn cmd_success(cmd: &str, args: &[&str]) -> Result<ExitStatus> {
-    let ecode = Command::new(cmd).args(args).spawn()?.wait().await?;
-    if !ecode.success() {
-        bail!("command failed: {}", cmd)
+/// Roughly 100ms worth of traffic, but never below what a single jumbo frame
+/// needs to pass
+fn get_burst_bytes(rate: u64) -> u64 {
+    u64::max(rate / 8 / 10, 16 * 1024)
+}
+
+async fn ensure_tc_available() -> Result<()> {
+    let result = Command::new("tc")
+        .arg("-V")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("tc not found; install iproute2 to use network rate limits")
+        }
+        Err(e) => Err(e).context("failed to run tc"),
     }
-    Ok(ecode)
 }
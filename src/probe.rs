@@ -0,0 +1,48 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Waits until the guest accepts TCP connections on port 22, and with
+/// `read_banner` until sshd also sends its version banner, which means it's
+/// actually serving rather than the port just being open. Returns how long it
+/// took.
+pub async fn wait_for_ssh(ip: Ipv4Addr, read_banner: bool, timeout: Duration) -> Result<Duration> {
+    let addr = SocketAddr::from((ip, 22));
+    let started_at = Instant::now();
+
+    loop {
+        if probe_ssh(addr, read_banner).await {
+            return Ok(started_at.elapsed());
+        }
+
+        if started_at.elapsed() >= timeout {
+            bail!("timed out waiting for ssh on {}", addr);
+        }
+
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+}
+
+async fn probe_ssh(addr: SocketAddr, read_banner: bool) -> bool {
+    let Ok(Ok(mut stream)) = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await
+    else {
+        return false;
+    };
+
+    if !read_banner {
+        return true;
+    }
+
+    let mut banner = [0u8; 4];
+    match tokio::time::timeout(CONNECT_TIMEOUT, stream.read_exact(&mut banner)).await {
+        Ok(Ok(_)) => &banner == b"SSH-",
+        _ => false,
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+/// Host resources used by a process, as reported by `/proc`
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessStats {
+    /// User and system CPU time since the process started
+    pub cpu_time: Duration,
+    /// Resident set size in bytes
+    pub rss: u64,
+}
+
+pub fn read_process_stats(pid: libc::pid_t) -> Result<ProcessStats> {
+    let stat_path = format!("/proc/{}/stat", pid);
+    let stat = std::fs::read_to_string(&stat_path).context(stat_path.clone())?;
+
+    // The command name is in parens and may contain spaces, so fields are
+    // counted from after it, starting with the state as field 3
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .ok_or(anyhow!("invalid process stat"))
+        .context(stat_path.clone())?
+        .1
+        .split_whitespace()
+        .collect();
+
+    let get_field = |n: usize| -> Result<u64> {
+        fields
+            .get(n - 3)
+            .ok_or(anyhow!("missing process stat field {}", n))?
+            .parse()
+            .context(format!("invalid process stat field {}", n))
+    };
+
+    let ticks = get_field(14)? + get_field(15)?;
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    let cpu_time = Duration::from_secs_f64(ticks as f64 / ticks_per_sec.max(1) as f64);
+
+    let status_path = format!("/proc/{}/status", pid);
+    let status = std::fs::read_to_string(&status_path).context(status_path.clone())?;
+
+    // e.g. `VmRSS:     123456 kB`
+    let rss_kib: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.split_whitespace().next())
+        .ok_or(anyhow!("missing VmRSS"))
+        .context(status_path.clone())?
+        .parse()
+        .context("invalid VmRSS")
+        .context(status_path)?;
+
+    Ok(ProcessStats {
+        cpu_time,
+        rss: rss_kib * 1024,
+    })
+}
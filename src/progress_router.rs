@@ -1,43 +1,113 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use anyhow::Result;
-use tokio::sync::{broadcast, mpsc};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+    time::Instant,
+};
 
 use crate::task_group::TaskGroup;
 
 pub fn create_progress_router(task_group: &mut TaskGroup<Result<()>>) -> ProgressRouterClient {
     let (mpsc_sender, mpsc_receiver) = mpsc::channel(100);
     let (broadcast_sender, _) = broadcast::channel(100);
+    let active = Arc::new(Mutex::new(HashMap::new()));
 
-    let progress_tracker = ProgressRouter::new(broadcast_sender.clone(), mpsc_receiver);
+    let progress_tracker =
+        ProgressRouter::new(broadcast_sender.clone(), mpsc_receiver, active.clone());
 
     task_group.spawn(async move {
         progress_tracker.run().await.unwrap();
         Ok(())
     });
 
-    ProgressRouterClient::new(mpsc_sender, broadcast_sender)
+    ProgressRouterClient::new(mpsc_sender, broadcast_sender, active)
 }
 
 #[derive(Debug, Clone)]
 pub enum ProgressMessage {
-    Start(String, Option<u64>),
-    Update(String, u64),
+    /// `label` is what UIs show for the item, since ids needn't be readable
+    Start {
+        id: String,
+        label: String,
+        total: Option<u64>,
+    },
+    Update(String, ProgressUpdate),
     Finish(String),
+    /// Synthetic progress summed across all active items, sent after every
+    /// item message. `total` is `None` if any active item has no known total.
+    Overall {
+        active: usize,
+        position: u64,
+        total: Option<u64>,
+    },
+    /// Sent to a subscriber in place of the messages it missed by falling
+    /// behind, with every item that's active now. Anything not listed has
+    /// finished.
+    Resync(Vec<ProgressItem>),
+}
+
+#[derive(Debug, Clone)]
+pub struct ProgressItem {
+    pub id: String,
+    pub label: String,
+    pub position: u64,
+    pub total: Option<u64>,
+}
+
+/// Producers only set `count`, the number of units since the last update. The
+/// router fills in the rest before the update is broadcast.
+#[derive(Debug, Clone)]
+pub struct ProgressUpdate {
+    pub count: u64,
+    pub position: u64,
+    pub total: Option<u64>,
+    pub eta: Option<Duration>,
+}
+
+impl ProgressUpdate {
+    pub fn new(count: u64) -> Self {
+        Self {
+            count,
+            position: 0,
+            total: None,
+            eta: None,
+        }
+    }
+
+    pub fn get_percentage(&self) -> Option<f64> {
+        self.total
+            .filter(|total| *total > 0)
+            .map(|total| self.position as f64 / total as f64 * 100.0)
+    }
 }
 
+type ActiveMap = Arc<Mutex<HashMap<String, ActiveProgress>>>;
+
 #[derive(Clone)]
 pub struct ProgressRouterClient {
     sender: mpsc::Sender<ProgressMessage>,
     sender_broadcast: broadcast::Sender<ProgressMessage>,
+    active: ActiveMap,
 }
 
 impl ProgressRouterClient {
-    pub fn new(
+    fn new(
         sender: mpsc::Sender<ProgressMessage>,
         sender_broadcast: broadcast::Sender<ProgressMessage>,
+        active: ActiveMap,
     ) -> Self {
         Self {
             sender,
             sender_broadcast,
+            active,
         }
     }
 
@@ -45,28 +115,135 @@ impl ProgressRouterClient {
         let _ = self.sender.send(message).await;
     }
 
-    pub fn subscribe(&self) -> broadcast::Receiver<ProgressMessage> {
-        self.sender_broadcast.subscribe()
+    pub fn subscribe(&self) -> ProgressSubscriber {
+        ProgressSubscriber {
+            receiver: self.sender_broadcast.subscribe(),
+            active: self.active.clone(),
+        }
+    }
+}
+
+/// Subscribers share a bounded buffer, so one that falls too far behind
+/// misses messages. Rather than surface that as an error, the subscriber
+/// skips ahead and hands back a `Resync` with the current state, so slow UIs
+/// drop frames instead of drifting out of sync.
+pub struct ProgressSubscriber {
+    receiver: broadcast::Receiver<ProgressMessage>,
+    active: ActiveMap,
+}
+
+impl ProgressSubscriber {
+    /// Returns `None` once the router has stopped
+    pub async fn recv(&mut self) -> Option<ProgressMessage> {
+        match self.receiver.recv().await {
+            Ok(message) => Some(message),
+            Err(RecvError::Lagged(_)) => {
+                // Skip to the newest message before taking the snapshot, so
+                // whatever follows is no older than it
+                self.receiver = self.receiver.resubscribe();
+                Some(ProgressMessage::Resync(self.get_items()))
+            }
+            Err(RecvError::Closed) => None,
+        }
+    }
+
+    fn get_items(&self) -> Vec<ProgressItem> {
+        let active = self.active.lock().unwrap();
+        active
+            .iter()
+            .map(|(id, progress)| ProgressItem {
+                id: id.clone(),
+                label: progress.label.clone(),
+                position: progress.position,
+                total: progress.total,
+            })
+            .collect()
     }
 }
 
 pub struct ProgressRouter {
     sender: broadcast::Sender<ProgressMessage>,
     receiver: mpsc::Receiver<ProgressMessage>,
+    active: ActiveMap,
+}
+
+struct ActiveProgress {
+    label: String,
+    started_at: Instant,
+    position: u64,
+    total: Option<u64>,
+}
+
+impl ActiveProgress {
+    fn get_eta(&self) -> Option<Duration> {
+        let total = self.total?;
+        if self.position == 0 {
+            return None;
+        }
+        let remaining = total.saturating_sub(self.position);
+        let elapsed = self.started_at.elapsed();
+        Some(elapsed.mul_f64(remaining as f64 / self.position as f64))
+    }
 }
 
 impl ProgressRouter {
-    pub fn new(
+    fn new(
         sender: broadcast::Sender<ProgressMessage>,
         receiver: mpsc::Receiver<ProgressMessage>,
+        active: ActiveMap,
     ) -> Self {
-        Self { sender, receiver }
+        Self {
+            sender,
+            receiver,
+            active,
+        }
     }
 
     pub async fn run(mut self) -> Result<()> {
-        while let Some(message) = self.receiver.recv().await {
+        while let Some(mut message) = self.receiver.recv().await {
+            // Hold the lock until the message is broadcast, so a subscriber
+            // resyncing never sees state the broadcast hasn't caught up to
+            let mut active = self.active.lock().unwrap();
+
+            match &mut message {
+                ProgressMessage::Start { id, label, total } => {
+                    let progress = ActiveProgress {
+                        label: label.clone(),
+                        started_at: Instant::now(),
+                        position: 0,
+                        total: *total,
+                    };
+                    active.insert(id.clone(), progress);
+                }
+                ProgressMessage::Update(id, update) => {
+                    if let Some(progress) = active.get_mut(id) {
+                        progress.position += update.count;
+                        update.position = progress.position;
+                        update.total = progress.total;
+                        update.eta = progress.get_eta();
+                    }
+                }
+                ProgressMessage::Finish(id) => {
+                    active.remove(id);
+                }
+                // Only the router produces these
+                ProgressMessage::Overall { .. } | ProgressMessage::Resync(_) => continue,
+            }
+
             let _ = self.sender.send(message);
+            let _ = self.sender.send(get_overall(&active));
         }
         Ok(())
     }
 }
+
+fn get_overall(active: &HashMap<String, ActiveProgress>) -> ProgressMessage {
+    let position = active.values().map(|progress| progress.position).sum();
+    let total = active.values().map(|progress| progress.total).sum();
+
+    ProgressMessage::Overall {
+        active: active.len(),
+        position,
+        total,
+    }
+}
@@ -0,0 +1,117 @@
+use std::{collections::HashMap, fmt::Write, time::Duration};
+
+use crate::progress_router::{ProgressMessage, ProgressRouterClient};
+use anyhow::Result;
+use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+
+/// Renders a bar for each item with a known total, and a spinner with the
+/// bytes so far for items without one. While more than one item is active, a
+/// combined bar shows the overall progress.
+pub async fn show_progress(progress_router: ProgressRouterClient) -> Result<()> {
+    let multi_progress = MultiProgress::new();
+    let mut progress_bars = HashMap::new();
+    let mut overall_bar: Option<ProgressBar> = None;
+    let mut receiver = progress_router.subscribe();
+
+    while let Some(message) = receiver.recv().await {
+        match message {
+            ProgressMessage::Start { id, label, total } => {
+                // Already added by a resync
+                if progress_bars.contains_key(&id) {
+                    continue;
+                }
+                let pb = multi_progress.add(create_progress_bar(label, total));
+                progress_bars.insert(id, pb);
+            }
+            ProgressMessage::Update(id, update) => {
+                if let Some(pb) = progress_bars.get(&id) {
+                    pb.set_position(update.position);
+                    if let Some(percentage) = update.get_percentage() {
+                        pb.set_prefix(format!("{:>3.0}%", percentage));
+                    }
+                }
+            }
+            ProgressMessage::Finish(id) => {
+                if let Some(pb) = progress_bars.remove(&id) {
+                    pb.finish_and_clear();
+                    multi_progress.remove(&pb);
+                }
+            }
+            ProgressMessage::Overall {
+                active,
+                position,
+                total,
+            } => {
+                // A bar can't turn into a spinner, so it's replaced whenever
+                // whether the total is known changes
+                if let Some(pb) =
+                    overall_bar.take_if(|pb| active < 2 || pb.length().is_some() != total.is_some())
+                {
+                    pb.finish_and_clear();
+                    multi_progress.remove(&pb);
+                }
+                if active < 2 {
+                    continue;
+                }
+                let pb = overall_bar.get_or_insert_with(|| {
+                    multi_progress.add(create_progress_bar(String::new(), total))
+                });
+                if let Some(total) = total {
+                    pb.set_length(total);
+                }
+                pb.set_message(format!("overall ({} active)", active));
+                pb.set_position(position);
+            }
+            ProgressMessage::Resync(items) => {
+                progress_bars.retain(|id, pb| {
+                    let active = items.iter().any(|item| item.id == *id);
+                    if !active {
+                        pb.finish_and_clear();
+                        multi_progress.remove(pb);
+                    }
+                    active
+                });
+
+                for item in items {
+                    let pb = progress_bars.entry(item.id).or_insert_with(|| {
+                        multi_progress.add(create_progress_bar(item.label, item.total))
+                    });
+                    pb.set_position(item.position);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn create_progress_bar(label: String, total: Option<u64>) -> ProgressBar {
+    let pb = match total {
+        Some(total) => {
+            let pb = ProgressBar::new(total);
+            pb.set_style(get_bar_style());
+            pb
+        }
+        None => {
+            let pb = ProgressBar::new_spinner();
+            pb.set_style(get_spinner_style());
+            pb.enable_steady_tick(Duration::from_millis(100));
+            pb
+        }
+    };
+    pb.set_message(label);
+    pb
+}
+
+fn get_bar_style() -> ProgressStyle {
+    ProgressStyle::with_template("{prefix} {msg} [{wide_bar}] {bytes}/{total_bytes} ({eta})")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
+            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+        })
+        .progress_chars("#>-")
+}
+
+fn get_spinner_style() -> ProgressStyle {
+    ProgressStyle::with_template("{msg} {spinner} {bytes} ({bytes_per_sec})").unwrap()
+}
@@ -0,0 +1,108 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail};
+use byte_unit::Byte;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::cmd::{DEFAULT_CMD_TIMEOUT, cmd_success, cmd_with_timeout};
+
+/// Converting or preallocating writes the whole image, which takes a while
+/// for large ones
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How much of a new image's space is allocated up front
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Preallocation {
+    /// Sparse, space is only used as the guest writes
+    #[default]
+    Off,
+    /// Only the qcow2 metadata is allocated
+    Metadata,
+    /// The whole image is written out
+    Full,
+}
+
+impl Preallocation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Metadata => "metadata",
+            Self::Full => "full",
+        }
+    }
+}
+
+/// Probes the format of a disk image, e.g. `qcow2` or `raw`, since downloaded
+/// images aren't always what their name suggests
+pub async fn get_image_format(path: &Path) -> Result<String> {
+    let path_text = path.to_string_lossy();
+
+    let output = cmd_success("qemu-img", &["info", "--output=json", &path_text]).await?;
+
+    let info: Value = serde_json::from_slice(&output.stdout)
+        .context("failed to parse qemu-img info")
+        .context(path.display().to_string())?;
+
+    let format = info
+        .get("format")
+        .and_then(Value::as_str)
+        .ok_or(anyhow!("qemu-img info has no format: {}", path.display()))?;
+
+    Ok(format.to_string())
+}
+
+pub async fn convert_to_qcow2(source: &Path, target: &Path) -> Result<()> {
+    let source = source.to_string_lossy();
+    let target = target.to_string_lossy();
+
+    #[rustfmt::skip]
+    let args = [
+        "convert", "-O", "qcow2",
+        &source, &target,
+    ];
+
+    let output = cmd_with_timeout("qemu-img", &args, WRITE_TIMEOUT).await?;
+    if !output.status.success() {
+        bail!(
+            "qemu-img convert failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn create_qcow2_image(
+    path: &Path,
+    size: Byte,
+    preallocation: Preallocation,
+) -> Result<()> {
+    let path = path.to_string_lossy();
+    let size = size.as_u64().to_string();
+    let options = format!("preallocation={}", preallocation.as_str());
+
+    #[rustfmt::skip]
+    let args = [
+        "create", "-f", "qcow2",
+        "-o", &options,
+        &path, &size,
+    ];
+
+    // Full preallocation writes out the whole image
+    let timeout = match preallocation {
+        Preallocation::Full => WRITE_TIMEOUT,
+        _ => DEFAULT_CMD_TIMEOUT,
+    };
+
+    let output = cmd_with_timeout("qemu-img", &args, timeout).await?;
+    if !output.status.success() {
+        bail!(
+            "qemu-img create failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        UnixStream,
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+    },
+};
+
+/// qemu answers right away unless it's wedged, and callers may be holding up
+/// others while they wait
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct QmpClient {
+    reader: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl QmpClient {
+    pub async fn connect(socket_path: &Path) -> Result<Self> {
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, UnixStream::connect(socket_path))
+            .await
+            .map_err(|_| anyhow!("timed out connecting to qmp socket"))
+            .and_then(|result| result.context("failed to connect to qmp socket"))
+            .context(socket_path.display().to_string())?;
+
+        let (reader, writer) = stream.into_split();
+
+        let mut client = Self {
+            reader: BufReader::new(reader).lines(),
+            writer,
+        };
+
+        let greeting = client.read_message().await?;
+        if greeting.get("QMP").is_none() {
+            bail!("unexpected qmp greeting: {}", greeting);
+        }
+
+        client.execute("qmp_capabilities", None).await?;
+
+        Ok(client)
+    }
+
+    /// Connects once qemu has created its QMP socket, which happens shortly
+    /// after the process is spawned
+    pub async fn connect_with_retry(socket_path: &Path, timeout: Duration) -> Result<Self> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            match Self::connect(socket_path).await {
+                Ok(client) => return Ok(client),
+                Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+    }
+
+    pub async fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+
+        let mut request_text = serde_json::to_string(&request)?;
+        request_text.push('\n');
+
+        self.writer
+            .write_all(request_text.as_bytes())
+            .await
+            .context("failed to write qmp command")
+            .context(command.to_string())?;
+
+        loop {
+            let message = self.read_message().await?;
+
+            if let Some(value) = message.get("return") {
+                return Ok(value.clone());
+            }
+
+            if let Some(error) = message.get("error") {
+                let desc = error
+                    .get("desc")
+                    .and_then(|desc| desc.as_str())
+                    .unwrap_or("unknown error");
+                bail!("qmp command {} failed: {}", command, desc);
+            }
+
+            // Anything else is an asynchronous event, which we don't track
+        }
+    }
+
+    /// Runs a command that starts a background job and waits for the job to
+    /// finish, returning its error if it failed
+    pub async fn run_job(&mut self, command: &str, job_id: &str, arguments: Value) -> Result<()> {
+        let mut arguments = arguments;
+        arguments["job-id"] = Value::from(job_id);
+
+        self.execute(command, Some(arguments)).await?;
+
+        loop {
+            let jobs = self.execute("query-jobs", None).await?;
+
+            let job = jobs
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find(|job| job.get("id").and_then(Value::as_str) == Some(job_id))
+                .ok_or(anyhow!("qmp job disappeared: {}", job_id))?;
+
+            if job.get("status").and_then(Value::as_str) == Some("concluded") {
+                let error = job.get("error").and_then(Value::as_str).map(String::from);
+
+                self.execute("job-dismiss", Some(json!({ "id": job_id })))
+                    .await?;
+
+                if let Some(error) = error {
+                    bail!("qmp job {} failed: {}", job_id, error);
+                }
+
+                return Ok(());
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    async fn read_message(&mut self) -> Result<Value> {
+        let line = tokio::time::timeout(READ_TIMEOUT, self.reader.next_line())
+            .await
+            .map_err(|_| anyhow!("timed out waiting for qmp message"))?
+            .context("failed to read qmp message")?
+            .ok_or(anyhow!("qmp connection closed"))?;
+
+        let message = serde_json::from_str(&line).context("failed to parse qmp message")?;
+
+        Ok(message)
+    }
+}
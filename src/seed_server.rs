@@ -0,0 +1,158 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    Router,
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use crate::cmd::cmd_success;
+
+/// Serves an instance's cloud-init files over http, for guests seeded through
+/// SMBIOS rather than an ISO. cloud-init fetches them by appending the file
+/// name to the seed url. Passing them through `-fw_cfg` instead would avoid
+/// the server, but cloud-init has no datasource that reads fw_cfg.
+pub struct SeedServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+/// Who may fetch the seed, since user-data carries the guest's keys and
+/// anything else the machine config puts there
+pub enum SeedClient {
+    /// The guest with this MAC address on the bridge, whichever address it
+    /// has
+    Bridge { bridge: String, mac: String },
+    /// Every guest on a user-mode network connects from the host's loopback,
+    /// like any local user, so user-data is only served once
+    Loopback,
+}
+
+struct SeedState {
+    dir: PathBuf,
+    client: SeedClient,
+    user_data_served: AtomicBool,
+}
+
+impl SeedServer {
+    /// `addr` usually has port 0, in which case a free port is picked
+    pub async fn start(
+        addr: SocketAddr,
+        cloud_init_dir: PathBuf,
+        client: SeedClient,
+    ) -> Result<Self> {
+        let state = Arc::new(SeedState {
+            dir: cloud_init_dir,
+            client,
+            user_data_served: AtomicBool::new(false),
+        });
+
+        let app = Router::new()
+            .route("/meta-data", get(get_meta_data))
+            .route("/user-data", get(get_user_data))
+            .route("/vendor-data", get(get_vendor_data))
+            .layer(middleware::from_fn_with_state(state.clone(), authorize))
+            .with_state(state);
+
+        let listener = TcpListener::bind(addr)
+            .await
+            .context("failed to bind cloud-init seed server address")
+            .context(addr)?;
+
+        let addr = listener.local_addr()?;
+
+        let task = tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(e) = axum::serve(listener, app).await {
+                eprintln!("error: cloud-init seed server failed: {:?}", e);
+            }
+        });
+
+        Ok(Self { addr, task })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    pub fn stop(self) {
+        self.task.abort();
+    }
+}
+
+async fn authorize(
+    State(state): State<Arc<SeedState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let SeedClient::Bridge { bridge, mac } = &state.client else {
+        return next.run(request).await;
+    };
+
+    match get_neighbor_mac(bridge, peer.ip()).await {
+        Ok(Some(peer_mac)) if peer_mac.eq_ignore_ascii_case(mac) => next.run(request).await,
+        Ok(_) => StatusCode::FORBIDDEN.into_response(),
+        Err(e) => {
+            eprintln!("error: {:?}", e);
+            StatusCode::FORBIDDEN.into_response()
+        }
+    }
+}
+
+/// Looks up the MAC address behind an address on the bridge. A guest that
+/// just connected has always been resolved.
+async fn get_neighbor_mac(bridge: &str, ip: IpAddr) -> Result<Option<String>> {
+    let ip = ip.to_string();
+    let output = cmd_success("ip", &["neigh", "show", &ip, "dev", bridge]).await?;
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    // e.g. `10.0.0.5 lladdr 52:54:00:ab:cd:ef REACHABLE`
+    let mac = output
+        .split_whitespace()
+        .skip_while(|field| *field != "lladdr")
+        .nth(1)
+        .map(str::to_string);
+
+    Ok(mac)
+}
+
+async fn get_meta_data(State(state): State<Arc<SeedState>>) -> Response {
+    read_seed_file(state.dir.join("meta-data.yaml")).await
+}
+
+async fn get_user_data(State(state): State<Arc<SeedState>>) -> Response {
+    if matches!(state.client, SeedClient::Loopback)
+        && state.user_data_served.swap(true, Ordering::SeqCst)
+    {
+        return StatusCode::GONE.into_response();
+    }
+    read_seed_file(state.dir.join("user-config.yaml")).await
+}
+
+/// There is no vendor-data, but cloud-init asks for it anyway
+async fn get_vendor_data() -> Response {
+    "".into_response()
+}
+
+async fn read_seed_file(path: PathBuf) -> Response {
+    match tokio::fs::read_to_string(&path).await {
+        Ok(text) => text.into_response(),
+        Err(e) => {
+            eprintln!("error: failed to read {}: {:?}", path.display(), e);
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
@@ -1,39 +1,153 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{Context, Result, anyhow, bail};
+use anyhow::{Context, Result, bail};
+use byte_unit::Byte;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::{Mutex, mpsc},
+};
 
 use crate::{
-    ctx::Ctx,
+    ctx::{BaseCtx, Ctx},
+    error::{VmmError, VmmResult},
     id::Id,
-    instance::Instance,
+    instance::{AttachDiskOptions, DiskFormat, Instance, QemuStatus, query_paused},
+    instance_events::{InstanceEvent, record_instance_event},
     machine::{Machine, MachineConfig},
     network::{Network, NetworkConfig},
 };
 
+/// Instances that stay up at least this long have their restart count reset
+const RESTART_RESET_UPTIME: Duration = Duration::from_secs(300);
+/// Listing instances shouldn't wait long on any one qemu
+const PAUSED_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InstanceSummary {
+    pub id: Id,
+    pub machine_id: Id,
+    pub network_id: Option<Id>,
+    pub running: bool,
+    /// The vcpus are stopped, e.g. after starting with `--paused`
+    #[serde(default)]
+    pub paused: bool,
+    /// Tells a stopped instance apart from one whose qemu exited on its own
+    #[serde(default)]
+    pub status: QemuStatus,
+    pub uptime_secs: Option<u64>,
+}
+
+impl InstanceSummary {
+    fn new(instance: &Instance, paused: bool) -> Self {
+        Self {
+            id: *instance.id(),
+            machine_id: *instance.machine().id(),
+            network_id: instance.network().map(|network| *network.id()),
+            running: instance.is_running(),
+            paused,
+            status: instance.status(),
+            uptime_secs: instance.uptime().map(|uptime| uptime.as_secs()),
+        }
+    }
+
+    /// Asks qemu whether the instance is paused, if it's running. A failed or
+    /// slow query is reported but doesn't keep the rest of the summary from
+    /// being listed.
+    async fn query(mut self, qmp_socket_path: Option<PathBuf>) -> Self {
+        let Some(qmp_socket_path) = qmp_socket_path else {
+            return self;
+        };
+
+        match tokio::time::timeout(PAUSED_QUERY_TIMEOUT, query_paused(&qmp_socket_path)).await {
+            Ok(Ok(paused)) => self.paused = paused,
+            Ok(Err(e)) => eprintln!("error: {:?}", e.context(self.id)),
+            Err(_) => eprintln!(
+                "error: timed out asking whether instance is paused: {}",
+                self.id
+            ),
+        }
+
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MachineSummary {
+    pub id: Id,
+    pub name: String,
+    pub cpus: u8,
+    pub memory: u64,
+}
+
+impl MachineSummary {
+    fn new(machine: &Machine) -> Self {
+        let config = machine.config();
+        Self {
+            id: *machine.id(),
+            name: config.name.clone(),
+            cpus: config.cpus,
+            memory: config.memory.as_u64(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 enum EntityKind {
     Machine,
     Network,
 }
 
+/// What a reload has to do once the configs have been read
+#[derive(Default)]
+struct ReloadPlan {
+    /// Running instances whose state was removed, to be stopped
+    removed: Vec<Instance>,
+    /// Running instances whose machine config changed, checked out to be
+    /// stopped and started again with the new config
+    changed: Vec<Instance>,
+    /// Instances that are new since the last reload, to be started
+    new_ids: Vec<Id>,
+}
+
 pub struct Server {
     names: HashMap<(EntityKind, String), Id>,
     machines: HashMap<Id, Machine>,
     networks: HashMap<Id, Network>,
     instances: HashMap<Id, Instance>,
+    /// Instances taken out of `instances` while they boot, stop or otherwise
+    /// wait on qemu, so that the server lock isn't held for as long as that
+    /// takes
+    checked_out: HashMap<Id, InstanceSummary>,
+    restart_counts: HashMap<Id, u32>,
+    /// Told by instances when their qemu exits
+    exit_tx: mpsc::UnboundedSender<Id>,
+    exit_rx: Option<mpsc::UnboundedReceiver<Id>>,
 }
 
 impl Server {
     pub fn new() -> Self {
+        let (exit_tx, exit_rx) = mpsc::unbounded_channel();
         Self {
             names: HashMap::new(),
             machines: HashMap::new(),
             networks: HashMap::new(),
             instances: HashMap::new(),
+            checked_out: HashMap::new(),
+            restart_counts: HashMap::new(),
+            exit_tx,
+            exit_rx: Some(exit_rx),
         }
     }
 
-    async fn read_machines(&mut self, ctx: &Ctx) -> Result<()> {
+    async fn read_machines(&mut self, ctx: &BaseCtx) -> Result<()> {
         let config = ctx.dirs();
         let ids = config.get_machine_config_ids()?;
         for id in ids {
@@ -47,7 +161,7 @@ impl Server {
         Ok(())
     }
 
-    async fn read_networks(&mut self, ctx: &Ctx) -> Result<()> {
+    async fn read_networks(&mut self, ctx: &BaseCtx) -> Result<()> {
         let config = ctx.dirs();
         let ids = config.get_network_config_ids()?;
         for id in ids {
@@ -62,24 +176,235 @@ impl Server {
     }
 
     // XXX TODO: do we even use config for instances?
-    async fn read_instances(&mut self, ctx: &Ctx) -> Result<()> {
+    async fn read_instances(&mut self, ctx: &BaseCtx) -> Result<()> {
         let state = ctx.dirs();
         let ids = state.get_instance_state_ids()?;
         for id in ids {
-            let instance = Instance::read(ctx, id).await?;
+            let mut instance = Instance::open(ctx, id).await?;
+            instance.set_exit_notifier(self.exit_tx.clone());
             self.instances.insert(id, instance);
         }
         Ok(())
     }
 
-    pub async fn read_all(&mut self, ctx: &Ctx) -> Result<()> {
+    pub async fn read_all(&mut self, ctx: &BaseCtx) -> Result<()> {
         self.read_machines(ctx).await?;
         self.read_networks(ctx).await?;
         self.read_instances(ctx).await?;
         Ok(())
     }
 
-    pub async fn create_machine(&mut self, ctx: &Ctx, config: MachineConfig) -> Result<Id> {
+    /// Re-reads all configs and works out which instances have to be stopped
+    /// or started, leaving that to `reload` so that the lock isn't held while
+    /// they shut down or boot
+    async fn reload_configs(
+        &mut self,
+        ctx: &BaseCtx,
+        actions: &mut Vec<String>,
+    ) -> Result<ReloadPlan> {
+        // Read into a fresh server first so that a bad config leaves the
+        // current state untouched
+        let mut next = Server::new();
+        next.read_machines(ctx).await?;
+        next.read_networks(ctx).await?;
+
+        let changed_machine_ids: HashSet<Id> = next
+            .machines
+            .iter()
+            .filter(|(id, machine)| {
+                self.machines
+                    .get(id)
+                    .is_none_or(|old| get_config_hash(old) != get_config_hash(machine))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        self.names = next.names;
+        self.machines = next.machines;
+        self.networks = next.networks;
+
+        let instance_ids: HashSet<Id> = ctx.dirs().get_instance_state_ids()?.into_iter().collect();
+
+        let mut plan = ReloadPlan::default();
+
+        let removed_ids: Vec<Id> = self
+            .instances
+            .keys()
+            .filter(|id| !instance_ids.contains(id))
+            .copied()
+            .collect();
+
+        for id in removed_ids {
+            let instance = self.instances.remove(&id);
+            plan.removed.extend(instance.filter(Instance::is_running));
+            self.restart_counts.remove(&id);
+        }
+
+        for id in instance_ids {
+            // Picked up by the next reload once it's back
+            if self.checked_out.contains_key(&id) {
+                continue;
+            }
+
+            let changed = match self.instances.get(&id) {
+                Some(instance) => changed_machine_ids.contains(instance.machine().id()),
+                None => true,
+            };
+            if !changed {
+                continue;
+            }
+
+            // Instances created since the last read are started, and changed
+            // ones only if they were running. A freshly started server starts
+            // none of them, since it can't tell which are new.
+            let is_new = !self.instances.contains_key(&id);
+
+            if self
+                .instances
+                .get(&id)
+                .is_some_and(|instance| instance.is_running())
+            {
+                match self.check_out(id) {
+                    Ok(instance) => plan.changed.push(instance),
+                    Err(e) => eprintln!("error: failed to restart instance {}: {:?}", id, e),
+                }
+                continue;
+            }
+
+            let mut instance = match Instance::open(ctx, id).await {
+                Ok(instance) => instance,
+                Err(e) => {
+                    eprintln!("error: failed to read instance {}: {:?}", id, e);
+                    self.instances.remove(&id);
+                    continue;
+                }
+            };
+            instance.set_exit_notifier(self.exit_tx.clone());
+
+            if is_new {
+                plan.new_ids.push(id);
+            } else {
+                actions.push(format!("reloaded machine config of instance {}", id));
+            }
+
+            self.instances.insert(id, instance);
+        }
+
+        Ok(plan)
+    }
+
+    /// Puts a checked out instance back as read from disk again, so that it
+    /// picks up its machine's new config
+    async fn check_in_reopened_instance(
+        &mut self,
+        ctx: &BaseCtx,
+        instance: Instance,
+    ) -> Result<()> {
+        let id = *instance.id();
+        drop(instance);
+
+        match Instance::open(ctx, id).await {
+            Ok(mut instance) => {
+                instance.set_exit_notifier(self.exit_tx.clone());
+                self.check_in_instance(instance);
+                Ok(())
+            }
+            Err(e) => {
+                self.checked_out.remove(&id);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn instances(&self) -> impl Iterator<Item = &Instance> {
+        self.instances.values()
+    }
+
+    pub fn list_machines(&self) -> Vec<MachineSummary> {
+        self.machines.values().map(MachineSummary::new).collect()
+    }
+
+    /// Summaries of every instance, along with the QMP socket of those whose
+    /// qemu still has to be asked whether they're paused
+    fn snapshot_instances(&self) -> Vec<(InstanceSummary, Option<PathBuf>)> {
+        let mut snapshots = Vec::with_capacity(self.instances.len() + self.checked_out.len());
+        snapshots.extend(self.instances.values().map(snapshot_instance));
+        snapshots.extend(
+            self.checked_out
+                .values()
+                .map(|summary| (summary.clone(), None)),
+        );
+        snapshots
+    }
+
+    fn snapshot_instance(&self, id: Id) -> VmmResult<(InstanceSummary, Option<PathBuf>)> {
+        if let Some(summary) = self.checked_out.get(&id) {
+            return Ok((summary.clone(), None));
+        }
+
+        let instance = self
+            .instances
+            .get(&id)
+            .ok_or(VmmError::InstanceNotFound(id))?;
+
+        Ok(snapshot_instance(instance))
+    }
+
+    fn get_instance_mut(&mut self, id: Id) -> VmmResult<&mut Instance> {
+        if self.checked_out.contains_key(&id) {
+            return Err(VmmError::InstanceBusy(id));
+        }
+
+        self.instances
+            .get_mut(&id)
+            .ok_or(VmmError::InstanceNotFound(id))
+    }
+
+    /// Takes an instance out to be worked on without holding the server lock.
+    /// It's listed as it was when taken out until it's put back with
+    /// `check_in_instance`, and anything else asking for it gets
+    /// `InstanceBusy`.
+    fn check_out(&mut self, id: Id) -> VmmResult<Instance> {
+        self.get_instance_mut(id)?;
+
+        let instance = self
+            .instances
+            .remove(&id)
+            .ok_or(VmmError::InstanceNotFound(id))?;
+
+        self.checked_out
+            .insert(id, InstanceSummary::new(&instance, false));
+
+        Ok(instance)
+    }
+
+    /// Takes an instance out to be started, listing it as starting
+    fn check_out_instance(&mut self, id: Id) -> VmmResult<Instance> {
+        // Starting it again would redo the boot-time setup under the guest
+        if self.get_instance_mut(id)?.is_running() {
+            return Err(VmmError::InstanceRunning(id));
+        }
+
+        let instance = self.check_out(id)?;
+
+        if let Some(summary) = self.checked_out.get_mut(&id) {
+            summary.status = QemuStatus::Starting;
+        }
+
+        Ok(instance)
+    }
+
+    fn check_in_instance(&mut self, instance: Instance) {
+        let id = *instance.id();
+        self.checked_out.remove(&id);
+        self.instances.insert(id, instance);
+
+        // An exit while the instance was out went unnoticed, since checking
+        // the instances didn't see it
+        let _ = self.exit_tx.send(id);
+    }
+
+    pub async fn create_machine(&mut self, ctx: &BaseCtx, config: MachineConfig) -> Result<Id> {
         let id = loop {
             let id = Id::new()?;
             if !self.machines.contains_key(&id) {
@@ -91,7 +416,7 @@ impl Server {
         Ok(id)
     }
 
-    pub async fn create_network(&mut self, ctx: &Ctx, config: NetworkConfig) -> Result<Id> {
+    pub async fn create_network(&mut self, ctx: &BaseCtx, config: NetworkConfig) -> Result<Id> {
         let id = loop {
             let id = Id::new()?;
             if !self.networks.contains_key(&id) {
@@ -105,10 +430,10 @@ impl Server {
 
     pub async fn create_instance(
         &mut self,
-        ctx: &Ctx,
+        ctx: &BaseCtx,
         machine_id: Id,
-        network_id: Id,
-    ) -> Result<Id> {
+        network_id: Option<Id>,
+    ) -> VmmResult<Id> {
         let id = loop {
             let id = Id::new()?;
             if !self.instances.contains_key(&id) {
@@ -119,46 +444,433 @@ impl Server {
         let machine = self
             .machines
             .get(&machine_id)
-            .ok_or(anyhow::anyhow!("machine not found"))?;
+            .ok_or(VmmError::MachineNotFound(machine_id))?;
 
-        let network = self
-            .networks
-            .get(&network_id)
-            .ok_or(anyhow::anyhow!("network not found"))?;
+        let network = match network_id {
+            Some(network_id) => Some(
+                self.networks
+                    .get(&network_id)
+                    .ok_or(VmmError::NetworkNotFound(network_id))?
+                    .clone(),
+            ),
+            None => None,
+        };
 
-        let instance = Instance::new(ctx, id, machine.clone(), network.clone()).await?;
+        let mut instance = Instance::new(ctx, id, machine.clone(), network).await?;
+        instance.set_exit_notifier(self.exit_tx.clone());
         self.instances.insert(id, instance);
 
         Ok(id)
     }
 
-    pub async fn start_instance(&mut self, ctx: &Ctx, id: &Id) -> Result<()> {
-        let instance = self
+    /// Finds the machine's instance, creating one on the machine's network if
+    /// it has none yet. Returns the instance id and whether it's already
+    /// running or starting.
+    async fn get_or_create_machine_instance(
+        &mut self,
+        ctx: &BaseCtx,
+        machine_id: Id,
+    ) -> VmmResult<(Id, bool)> {
+        // Machines created since the server started aren't loaded yet
+        if !self.machines.contains_key(&machine_id) {
+            if !ctx.dirs().get_machine_config_ids()?.contains(&machine_id) {
+                return Err(VmmError::MachineNotFound(machine_id));
+            }
+            let machine = Machine::open(ctx, machine_id).await?;
+            let name = machine.config().name.clone();
+            self.names.insert((EntityKind::Machine, name), machine_id);
+            self.machines.insert(machine_id, machine);
+        }
+
+        // One that's checked out for something other than a start is left
+        // for `start_instance` to report as busy
+        if let Some(summary) = self
+            .checked_out
+            .values()
+            .find(|summary| summary.machine_id == machine_id)
+        {
+            let running = summary.running || matches!(summary.status, QemuStatus::Starting);
+            return Ok((summary.id, running));
+        }
+
+        let existing = self
             .instances
-            .get_mut(&id)
-            .ok_or(anyhow!("instance not found"))?;
+            .values()
+            .find(|instance| *instance.machine().id() == machine_id)
+            .map(|instance| (*instance.id(), instance.is_running()));
+
+        if let Some(existing) = existing {
+            return Ok(existing);
+        }
 
+        let network_id = self.machines[&machine_id]
+            .config()
+            .network
+            .as_ref()
+            .map(|network| network.id);
+        let id = self.create_instance(ctx, machine_id, network_id).await?;
+
+        Ok((id, false))
+    }
+
+    /// Cleans up after instances whose qemu exited on its own. Returns those
+    /// that their machine's restart policy says to start again.
+    async fn check_instances(&mut self, ctx: &BaseCtx) -> Vec<Id> {
+        let mut restart_ids = vec![];
+
+        for (id, instance) in self.instances.iter_mut() {
+            let uptime = instance.uptime();
+
+            let status = match instance.try_wait(ctx).await {
+                Ok(Some(status)) => {
+                    let event = InstanceEvent::QemuExited {
+                        status: status.to_string(),
+                    };
+                    record_instance_event(ctx, *id, event).await;
+                    status
+                }
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("error: {:?}", e);
+                    continue;
+                }
+            };
+
+            let retries = self.restart_counts.entry(*id).or_insert(0);
+            if uptime.is_some_and(|uptime| uptime >= RESTART_RESET_UPTIME) {
+                *retries = 0;
+            }
+
+            let restart = &instance.machine().config().restart;
+            if !restart.should_restart(status, *retries) {
+                continue;
+            }
+
+            *retries += 1;
+
+            let event = InstanceEvent::RestartedByPolicy { retries: *retries };
+            record_instance_event(ctx, *id, event).await;
+
+            restart_ids.push(*id);
+        }
+
+        restart_ids
+    }
+}
+
+/// Starts an instance without holding the server lock, which would stall
+/// every other request for as long as the boot takes
+pub async fn start_instance(
+    server: &Mutex<Server>,
+    ctx: &Ctx,
+    id: Id,
+    paused: bool,
+) -> VmmResult<()> {
+    let mut instance = server.lock().await.check_out_instance(id)?;
+
+    let result = instance
+        .start(ctx, paused)
+        .await
+        .context("failed to start instance")
+        .context(id);
+
+    server.lock().await.check_in_instance(instance);
+
+    result?;
+    Ok(())
+}
+
+fn snapshot_instance(instance: &Instance) -> (InstanceSummary, Option<PathBuf>) {
+    let qmp_socket_path = instance
+        .is_running()
+        .then(|| instance.get_qmp_socket_path());
+    (InstanceSummary::new(instance, false), qmp_socket_path)
+}
+
+/// Lists the instances, asking qemu about them without holding the server
+/// lock, so that one hung monitor can't stall everything else
+pub async fn list_instances(server: &Mutex<Server>) -> Vec<InstanceSummary> {
+    let snapshots = server.lock().await.snapshot_instances();
+    let queries = snapshots
+        .into_iter()
+        .map(|(summary, qmp_socket_path)| summary.query(qmp_socket_path));
+    join_all(queries).await
+}
+
+pub async fn get_instance_status(server: &Mutex<Server>, id: Id) -> VmmResult<InstanceSummary> {
+    let (summary, qmp_socket_path) = server.lock().await.snapshot_instance(id)?;
+    Ok(summary.query(qmp_socket_path).await)
+}
+
+/// Runs `f` on an instance checked out of the server, so that the server
+/// lock isn't held while it waits on qemu
+async fn with_instance<T>(
+    server: &Mutex<Server>,
+    id: Id,
+    f: impl AsyncFnOnce(&mut Instance) -> Result<T>,
+) -> VmmResult<T> {
+    let mut instance = server.lock().await.check_out(id)?;
+
+    let result = f(&mut instance).await;
+
+    server.lock().await.check_in_instance(instance);
+
+    Ok(result.context(id)?)
+}
+
+pub async fn eject_install_iso(server: &Mutex<Server>, ctx: &BaseCtx, id: Id) -> VmmResult<()> {
+    let machine = with_instance(server, id, async |instance| {
         instance
-            .start(ctx)
+            .eject_install_iso(ctx)
             .await
-            .context("failed to start instance")
-            .context(*id)?;
+            .context("failed to eject install iso")?;
+        Ok(instance.machine().clone())
+    })
+    .await?;
 
-        Ok(())
-    }
+    // Keep the server's copy in sync, or the next reload would see the
+    // config change and restart the instance
+    server.lock().await.machines.insert(*machine.id(), machine);
 
-    pub async fn stop_instance(&mut self, ctx: &Ctx, id: Id) -> Result<()> {
-        let instance = self
-            .instances
-            .get_mut(&id)
-            .ok_or(anyhow!("instance not found"))?;
+    Ok(())
+}
+
+pub async fn resume_instance(server: &Mutex<Server>, id: Id) -> VmmResult<()> {
+    with_instance(server, id, async |instance| {
+        instance.resume().await.context("failed to resume instance")
+    })
+    .await
+}
 
+pub async fn set_instance_memory(
+    server: &Mutex<Server>,
+    ctx: &BaseCtx,
+    id: Id,
+    memory: Byte,
+) -> VmmResult<()> {
+    with_instance(server, id, async |instance| {
         instance
-            .stop()
+            .set_memory(ctx, memory)
             .await
-            .context("failed to stop instance")
-            .context(id)?;
+            .context("failed to set instance memory")
+    })
+    .await
+}
 
-        Ok(())
+/// Returns the id of the attached disk, which detaches it again
+pub async fn attach_instance_disk(
+    server: &Mutex<Server>,
+    ctx: &BaseCtx,
+    id: Id,
+    path: PathBuf,
+    format: DiskFormat,
+    options: AttachDiskOptions,
+) -> VmmResult<String> {
+    with_instance(server, id, async |instance| {
+        instance
+            .attach_disk(ctx, path, format, options)
+            .await
+            .context("failed to attach disk")
+    })
+    .await
+}
+
+pub async fn detach_instance_disk(
+    server: &Mutex<Server>,
+    ctx: &BaseCtx,
+    id: Id,
+    disk_id: &str,
+) -> VmmResult<()> {
+    with_instance(server, id, async |instance| {
+        instance
+            .detach_disk(ctx, disk_id)
+            .await
+            .context("failed to detach disk")
+    })
+    .await
+}
+
+pub async fn set_instance_io_throttle(
+    server: &Mutex<Server>,
+    id: Id,
+    disk_id: Option<&str>,
+    iops_limit: Option<u64>,
+    bps_limit: Option<u64>,
+) -> VmmResult<()> {
+    with_instance(server, id, async |instance| {
+        instance
+            .set_io_throttle(disk_id, iops_limit, bps_limit)
+            .await
+            .context("failed to set io throttle")
+    })
+    .await
+}
+
+/// Returns the tag the guest mounts the share by
+pub async fn add_instance_share_dir(
+    server: &Mutex<Server>,
+    ctx: &BaseCtx,
+    id: Id,
+    path: PathBuf,
+) -> VmmResult<String> {
+    with_instance(server, id, async |instance| {
+        instance
+            .add_share_dir(ctx, path)
+            .await
+            .context("failed to add share dir")
+    })
+    .await
+}
+
+pub async fn remove_instance_share_dir(server: &Mutex<Server>, id: Id, tag: &str) -> VmmResult<()> {
+    with_instance(server, id, async |instance| {
+        instance
+            .remove_share_dir(tag)
+            .await
+            .context("failed to remove share dir")
+    })
+    .await
+}
+
+/// Stops an instance without holding the server lock for the up to
+/// `graceful_timeout_secs` the guest gets to power down
+pub async fn stop_instance(server: &Mutex<Server>, ctx: &BaseCtx, id: Id) -> VmmResult<()> {
+    let killed = with_instance(server, id, async |instance| {
+        instance.stop(ctx).await.context("failed to stop instance")
+    })
+    .await?;
+
+    if killed {
+        eprintln!(
+            "warning: instance did not shut down in time and was killed: {}",
+            id
+        );
     }
+
+    Ok(())
+}
+
+/// Checks on the instances whenever one's qemu exits, which restarts those
+/// whose restart policy calls for it
+pub async fn supervise(server: Arc<Mutex<Server>>, ctx: Ctx) -> Result<()> {
+    let mut exits = server
+        .lock()
+        .await
+        .exit_rx
+        .take()
+        .context("server is already supervised")?;
+
+    while exits.recv().await.is_some() {
+        let restart_ids = server.lock().await.check_instances(&ctx).await;
+
+        for id in restart_ids {
+            if let Err(e) = start_instance(&server, &ctx, id, false).await {
+                eprintln!("error: failed to restart instance {}: {:?}", id, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts the machine's instance, creating one on the machine's network if it
+/// has none yet. Returns the instance id.
+pub async fn start_machine(server: &Mutex<Server>, ctx: &Ctx, machine_id: Id) -> VmmResult<Id> {
+    let (id, running) = server
+        .lock()
+        .await
+        .get_or_create_machine_instance(ctx, machine_id)
+        .await?;
+
+    if !running {
+        start_instance(server, ctx, id, false).await?;
+    }
+
+    Ok(id)
+}
+
+/// Re-reads all configs and brings the running instances in line with them:
+/// new instances are started, removed ones are stopped, and those whose
+/// machine config changed are restarted. Returns the actions taken.
+pub async fn reload(server: &Mutex<Server>, ctx: &Ctx) -> Result<Vec<String>> {
+    let mut actions = vec![];
+
+    let plan = server
+        .lock()
+        .await
+        .reload_configs(ctx, &mut actions)
+        .await?;
+
+    for mut instance in plan.removed {
+        let id = *instance.id();
+        match instance.stop(ctx).await {
+            Ok(_) => actions.push(format!("stopped removed instance {}", id)),
+            Err(e) => eprintln!("error: failed to stop instance {}: {:?}", id, e),
+        }
+    }
+
+    for mut instance in plan.changed {
+        let id = *instance.id();
+
+        if let Err(e) = instance.stop(ctx).await {
+            eprintln!("error: failed to stop instance {}: {:?}", id, e);
+            server.lock().await.check_in_instance(instance);
+            continue;
+        }
+
+        let result = server
+            .lock()
+            .await
+            .check_in_reopened_instance(ctx, instance)
+            .await;
+        if let Err(e) = result {
+            eprintln!("error: failed to read instance {}: {:?}", id, e);
+            continue;
+        }
+
+        match start_instance(server, ctx, id, false).await {
+            Ok(()) => actions.push(format!(
+                "restarted instance {} after its machine config changed",
+                id
+            )),
+            Err(e) => eprintln!("error: failed to start instance {}: {:?}", id, e),
+        }
+    }
+
+    for id in plan.new_ids {
+        match start_instance(server, ctx, id, false).await {
+            Ok(()) => actions.push(format!("started new instance {}", id)),
+            Err(e) => eprintln!("error: failed to start instance {}: {:?}", id, e),
+        }
+    }
+
+    Ok(actions)
+}
+
+/// Reloads the server whenever the process receives SIGHUP
+pub async fn reload_on_sighup(server: Arc<Mutex<Server>>, ctx: Ctx) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).context("failed to listen for SIGHUP")?;
+
+    while hangup.recv().await.is_some() {
+        match reload(&server, &ctx).await {
+            Ok(actions) if actions.is_empty() => println!("reloaded, nothing changed"),
+            Ok(actions) => {
+                for action in actions {
+                    println!("reloaded: {}", action);
+                }
+            }
+            Err(e) => eprintln!("error: failed to reload: {:?}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Hashes a machine config as it would be written, to tell whether it changed
+fn get_config_hash(machine: &Machine) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(machine.config())
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish()
 }
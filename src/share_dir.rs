@@ -1,7 +1,10 @@
-use std::{cell::OnceCell, path::PathBuf, process::Stdio};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
 
-use anyhow::{Context, Result, anyhow};
-use byte_unit::Byte;
+use anyhow::{Context, Result, anyhow, bail};
 use rand_core::{OsRng, TryRngCore};
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
@@ -10,87 +13,101 @@ use tokio::{
 };
 
 use crate::{
-    ctx::Ctx,
+    cmd::find_program,
+    ctx::BaseCtx,
+    gc::remove_stale_file,
     id::Id,
     logger::{LogLine, LogSource, LogStream},
-    machine::Machine,
+    vmm_dirs::VmmDirs,
 };
 
 pub struct ShareDir {
     instance_id: Id,
     boot_seq: u64,
-    instance_memory: Byte,
     tag: String,
     path: PathBuf,
-    socket_path: OnceCell<PathBuf>,
+    socket_path: PathBuf,
     daemon: Option<(Child, Vec<JoinHandle<()>>)>,
 }
 
 impl ShareDir {
-    pub fn new(instance_id: Id, boot_seq: u64, machine: &Machine, path: PathBuf) -> Result<Self> {
-        let instance_memory = machine.config().memory;
-        loop {
-            let mut bytes = [0u8; 4];
-            OsRng.try_fill_bytes(&mut bytes).map_err(|e| anyhow!(e))?;
-            let tag = base_62::encode(&bytes);
-            let sharer_dir = Self {
-                instance_id,
-                boot_seq,
-                instance_memory,
-                tag,
-                path: path.clone(),
-                socket_path: OnceCell::new(),
-                daemon: None,
-            };
-            if !sharer_dir.path.exists() {
-                break Ok(sharer_dir);
-            }
-        }
+    /// The path is only validated when the directory is shared, so that an
+    /// instance with a since-removed share dir can still be loaded
+    pub fn new(dirs: &VmmDirs, instance_id: Id, boot_seq: u64, path: PathBuf) -> Result<Self> {
+        let mut bytes = [0u8; 4];
+        OsRng.try_fill_bytes(&mut bytes).map_err(|e| anyhow!(e))?;
+        let tag = base_62::encode(&bytes);
+        let socket_path = dirs.get_virtiofs_socket_path(instance_id, &tag)?;
+
+        Ok(Self {
+            instance_id,
+            boot_seq,
+            tag,
+            path,
+            socket_path,
+            daemon: None,
+        })
+    }
+
+    /// Checks the directory right before it's shared, since it may have
+    /// changed since the instance was loaded
+    pub fn validate(&self) -> Result<()> {
+        validate_share_dir_path(&self.path)
+    }
+
+    pub fn set_boot_seq(&mut self, boot_seq: u64) {
+        self.boot_seq = boot_seq;
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn get_chardev_id(&self) -> String {
+        format!("char-{}", self.tag)
+    }
+
+    pub fn get_device_id(&self) -> String {
+        format!("fs-{}", self.tag)
     }
 
     pub fn get_socket_path(&self) -> &PathBuf {
-        self.socket_path.get_or_init(|| {
-            let socket = format!(
-                "/tmp/vmm-virtiofs-{}-{}.sock",
-                self.instance_id.to_string(),
-                self.tag
-            );
-            PathBuf::from(socket)
-        })
+        &self.socket_path
     }
 
     pub fn get_qemu_args(&self) -> Vec<String> {
         let chardev = format!(
-            "socket,id=char-{},path={}",
-            self.tag,
+            "socket,id={},path={}",
+            self.get_chardev_id(),
             self.get_socket_path().to_string_lossy()
         );
 
         let device = format!(
-            "vhost-user-fs-pci,queue-size=1024,chardev=char-{tag},tag={tag}",
-            tag = self.tag
+            "vhost-user-fs-pci,id={},queue-size=1024,chardev={},tag={}",
+            self.get_device_id(),
+            self.get_chardev_id(),
+            self.tag
         );
 
-        let memory = self.instance_memory.as_u64().to_string();
-        let shm = "/dev/shm";
-
-        let mem = format!("memory-backend-file,id=mem,size={memory}B,mem-path={shm},share=on");
-        let numa = format!("node,memdev=mem");
+        // The shared guest memory backend that vhost-user requires is set up
+        // by the instance, since it must be consistent with its NUMA layout
 
         #[rustfmt::skip]
         let args = vec![
             "-chardev".into(), chardev,
             "-device".into(), device,
-            "-object".into(), mem,
-            "-numa".into(), numa,
         ];
 
         args
     }
 
-    async fn start_virtiofsd(&mut self, ctx: &Ctx) -> Result<()> {
+    async fn start_virtiofsd(&mut self, ctx: &BaseCtx) -> Result<()> {
         assert!(self.daemon.is_none(), "virtiofsd already running");
 
+        // Left behind if virtiofsd was killed, and it won't bind over it. The
+        // instance has already checked that its qemu isn't running.
+        remove_stale_file(self.get_socket_path())?;
+
         let socket_path = self.get_socket_path().to_string_lossy();
         let path = self.path.to_string_lossy();
 
@@ -101,7 +118,9 @@ impl ShareDir {
             "--tag", &self.tag,
         ];
 
-        let mut child = Command::new("/usr/lib/virtiofsd")
+        let virtiofsd = find_virtiofsd()?;
+
+        let mut child = Command::new(virtiofsd)
             .args(args)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -132,14 +151,16 @@ impl ShareDir {
 
         if let Some(stderr) = child.stderr.take() {
             let id = self.instance_id.clone();
+            let boot_seq = self.boot_seq;
             let mut reader = BufReader::new(stderr).lines();
             let logger = ctx.logger().clone();
             let stderr_task = tokio::spawn(async move {
                 while let Ok(Some(line)) = reader.next_line().await {
-                    let _ = logger.log(LogLine::machine(
+                    let _ = logger.log(LogLine::instance(
                         id,
+                        boot_seq,
                         LogStream::Stderr,
-                        LogSource::CloudInit,
+                        LogSource::Virtiofs,
                         line,
                     ));
                 }
@@ -152,7 +173,7 @@ impl ShareDir {
         Ok(())
     }
 
-    pub async fn start(&mut self, ctx: &Ctx) -> Result<bool> {
+    pub async fn start(&mut self, ctx: &BaseCtx) -> Result<bool> {
         if self.daemon.is_some() {
             return Ok(false);
         }
@@ -160,14 +181,43 @@ impl ShareDir {
         Ok(true)
     }
 
-    pub async fn stop(&mut self) -> Result<bool> {
+    /// Waits for virtiofsd to create its socket, which qemu needs to exist
+    /// before it can connect the chardev
+    pub async fn wait_for_socket(&self, timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.get_socket_path().exists() {
+            if tokio::time::Instant::now() >= deadline {
+                anyhow::bail!(
+                    "timed out waiting for virtiofsd socket: {}",
+                    self.get_socket_path().display()
+                );
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(())
+    }
+
+    /// Waits for virtiofsd to exit, which it does once qemu disconnects, and
+    /// kills it if that takes longer than `timeout`. Returns whether it had to
+    /// be killed.
+    pub async fn stop(&mut self, timeout: Duration) -> Result<bool> {
         let Some((mut child, mut tasks)) = self.daemon.take() else {
             return Ok(false);
         };
 
-        let status = child
-            .wait()
-            .await
+        let (status, killed) = match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => (status, false),
+            Err(_) => {
+                child
+                    .kill()
+                    .await
+                    .context("failed to kill virtiofsd")
+                    .context(self.instance_id)?;
+                (child.wait().await, true)
+            }
+        };
+
+        let status = status
             .context("failed to wait for virtiofsd")
             .context(self.instance_id)?;
 
@@ -175,12 +225,39 @@ impl ShareDir {
             let _ = task.await;
         }
 
-        if !status.success() {
+        if !killed && !status.success() {
             anyhow::bail!("virtiofsd exited with {}", status);
         }
 
-        Ok(true)
+        Ok(killed)
+    }
+}
+
+/// Resolves virtiofsd, which distros install outside of `PATH` in different
+/// places. `VMM_VIRTIOFSD` overrides the search.
+pub fn find_virtiofsd() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("VMM_VIRTIOFSD") {
+        let path = PathBuf::from(path);
+        if !path.is_file() {
+            anyhow::bail!("VMM_VIRTIOFSD does not exist: {}", path.display());
+        }
+        return Ok(path);
     }
+
+    let known_paths = [
+        "/usr/lib/virtiofsd",
+        "/usr/libexec/virtiofsd",
+        "/usr/lib/qemu/virtiofsd",
+    ];
+
+    known_paths
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+        .or_else(|| find_program("virtiofsd"))
+        .ok_or(anyhow!(
+            "virtiofsd not found; install virtiofsd or set VMM_VIRTIOFSD to its path"
+        ))
 }
 
 impl Drop for ShareDir {
@@ -188,3 +265,21 @@ impl Drop for ShareDir {
         assert!(self.daemon.is_none(), "virtiofsd is still running");
     }
 }
+
+/// virtiofsd only fails once it's started, with an error that doesn't say
+/// which dir was the problem
+fn validate_share_dir_path(path: &Path) -> Result<()> {
+    if !path.exists() {
+        bail!("share dir not found: {}", path.display());
+    }
+
+    if !path.is_dir() {
+        bail!("share dir is not a directory: {}", path.display());
+    }
+
+    std::fs::read_dir(path)
+        .context("share dir is not readable")
+        .context(path.display().to_string())?;
+
+    Ok(())
+}
@@ -0,0 +1,297 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    cmd::cmd_success,
+    ctx::BaseCtx,
+    id::Id,
+    instance::Instance,
+    instance_events::{InstanceEvent, record_instance_event},
+    qmp::QmpClient,
+};
+
+pub struct SnapshotInfo {
+    pub id: String,
+    pub tag: String,
+    pub vm_size: String,
+    pub date: String,
+    pub vm_clock: String,
+    /// Whether the snapshot includes the guest's memory, unknown for
+    /// snapshots that weren't taken through vmm
+    pub live: Option<bool>,
+}
+
+/// What vmm remembers about a snapshot beyond what qemu-img reports, kept in
+/// the instance's snapshot dir
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotRecord {
+    name: String,
+    created_at: u64,
+    live: bool,
+}
+
+impl SnapshotRecord {
+    async fn open(ctx: &BaseCtx, instance_id: Id, name: &str) -> Result<Option<Self>> {
+        let path = get_record_path(ctx, instance_id, name)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let text = tokio::fs::read_to_string(&path)
+            .await
+            .context("failed to read snapshot record")
+            .context(path.display().to_string())?;
+
+        let record = serde_json::from_str(&text)
+            .context("failed to parse snapshot record")
+            .context(path.display().to_string())?;
+
+        Ok(Some(record))
+    }
+
+    async fn save(&self, ctx: &BaseCtx, instance_id: Id) -> Result<()> {
+        let snapshot_dir = ctx.dirs().get_instance_snapshot_dir(instance_id)?;
+        tokio::fs::create_dir_all(&snapshot_dir).await?;
+
+        let text =
+            serde_json::to_string_pretty(self).context("failed to serialize snapshot record")?;
+
+        tokio::fs::write(get_record_path(ctx, instance_id, &self.name)?, text)
+            .await
+            .context("failed to write snapshot record")
+            .context(instance_id)?;
+
+        Ok(())
+    }
+}
+
+fn get_record_path(ctx: &BaseCtx, instance_id: Id, name: &str) -> Result<PathBuf> {
+    let path = ctx
+        .dirs()
+        .get_instance_snapshot_dir(instance_id)?
+        .join(format!("{}.json", name));
+    Ok(path)
+}
+
+/// Names double as record file names, so they can't contain path separators
+fn validate_snapshot_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        bail!("invalid snapshot name: {:?}", name);
+    }
+    Ok(())
+}
+
+pub async fn create_snapshot(ctx: &BaseCtx, instance: &Instance, name: &str) -> Result<()> {
+    validate_snapshot_name(name)?;
+
+    let root_disk = get_root_disk_path(ctx, instance)?;
+
+    let live = match connect_qmp(instance).await {
+        Some(mut qmp) => {
+            let job_id = format!("snapshot-save-{}", name);
+            qmp.run_job(
+                "snapshot-save",
+                &job_id,
+                json!({ "tag": name, "vmstate": "root-disk", "devices": ["root-disk"] }),
+            )
+            .await
+            .context(*instance.id())?;
+            true
+        }
+        None => {
+            qemu_img_snapshot(&["-c", name], &root_disk).await?;
+            false
+        }
+    };
+
+    let record = SnapshotRecord {
+        name: name.to_string(),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        live,
+    };
+    record.save(ctx, *instance.id()).await?;
+
+    let event = InstanceEvent::SnapshotTaken {
+        name: name.to_string(),
+    };
+    record_instance_event(ctx, *instance.id(), event).await;
+
+    Ok(())
+}
+
+pub async fn delete_snapshot(ctx: &BaseCtx, instance: &Instance, name: &str) -> Result<()> {
+    validate_snapshot_name(name)?;
+
+    let root_disk = get_root_disk_path(ctx, instance)?;
+
+    match connect_qmp(instance).await {
+        Some(mut qmp) => {
+            let job_id = format!("snapshot-delete-{}", name);
+            qmp.run_job(
+                "snapshot-delete",
+                &job_id,
+                json!({ "tag": name, "devices": ["root-disk"] }),
+            )
+            .await
+            .context(*instance.id())?;
+        }
+        None => {
+            qemu_img_snapshot(&["-d", name], &root_disk).await?;
+        }
+    }
+
+    let record_path = get_record_path(ctx, *instance.id(), name)?;
+    if record_path.exists() {
+        tokio::fs::remove_file(&record_path)
+            .await
+            .context("failed to delete snapshot record")
+            .context(record_path.display().to_string())?;
+    }
+
+    Ok(())
+}
+
+pub async fn apply_snapshot(ctx: &BaseCtx, instance: &Instance, name: &str) -> Result<()> {
+    validate_snapshot_name(name)?;
+
+    let root_disk = get_root_disk_path(ctx, instance)?;
+
+    match connect_qmp(instance).await {
+        Some(mut qmp) => {
+            let job_id = format!("snapshot-load-{}", name);
+            qmp.run_job(
+                "snapshot-load",
+                &job_id,
+                json!({ "tag": name, "vmstate": "root-disk", "devices": ["root-disk"] }),
+            )
+            .await
+            .context(*instance.id())?;
+        }
+        None => {
+            qemu_img_snapshot(&["-a", name], &root_disk).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn list_snapshots(ctx: &BaseCtx, instance: &Instance) -> Result<Vec<SnapshotInfo>> {
+    let root_disk = get_root_disk_path(ctx, instance)?;
+
+    // -U allows reading the snapshot table while qemu holds the image lock
+    let output = qemu_img_snapshot(&["-U", "-l"], &root_disk).await?;
+
+    let mut snapshots = parse_snapshot_list(&output);
+
+    for snapshot in snapshots.iter_mut() {
+        if validate_snapshot_name(&snapshot.tag).is_err() {
+            continue;
+        }
+        if let Some(record) = SnapshotRecord::open(ctx, *instance.id(), &snapshot.tag).await? {
+            snapshot.live = Some(record.live);
+        }
+    }
+
+    Ok(snapshots)
+}
+
+/// The output is a "Snapshot list:" line, a column header line, then one line
+/// per snapshot:
+///
+/// ```text
+/// ID  TAG    VM SIZE  DATE                 VM CLOCK      ICOUNT
+/// 1   base   0 B      2025-01-01 00:00:00  00:00:00.000  0
+/// ```
+fn parse_snapshot_list(output: &str) -> Vec<SnapshotInfo> {
+    output
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 7 {
+                return None;
+            }
+            Some(SnapshotInfo {
+                id: fields[0].to_string(),
+                tag: fields[1].to_string(),
+                vm_size: format!("{} {}", fields[2], fields[3]),
+                date: format!("{} {}", fields[4], fields[5]),
+                vm_clock: fields[6].to_string(),
+                live: None,
+            })
+        })
+        .collect()
+}
+
+fn get_root_disk_path(ctx: &BaseCtx, instance: &Instance) -> Result<PathBuf> {
+    let root_disk = ctx.dirs().get_instance_root_disk_path(*instance.id())?;
+    if !root_disk.exists() {
+        bail!(
+            "instance has no disk yet, start it at least once: {}",
+            instance.id()
+        );
+    }
+    Ok(root_disk)
+}
+
+/// Connects to the instance's QMP socket, which only succeeds while qemu is
+/// running
+async fn connect_qmp(instance: &Instance) -> Option<QmpClient> {
+    QmpClient::connect(&instance.get_qmp_socket_path())
+        .await
+        .ok()
+}
+
+async fn qemu_img_snapshot(args: &[&str], root_disk: &Path) -> Result<String> {
+    let root_disk = root_disk.to_string_lossy();
+
+    let mut args = [&["snapshot"], args].concat();
+    args.push(&root_disk);
+
+    let output = cmd_success("qemu-img", &args).await?;
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_list_is_parsed_by_column() {
+        let output = "\
+Snapshot list:
+ID        TAG               VM SIZE                DATE     VM CLOCK     ICOUNT
+1         base                  0 B 2025-01-01 00:00:00 00:00:00.000          0
+2         live            512 MiB 2025-01-02 12:30:45 0000:01:02.345
+";
+
+        let snapshots = parse_snapshot_list(output);
+        assert_eq!(snapshots.len(), 2);
+
+        assert_eq!(snapshots[0].id, "1");
+        assert_eq!(snapshots[0].tag, "base");
+        assert_eq!(snapshots[0].vm_size, "0 B");
+        assert_eq!(snapshots[0].date, "2025-01-01 00:00:00");
+        assert_eq!(snapshots[0].vm_clock, "00:00:00.000");
+        assert!(snapshots[0].live.is_none());
+
+        assert_eq!(snapshots[1].id, "2");
+        assert_eq!(snapshots[1].tag, "live");
+        assert_eq!(snapshots[1].vm_size, "512 MiB");
+        assert_eq!(snapshots[1].date, "2025-01-02 12:30:45");
+        assert_eq!(snapshots[1].vm_clock, "0000:01:02.345");
+    }
+
+    #[test]
+    fn empty_snapshot_list_is_parsed() {
+        assert!(parse_snapshot_list("").is_empty());
+        assert!(parse_snapshot_list("Snapshot list:\nID TAG VM SIZE DATE VM CLOCK\n").is_empty());
+    }
+}
@@ -5,9 +5,9 @@ use futures::StreamExt;
 use tokio::sync::mpsc;
 use tokio_util::{sync::CancellationToken, time::DelayQueue};
 
-use crate::task_group::TaskGroup;
+use crate::task_group::{TaskGroup, TaskId};
 
-const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub enum TaskActorTimer<Timer> {
     Timer(Timer),
@@ -18,6 +18,7 @@ pub enum TaskActorTimer<Timer> {
 pub enum TaskActorEvent<Message, Timer> {
     Message(Message),
     Timer(Timer),
+    TaskFailed(TaskId),
     Stopped(TaskActorStopReason),
 }
 
@@ -30,6 +31,7 @@ pub enum TaskActorStopReason {
 
 pub struct TaskActor<Message, Timer, Return> {
     shutdown: bool,
+    shutdown_timeout: Duration,
     cancel_token: CancellationToken,
     receiver: mpsc::Receiver<Message>,
     timers: DelayQueue<TaskActorTimer<Timer>>,
@@ -41,13 +43,21 @@ impl<Message, Timer, Return> TaskActor<Message, Timer, Return> {
         let tasks_cancel_token = CancellationToken::new();
         Self {
             shutdown: false,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
             cancel_token,
             receiver,
             timers: DelayQueue::new(),
-            tasks: TaskGroup::new(tasks_cancel_token),
+            tasks: TaskGroup::new(tasks_cancel_token).with_failed_tasks(),
         }
     }
 
+    /// How long tasks get to finish after shutdown begins before they're
+    /// aborted
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
     pub async fn update(&mut self) -> Result<TaskActorEvent<Message, Timer>> {
         while self.is_running() {
             tokio::select! {
@@ -79,6 +89,9 @@ impl<Message, Timer, Return> TaskActor<Message, Timer, Return> {
                         }
                     }
                 }
+                task_id = self.tasks.next_failed() => {
+                    return Ok(TaskActorEvent::TaskFailed(task_id));
+                }
                 _ = self.cancel_token.cancelled() => {
                     self.shutdown().await;
                     let reason = TaskActorStopReason::Cancelled;
@@ -108,7 +121,7 @@ impl<Message, Timer, Return> TaskActor<Message, Timer, Return> {
 
         self.shutdown = true;
         self.receiver.close();
-        self.timers.insert(timer, SHUTDOWN_TIMEOUT);
+        self.timers.insert(timer, self.shutdown_timeout);
         self.tasks.cancel().await;
         self.tasks.wait().await;
     }
@@ -1,7 +1,8 @@
-use std::sync::Arc;
+use std::{panic::AssertUnwindSafe, sync::Arc};
 
 use dashmap::DashMap;
-use tokio::task::JoinHandle;
+use futures::FutureExt;
+use tokio::{sync::mpsc, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -11,11 +12,16 @@ pub struct TaskGroup<T> {
     cancel_token: CancellationToken,
     tasks: Arc<DashMap<TaskId, JoinHandle<TaskResult<T>>>>,
     next_task_id: TaskId,
+    /// Only set for groups that read panics through `next_failed`, so the
+    /// rest don't queue them forever
+    failed_sender: Option<mpsc::UnboundedSender<TaskId>>,
+    failed_receiver: Option<mpsc::UnboundedReceiver<TaskId>>,
 }
 
 pub enum TaskResult<T> {
     Completed(T),
     Cancelled,
+    Panicked,
 }
 
 impl<T> TaskGroup<T> {
@@ -24,9 +30,20 @@ impl<T> TaskGroup<T> {
             cancel_token,
             tasks: Arc::new(DashMap::new()),
             next_task_id: TaskId(0),
+            failed_sender: None,
+            failed_receiver: None,
         }
     }
 
+    /// Reports tasks that panic through `next_failed`, which must then be
+    /// polled
+    pub fn with_failed_tasks(mut self) -> Self {
+        let (failed_sender, failed_receiver) = mpsc::unbounded_channel();
+        self.failed_sender = Some(failed_sender);
+        self.failed_receiver = Some(failed_receiver);
+        self
+    }
+
     pub fn spawn<F>(&mut self, future: F) -> TaskId
     where
         F: Future<Output = T> + Send + 'static,
@@ -35,10 +52,19 @@ impl<T> TaskGroup<T> {
         let task_id = self.next_task_id;
         let cancel_token = self.cancel_token.clone();
         let tasks = self.tasks.clone();
+        let failed_sender = self.failed_sender.clone();
 
         let task_handle = tokio::spawn(async move {
             let result = tokio::select! {
-                result = future => TaskResult::Completed(result),
+                result = AssertUnwindSafe(future).catch_unwind() => match result {
+                    Ok(result) => TaskResult::Completed(result),
+                    Err(_) => {
+                        if let Some(failed_sender) = failed_sender {
+                            let _ = failed_sender.send(task_id);
+                        }
+                        TaskResult::Panicked
+                    }
+                },
                 _ = cancel_token.cancelled() => TaskResult::Cancelled
             };
             tasks.remove(&task_id);
@@ -51,6 +77,20 @@ impl<T> TaskGroup<T> {
         task_id
     }
 
+    /// Resolves with the id of the next task that panicked, or never if the
+    /// group wasn't created `with_failed_tasks`. The panic itself has already
+    /// been reported by the panic hook.
+    pub async fn next_failed(&mut self) -> TaskId {
+        let Some(failed_receiver) = &mut self.failed_receiver else {
+            return std::future::pending().await;
+        };
+        match failed_receiver.recv().await {
+            Some(task_id) => task_id,
+            // The group holds a sender, so the channel never closes
+            None => std::future::pending().await,
+        }
+    }
+
     pub async fn wait(&mut self) {
         let mut waited = vec![];
 
@@ -1,16 +1,20 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use directories::BaseDirs;
 
 use crate::id::Id;
 
 #[derive(Debug, Clone)]
 pub struct VmmDirs {
-    base_dirs: BaseDirs,
     config_dir: PathBuf,
     cache_dir: PathBuf,
     state_dir: PathBuf,
+    runtime_dir: PathBuf,
 }
 
 impl VmmDirs {
@@ -23,64 +27,121 @@ impl VmmDirs {
             .ok_or(anyhow!("no state dir"))?
             .join("vmm");
 
+        // Falls back to a per-user dir when there's no XDG_RUNTIME_DIR, e.g.
+        // when running as root outside of a login session. Anyone can create
+        // that name in the shared temp dir first, so `get_runtime_dir` checks
+        // who owns it.
+        let runtime_dir = match base_dirs.runtime_dir() {
+            Some(runtime_dir) => runtime_dir.join("vmm"),
+            None => std::env::temp_dir().join(format!("vmm-{}", get_uid())),
+        };
+
         Ok(Self {
-            base_dirs,
             config_dir,
             cache_dir,
             state_dir,
+            runtime_dir,
         })
     }
 
+    /// Keeps everything under `root`, so that tests don't touch the user's
+    /// dirs
+    #[cfg(test)]
+    pub fn from_root(root: &std::path::Path) -> Self {
+        Self {
+            config_dir: root.join("config"),
+            cache_dir: root.join("cache"),
+            state_dir: root.join("state"),
+            runtime_dir: root.join("runtime"),
+        }
+    }
+
+    /// Holds sockets and other files that only make sense while the host is
+    /// up. Created on demand and only accessible by the current user.
+    pub fn get_runtime_dir(&self) -> Result<PathBuf> {
+        if !self.runtime_dir.exists() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(&self.runtime_dir)?;
+        }
+
+        // Not following symlinks, so the dir can't be redirected elsewhere
+        let metadata = fs::symlink_metadata(&self.runtime_dir)
+            .context("failed to read runtime dir")
+            .context(self.runtime_dir.display().to_string())?;
+
+        if !metadata.is_dir() {
+            bail!(
+                "runtime dir is not a directory: {}",
+                self.runtime_dir.display()
+            );
+        }
+        if metadata.uid() != get_uid() {
+            bail!(
+                "runtime dir is owned by another user: {}",
+                self.runtime_dir.display()
+            );
+        }
+        if metadata.mode() & 0o777 != 0o700 {
+            bail!(
+                "runtime dir must only be accessible by its owner (mode 0700): {}",
+                self.runtime_dir.display()
+            );
+        }
+
+        Ok(self.runtime_dir.clone())
+    }
+
+    pub fn get_instance_qmp_socket_path(&self, instance_id: Id) -> Result<PathBuf> {
+        let path = self
+            .get_runtime_dir()?
+            .join(format!("qmp-{}.sock", instance_id));
+        Ok(path)
+    }
+
+    pub fn get_instance_serial_socket_path(&self, instance_id: Id) -> Result<PathBuf> {
+        let path = self
+            .get_runtime_dir()?
+            .join(format!("serial-{}.sock", instance_id));
+        Ok(path)
+    }
+
+    pub fn get_instance_monitor_socket_path(&self, instance_id: Id) -> Result<PathBuf> {
+        let path = self
+            .get_runtime_dir()?
+            .join(format!("hmp-{}.sock", instance_id));
+        Ok(path)
+    }
+
+    pub fn get_virtiofs_socket_path(&self, instance_id: Id, tag: &str) -> Result<PathBuf> {
+        let path = self
+            .get_runtime_dir()?
+            .join(format!("virtiofs-{}-{}.sock", instance_id, tag));
+        Ok(path)
+    }
+
+    pub fn get_control_socket_path(&self) -> Result<PathBuf> {
+        let path = self.get_runtime_dir()?.join("server.sock");
+        Ok(path)
+    }
+
+    pub fn get_http_token_path(&self) -> Result<PathBuf> {
+        let path = self.config_dir.join("http-token");
+        Ok(path)
+    }
+
     pub fn get_machine_config_ids(&self) -> Result<Vec<Id>> {
-        let paths = fs::read_dir(&self.config_dir.join("machines"))?;
-        let ids = paths
-            .map(|path| {
-                path.unwrap()
-                    .path()
-                    .file_stem()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .parse::<Id>()
-                    .unwrap()
-            })
-            .collect();
-        Ok(ids)
+        read_dir_ids(&self.config_dir.join("machines"))
     }
 
     pub fn get_network_config_ids(&self) -> Result<Vec<Id>> {
-        let paths = fs::read_dir(&self.config_dir.join("networks"))?;
-        let ids = paths
-            .map(|path| {
-                path.unwrap()
-                    .path()
-                    .file_stem()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .parse::<Id>()
-                    .unwrap()
-            })
-            .collect();
-        Ok(ids)
+        read_dir_ids(&self.config_dir.join("networks"))
     }
 
     // XXX TODO: do we even use config for instances?
     pub fn get_instance_state_ids(&self) -> Result<Vec<Id>> {
-        let paths = fs::read_dir(&self.state_dir.join("instances"))?;
-        let ids = paths
-            .map(|path| {
-                path.unwrap()
-                    .path()
-                    .file_stem()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .parse::<Id>()
-                    .unwrap()
-            })
-            .collect();
-        Ok(ids)
+        read_dir_ids(&self.state_dir.join("instances"))
     }
 
     pub fn get_instance_state_dir(&self, instance_id: Id) -> Result<PathBuf> {
@@ -96,6 +157,33 @@ impl VmmDirs {
         Ok(state_path)
     }
 
+    pub fn get_instance_pid_path(&self, instance_id: Id) -> Result<PathBuf> {
+        let path = self.get_instance_state_dir(instance_id)?.join("qemu.pid");
+        Ok(path)
+    }
+
+    pub fn get_instance_root_disk_path(&self, instance_id: Id) -> Result<PathBuf> {
+        let path = self.get_instance_state_dir(instance_id)?.join("root.qcow2");
+        Ok(path)
+    }
+
+    pub fn get_instance_events_path(&self, instance_id: Id) -> Result<PathBuf> {
+        let path = self
+            .get_instance_state_dir(instance_id)?
+            .join("events.jsonl");
+        Ok(path)
+    }
+
+    pub fn get_instance_snapshot_dir(&self, instance_id: Id) -> Result<PathBuf> {
+        let path = self.get_instance_state_dir(instance_id)?.join("snapshots");
+        Ok(path)
+    }
+
+    pub fn get_instance_cloud_init_dir(&self, instance_id: Id) -> Result<PathBuf> {
+        let path = self.get_instance_state_dir(instance_id)?.join("cloud-init");
+        Ok(path)
+    }
+
     pub fn get_network_config_dir(&self, network_id: Id) -> Result<PathBuf> {
         let path = self
             .config_dir
@@ -105,10 +193,15 @@ impl VmmDirs {
     }
 
     pub fn get_network_config_file_path(&self, id: Id) -> Result<PathBuf> {
-        let config_path = self.get_network_config_dir(id)?.join("config.json");
+        let config_path = get_config_file_path(self.get_network_config_dir(id)?);
         Ok(config_path)
     }
 
+    pub fn get_network_state_dir(&self, network_id: Id) -> Result<PathBuf> {
+        let path = self.state_dir.join("networks").join(network_id.to_string());
+        Ok(path)
+    }
+
     pub fn get_machine_config_dir(&self, machine_id: Id) -> Result<PathBuf> {
         let path = self
             .config_dir
@@ -118,7 +211,7 @@ impl VmmDirs {
     }
 
     pub fn get_machine_config_file_path(&self, id: Id) -> Result<PathBuf> {
-        let config_path = self.get_machine_config_dir(id)?.join("config.json");
+        let config_path = get_config_file_path(self.get_machine_config_dir(id)?);
         Ok(config_path)
     }
 
@@ -136,8 +229,10 @@ impl VmmDirs {
         Ok(path)
     }
 
+    /// Downloads are written next to the cached images so that moving them
+    /// into place is an atomic rename on the same filesystem
     pub fn get_image_download_dir(&self) -> Result<PathBuf> {
-        let path = self.cache_dir.join("downloads");
+        let path = self.cache_dir.join("images");
         Ok(path)
     }
 
@@ -149,6 +244,11 @@ impl VmmDirs {
         Ok(path)
     }
 
+    pub fn get_image_cache_index_path(&self) -> Result<PathBuf> {
+        let path = self.cache_dir.join("index.json");
+        Ok(path)
+    }
+
     pub fn get_image_cache_path(&self, hash: &str) -> Result<PathBuf> {
         let path = self.cache_dir.join("images").join(hash);
         Ok(path)
@@ -162,4 +262,65 @@ impl VmmDirs {
             .join("logs");
         Ok(path)
     }
+
+    pub fn get_instance_console_log_path(&self, instance_id: Id) -> Result<PathBuf> {
+        let path = self.get_instance_log_dir(instance_id)?.join("console.log");
+        Ok(path)
+    }
+}
+
+/// Picks the config file in an entity's config dir, preferring `config.json`
+/// and falling back to `config.yaml` when only that exists. New configs are
+/// written as JSON.
+fn get_config_file_path(config_dir: PathBuf) -> PathBuf {
+    let json_path = config_dir.join("config.json");
+    let yaml_path = config_dir.join("config.yaml");
+    if !json_path.exists() && yaml_path.exists() {
+        yaml_path
+    } else {
+        json_path
+    }
+}
+
+/// Ids of the entries in a dir of id-named files or dirs
+fn read_dir_ids(dir: &Path) -> Result<Vec<Id>> {
+    let entries = fs::read_dir(dir)
+        .context("failed to read dir")
+        .context(dir.display().to_string())?;
+
+    let mut ids = vec![];
+    for entry in entries {
+        let path = entry
+            .context("failed to read dir")
+            .context(dir.display().to_string())?
+            .path();
+
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or(anyhow!("invalid file name: {}", path.display()))?;
+
+        let id: Id = stem.parse().context(path.display().to_string())?;
+
+        // Ids from before they were padded name their files without the
+        // padding, which paths built from the id wouldn't find
+        if stem != id.to_string() {
+            let mut padded_path = dir.join(id.to_string());
+            if let Some(extension) = path.extension() {
+                padded_path.set_extension(extension);
+            }
+            fs::rename(&path, &padded_path)
+                .context("failed to rename to padded id")
+                .context(path.display().to_string())?;
+        }
+
+        ids.push(id);
+    }
+
+    Ok(ids)
+}
+
+fn get_uid() -> u32 {
+    // SAFETY: getuid takes no arguments, can't fail and has no side effects
+    unsafe { libc::getuid() }
 }